@@ -1,34 +1,115 @@
-use clap::Parser;
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
 use crate::util::ip::{parse_origin, parse_port, validate_endpoint, validate_port};
 
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 #[command(name = "tau-tower")]
 #[command(version = env!("CARGO_PKG_VERSION"))]
 #[command( about = "Webradio server, distributes audio stream from a tau-radio client")]
 pub struct Args {
     /// Webradio username
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub username: Option<String>,
 
     /// Webradio password
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub password: Option<String>,
 
     /// Stream port
-    #[arg(short='l', long, value_parser=|p: &str| { validate_port(parse_port(p).unwrap()) })]
+    #[arg(short='l', long, global = true, value_parser=|p: &str| { validate_port(parse_port(p).unwrap()) })]
     pub listen_port: Option<u16>,
     
     /// Stream port
-    #[arg(short='b', long, value_parser=|p: &str| { validate_port(parse_port(p).unwrap()) })]
+    #[arg(short='b', long, global = true, value_parser=|p: &str| { validate_port(parse_port(p).unwrap()) })]
     pub broadcast_port: Option<u16>,
 
-    #[arg(short='a', long, value_parser=|s: &str| { parse_origin(s) })]
+    #[arg(short='a', long, global = true, value_parser=|s: &str| { parse_origin(s) })]
     pub cors_allow_list: Option<Vec<String>>,
 
-    #[arg(short='e', long, value_parser=|e: &str| { validate_endpoint(e) })]
+    #[arg(short='e', long, global = true, value_parser=|e: &str| { validate_endpoint(e) })]
     pub broadcast_endpoint: Option<String>,
 
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub reset_config: bool,
+
+    /// Path to tower.toml, instead of the one in the user's config directory
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Control socket of the running server, used by `serve` and the commands talking to it
+    #[arg(long, global = true)]
+    pub control_socket: Option<PathBuf>,
+
+    /// Fail with the missing fields instead of prompting when there is no config file
+    #[arg(long, global = true)]
+    pub no_interactive: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
 }
 
+#[derive(Subcommand, Clone)]
+pub enum Command {
+    /// Run the server, the default without a subcommand
+    Serve,
+    /// Show the source, listener count and uptime of the running server
+    Status,
+    /// List the listeners connected to the running server
+    Listeners,
+    /// Disconnect a listener, by the id shown in `listeners`
+    Kick {
+        id: u64,
+    },
+    /// Change the stream metadata of the running server
+    #[command(subcommand)]
+    Metadata(MetadataCommand),
+    /// Make the running server reload tower.toml
+    Reload,
+    /// Hash a source password for `password_hash` in tower.toml
+    HashPassword {
+        /// Password to hash, prompted for if omitted
+        password: Option<String>,
+    },
+    /// Generate a bearer token for a `[[users]]` entry in tower.toml
+    GenerateToken,
+    /// Validate the config file, environment and flags without starting the server
+    CheckConfig,
+    /// Sign a stream URL for a mount with `mode = "signed"` listener authentication
+    SignUrl {
+        /// Mount to sign, defaults to the broadcast endpoint
+        mount: Option<String>,
+
+        /// Seconds until the link expires
+        #[arg(long, default_value_t = 3600)]
+        expires_in: u64,
+
+        /// Prepended to the signed path, e.g. `https://example.com`
+        #[arg(long)]
+        base_url: Option<String>,
+    },
+}
+
+
+#[derive(Subcommand, Clone)]
+pub enum MetadataCommand {
+    /// Set Vorbis comments, e.g. `TITLE="Night Show"`, sent to listeners joining from now on
+    Set {
+        /// Mount to update, defaults to the broadcast endpoint
+        #[arg(long)]
+        mount: Option<String>,
+
+        #[arg(required = true, value_parser = parse_comment)]
+        comments: Vec<(String, String)>,
+    },
+}
+
+/// Parses a `FIELD=value` Vorbis comment, the field name being printable ASCII without `=`.
+fn parse_comment(s: &str) -> Result<(String, String), String> {
+    let Some((field, value)) = s.split_once('=') else {
+        return Err(format!("expected FIELD=value, got `{s}`"));
+    };
+    if field.is_empty() || !field.bytes().all(|b| (0x20..=0x7d).contains(&b)) {
+        return Err(format!("invalid comment field name `{field}`"));
+    }
+    Ok((field.to_string(), value.to_string()))
+}
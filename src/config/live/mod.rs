@@ -0,0 +1,65 @@
+use crate::config::{Config, TauConfigError};
+use crate::util::access::AccessList;
+use crate::util::credentials::Credentials;
+use crate::util::listener_auth::ListenerAuth;
+use std::time::Duration;
+
+const DEFAULT_READY_MAX_SILENCE: u64 = 10;
+
+/// The part of the config that is applied to a running server, rebuilt from `tower.toml` on
+/// every reload and swapped in as a whole.
+pub struct Live {
+  pub credentials: Credentials,
+  pub source_access: AccessList,
+  pub listener_access: AccessList,
+  pub listener_auth: ListenerAuth,
+  pub allowed_origins: Option<Vec<String>>,
+  /// Silence after which `/readyz` reports a mount as not ready.
+  pub ready_max_silence: Duration,
+}
+
+impl Live {
+  /// Validates and builds every live setting, so a broken config is never partially applied.
+  /// # Errors
+  /// Returns the first invalid setting.
+  pub fn from_config(config: &Config) -> Result<Self, TauConfigError> {
+    Ok(Self {
+      credentials: Credentials::from_config(config)?,
+      source_access: AccessList::from_config(config.source_access.as_ref())?,
+      listener_access: AccessList::from_config(config.listener_access.as_ref())?,
+      listener_auth: ListenerAuth::from_config(config.listener_auth())?,
+      allowed_origins: config.cors_allow_list.clone(),
+      ready_max_silence: Duration::from_secs(config.ready_max_silence.unwrap_or(DEFAULT_READY_MAX_SILENCE)),
+    })
+  }
+}
+
+/// Names the settings that differ between `running` and `new`, split into those applied live
+/// and those that only take effect after a restart.
+pub fn changed_settings(running: &Config, new: &Config) -> (Vec<&'static str>, Vec<&'static str>) {
+  let live = [
+    ("username", running.username != new.username),
+    ("password", running.password != new.password || running.password_hash != new.password_hash),
+    ("users", running.users != new.users),
+    ("cors_allow_list", running.cors_allow_list != new.cors_allow_list),
+    ("source_access", running.source_access != new.source_access),
+    ("listener_access", running.listener_access != new.listener_access),
+    ("listener_auth", running.listener_auth != new.listener_auth),
+    ("ready_max_silence", running.ready_max_silence != new.ready_max_silence),
+  ];
+  let restart = [
+    ("listen_port", running.listen_port != new.listen_port),
+    ("broadcast_port", running.broadcast_port != new.broadcast_port),
+    ("broadcast_endpoint", running.broadcast_endpoint != new.broadcast_endpoint),
+    ("hooks", running.hooks != new.hooks),
+    ("control_socket", running.control_socket != new.control_socket),
+    ("unix_sockets", running.unix_sockets != new.unix_sockets),
+    ("segment_window", running.segment_window != new.segment_window),
+    ("variants", running.variants != new.variants),
+  ];
+  let names = |settings: &[(&'static str, bool)]| settings.iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(name, _)| *name)
+    .collect();
+  (names(&live), names(&restart))
+}
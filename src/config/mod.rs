@@ -1,19 +1,130 @@
+pub mod live;
+pub mod validate;
+
 use dialoguer::{Input, Password};
 use serde::{Deserialize, Serialize};
-use std::{fs, path::PathBuf};
+use std::{collections::BTreeMap, fs, path::PathBuf};
+use toml::{Table, Value};
+use crate::args::Args;
 use inline_colorization::{color_reset, color_bright_red, color_bright_yellow};
-use crate::util::ip::{validate_port, validate_endpoint, ORIGIN_RE};
+use crate::util::credentials::hash_password;
+use crate::util::ip::{filter_mount_endpoint, validate_port, validate_endpoint, ORIGIN_RE};
+use crate::util::socket::Binding;
+use std::net::{Ipv4Addr, SocketAddr};
+
+const DEFAULT_SOCKET_MODE: u32 = 0o660;
+const DEFAULT_SEGMENT_WINDOW: usize = 10;
 
 
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
-    pub username: String,
-    pub password: String,
+    pub username: Option<String>,
+    /// Legacy plaintext password, superseded by `password_hash`.
+    pub password: Option<String>,
+    /// Argon2 PHC or bcrypt hash of the source password.
+    pub password_hash: Option<String>,
     pub listen_port: u16,
     pub broadcast_port: u16,
     pub cors_allow_list: Option<Vec<String>>,
     pub broadcast_endpoint: String,
+    pub users: Option<Vec<UserConfig>>,
+    pub source_access: Option<AccessConfig>,
+    pub listener_access: Option<AccessConfig>,
+    /// Listener authentication keyed by mount, e.g. `[listener_auth."tau.ogg"]`.
+    pub listener_auth: Option<BTreeMap<String, ListenerAuthConfig>>,
+    pub hooks: Option<Vec<HookConfig>>,
+    /// Unix socket the `status`, `listeners`, ... subcommands connect to.
+    pub control_socket: Option<String>,
+    pub unix_sockets: Option<UnixSocketsConfig>,
+    /// Seconds without audio after which `/readyz` fails, 10 if omitted.
+    pub ready_max_silence: Option<u64>,
+    /// HLS and DASH segments kept in memory per mount, 10 if omitted.
+    pub segment_window: Option<usize>,
+    pub variants: Option<Vec<VariantConfig>>,
+}
+
+/// An entry of the `[[users]]` table, for stations with several hosts streaming to the tower.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub username: String,
+    pub password_hash: Option<String>,
+    /// Hex encoded SHA-256 of a bearer token, see `tau-tower generate-token`.
+    pub token_sha256: Option<String>,
+    /// Mounts the user may stream to, every mount if omitted.
+    pub mounts: Option<Vec<String>>,
+    pub expires: Option<toml::value::Datetime>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "mode", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ListenerAuthConfig {
+    /// HTTP Basic authentication against a list of users.
+    Basic { users: Vec<ListenerUserConfig> },
+    /// URLs signed with `tau-tower sign-url`, carrying `expires` and `sig` query parameters.
+    Signed { secret: String },
+    /// Icecast compatible `url` authentication, see `listener_add` / `listener_remove`.
+    Url { listener_add: String, listener_remove: Option<String> },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerUserConfig {
+    pub username: String,
+    pub password_hash: String,
+}
+
+/// A `[[hooks]]` entry, run on stream lifecycle events.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HookConfig {
+    /// Event kinds to fire on, every event if omitted.
+    pub events: Option<Vec<String>>,
+    /// URL receiving a JSON POST per event.
+    pub webhook: Option<String>,
+    /// Shell command run per event, with the event data in `TAU_*` environment variables.
+    pub command: Option<String>,
+    /// Webhook delivery retries after the first attempt.
+    pub retries: Option<u32>,
+}
+
+/// A `[[variants]]` entry: a mount re-encoding the Opus stream of the source, e.g. at a lower
+/// bitrate for listeners on mobile data.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct VariantConfig {
+    pub endpoint: String,
+    /// Target bitrate in bits per second, from 6000 to 510000.
+    pub bitrate: u32,
+    /// 1 or 2, the channel count of the source if omitted.
+    pub channels: Option<u8>,
+    /// Encoder complexity from 0 to 10, trading quality for CPU time, 10 if omitted.
+    pub complexity: Option<u8>,
+}
+
+/// `[unix_sockets]`, serving the source and/or broadcast side on Unix domain sockets, e.g. for a
+/// reverse proxy on the same host.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UnixSocketsConfig {
+    /// Socket path for the WebSocket source, next to `listen_port`.
+    pub source: Option<String>,
+    /// Socket path for listeners, next to `broadcast_port`.
+    pub broadcast: Option<String>,
+    /// Permissions of the socket files, `0o660` if omitted.
+    pub mode: Option<u32>,
+    /// Whether the TCP ports are bound as well, `true` if omitted.
+    pub tcp: Option<bool>,
+}
+
+/// CIDR allow/deny lists, e.g. `[source_access]` with `allow = ["203.0.113.7/32"]`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AccessConfig {
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -36,12 +147,44 @@ pub enum TauConfigError {
     #[error("invalid endpoint formatting: {0}")]
     InvalidEndpoint(String),
 
+    #[error("invalid password hash: {0}")]
+    InvalidPasswordHash(String),
+
+    #[error("missing config field: {0}")]
+    MissingField(String),
+
+    #[error("{0}")]
+    UnknownKeys(String),
+
+    #[error("invalid CIDR network or IP address: {0}")]
+    InvalidCidr(String),
+
+    #[error("invalid hook: {0}")]
+    InvalidHook(String),
+
+    #[error("invalid unix_sockets: {0}")]
+    InvalidUnixSockets(String),
+
+    #[error("invalid segment_window: {0}")]
+    InvalidSegmentWindow(String),
+
+    #[error("invalid variant: {0}")]
+    InvalidVariant(String),
+
     #[error("user input error: {0}")]
     Input(String),
 }
 
+/// Fields read from their `TAU_TOWER_*` environment variable as a plain string. Every other
+/// field is read as a TOML value such as `8000`, `["*"]` or `{ allow = ["203.0.113.7"] }`.
+const STRING_FIELDS: [&str; 5] = ["username", "password_hash", "broadcast_endpoint", "control_socket", "password"];
+
 impl Config {
-  fn get_config_path() -> PathBuf {
+  /// The `--config` path if given, otherwise `tau/tower.toml` in the user's config directory.
+  pub fn get_config_path(args: &Args) -> PathBuf {
+    if let Some(path) = &args.config {
+      return path.clone();
+    }
     let local_dir = PathBuf::new().join("tau").join("tower.toml");
     match (std::env::var("XDG_CONFIG_HOME"), std::env::var("HOME")) {
       // XDG_CONFIG_HOME
@@ -53,145 +196,324 @@ impl Config {
     }
   }
 
-  /// Merges local config.toml with current CLI arguments if there are any.
-  pub fn merge_cli_args(mut self, args: &crate::args::Args) -> Self {
+  pub fn segment_window(&self) -> usize {
+    self.segment_window.unwrap_or(DEFAULT_SEGMENT_WINDOW)
+  }
+
+  /// The `listener_auth` entries, with the source mount's for each variant that has none of its
+  /// own, so a variant is never more open than the stream it is made from.
+  pub fn listener_auth(&self) -> Vec<(&String, &ListenerAuthConfig)> {
+    let entries = || self.listener_auth.iter().flatten();
+    let find = |endpoint: &str| {
+      let path = filter_mount_endpoint(endpoint).ok();
+      entries().find(|(mount, _)| filter_mount_endpoint(mount).ok() == path).map(|(_, auth)| auth)
+    };
+    let inherited = find(&self.broadcast_endpoint).into_iter().flat_map(|auth| {
+      self.variants.iter().flatten()
+        .filter(|variant| find(&variant.endpoint).is_none())
+        .map(move |variant| (&variant.endpoint, auth))
+    });
+    entries().chain(inherited).collect()
+  }
+
+  /// Path of the control socket, `tau-tower.sock` in `$XDG_RUNTIME_DIR` or the temp directory
+  /// unless `control_socket` is set.
+  pub fn control_socket(&self) -> PathBuf {
+    self.control_socket.as_ref().map_or_else(default_control_socket, PathBuf::from)
+  }
+
+  /// Where the WebSocket source is accepted, see [`UnixSocketsConfig`].
+  pub fn source_binding(&self) -> Binding {
+    let sockets = self.unix_sockets.clone().unwrap_or_default();
+    Binding {
+      tcp: sockets.tcp.unwrap_or(true).then_some(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.listen_port)),
+      unix: sockets.source.map(PathBuf::from),
+      mode: sockets.mode.unwrap_or(DEFAULT_SOCKET_MODE),
+    }
+  }
+
+  /// Where listeners are accepted, see [`UnixSocketsConfig`].
+  pub fn broadcast_binding(&self) -> Binding {
+    let sockets = self.unix_sockets.clone().unwrap_or_default();
+    Binding {
+      tcp: sockets.tcp.unwrap_or(true).then_some(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.broadcast_port)),
+      unix: sockets.broadcast.map(PathBuf::from),
+      mode: sockets.mode.unwrap_or(DEFAULT_SOCKET_MODE),
+    }
+  }
+
+  /// The control socket a client should connect to. Only `control_socket` is read from the
+  /// layers, so the commands talking to a running server work without its secrets.
+  pub fn client_control_socket(args: &Args) -> PathBuf {
+    let from_file = || {
+      let mut table: Table = toml::from_str(&fs::read_to_string(Self::get_config_path(args)).ok()?).ok()?;
+      validate::normalize_keys(&mut table, "").ok()?;
+      table.get("control_socket")?.as_str().map(ToString::to_string)
+    };
+    args.control_socket.clone()
+      .or_else(|| env_nonempty("TAU_TOWER_CONTROL_SOCKET").map(PathBuf::from))
+      .or_else(|| from_file().map(PathBuf::from))
+      .unwrap_or_else(default_control_socket)
+  }
+
+  /// Overlays the current CLI arguments on top of the config table.
+  fn merge_cli_args(table: &mut Table, args: &Args) {
     if let Some(username) = &args.username {
-      self.username.clone_from(username);
+      table.insert("username".into(), username.clone().into());
     }
     if let Some(password) = &args.password {
-      self.password.clone_from(password);
+      set_password(table, password.clone());
     }
     if let Some(listen_port) = args.listen_port {
-      self.listen_port = listen_port;
+      table.insert("listen_port".into(), i64::from(listen_port).into());
     }
     if let Some(broadcast_port) = args.broadcast_port {
-      self.broadcast_port = broadcast_port;
+      table.insert("broadcast_port".into(), i64::from(broadcast_port).into());
     }
     if let Some(endpoint) = &args.broadcast_endpoint {
-      self.broadcast_endpoint.clone_from(endpoint);
+      table.insert("broadcast_endpoint".into(), endpoint.clone().into());
+    }
+    if let Some(origins) = &args.cors_allow_list {
+      table.insert("cors_allow_list".into(), origins.clone().into());
     }
-    if args.cors_allow_list.is_some() {
-      self.cors_allow_list.clone_from(&args.cors_allow_list);
+    if let Some(socket) = &args.control_socket {
+      table.insert("control_socket".into(), socket.display().to_string().into());
     }
-    self
   }
 
-  /// Overlays the source credentials from the environment
-  /// (`TAU_TOWER_USERNAME` / `TAU_TOWER_PASSWORD`) on top of the loaded config.
-  /// This lets the secrets be injected at runtime instead of being written into `tower.toml`.
-  pub fn merge_env(mut self) -> Self {
-    if let Some(username) = env_nonempty("TAU_TOWER_USERNAME") {
-      self.username = username;
-    }
-    if let Some(password) = env_nonempty("TAU_TOWER_PASSWORD") {
-      self.password = password;
+  /// Overlays a `TAU_TOWER_<FIELD>` environment variable for every config field, e.g.
+  /// `TAU_TOWER_PASSWORD` or `TAU_TOWER_LISTEN_PORT=8000`. This lets secrets be injected at
+  /// runtime instead of being written into `tower.toml`, or the file be left out entirely.
+  /// Variables are looked up through `var`, normally [`env_nonempty`].
+  fn merge_env(table: &mut Table, var: impl Fn(&str) -> Option<String>) -> Result<(), TauConfigError> {
+    for &field in validate::fields::<Self>() {
+      let key = format!("TAU_TOWER_{}", field.to_uppercase());
+      let Some(raw) = var(&key) else { continue };
+      if field == "password" {
+        set_password(table, raw);
+        continue;
+      }
+      let value = if STRING_FIELDS.contains(&field) {
+        Value::String(raw)
+      } else {
+        toml::from_str::<Table>(&format!("value = {raw}"))
+          .ok()
+          .and_then(|mut t| t.remove("value"))
+          .ok_or_else(|| TauConfigError::Input(format!("{key} is not a valid TOML value: {raw}")))?
+      };
+      table.insert(field.to_string(), value);
     }
-    self
+    Ok(())
   }
 
-  fn load_config(path: &PathBuf) -> Result<Self, TauConfigError> {
-    let settings = fs::read_to_string(path)?; //.expect("could not read config file");
-    match toml::from_str(&settings) {
-      Ok(config) => Ok(config),
-      Err(e) => Err(TauConfigError::TomlRead(e)),
+  /// Builds the config from a table read from `tower.toml` (or an empty one), with the
+  /// environment and then the CLI arguments layered on top.
+  /// # Errors
+  /// Lists every missing required field, or fails on an invalid value.
+  pub fn from_layers(mut table: Table, args: &Args) -> Result<Self, TauConfigError> {
+    Self::merge_env(&mut table, env_nonempty)?;
+    Self::merge_cli_args(&mut table, args);
+
+    validate::check_unknown_keys(&table, validate::fields::<Self>())?;
+    let missing = missing_fields(&table);
+    if !missing.is_empty() {
+      return Err(TauConfigError::MissingField(missing.join(", ")));
     }
+    // round trip through text, so type errors point at the offending key and value
+    let config: Self = toml::from_str(&toml::to_string(&table)?)?;
+    config.validate()?;
+    Ok(config)
   }
 
-  /// Creates an instance of Config, and reads from the saved `config.toml` file stored on disc.
-  /// If no `config.toml` file can be found, it prompts the user to enter one.
-  pub fn load_or_create(reset: bool) -> Result<Self, TauConfigError> {
-    let path = Self::get_config_path();
-    if path.exists() && !reset {
-      Self::load_config(&path)
-    } else {
-      println!(
-        "\n{color_bright_red}No config found at '{}'. Let's create one: {color_reset}",
+  /// Reads a config file as a table, without prompting for anything.
+  fn read_table(path: &PathBuf) -> Result<Table, TauConfigError> {
+    let settings = fs::read_to_string(path)?;
+    let mut table: Table = toml::from_str(&settings)?;
+    validate::normalize_keys(&mut table, "")?;
+    if table.contains_key("password") && !table.contains_key("password_hash") {
+      eprintln!(
+        "{color_bright_yellow}Warning: plaintext `password` in '{}' is deprecated, \
+        replace it with the output of `tau-tower hash-password` as `password_hash`{color_reset}",
         path.display()
       );
-      println!("{color_bright_yellow}Credentials must correspond to the source stream config{color_reset}\n");
-      let username: String = Input::new()
-        .with_prompt(prompt("Username"))
-        .interact_text()
-        .map_err(|e| TauConfigError::Input(e.to_string()))?;
-
-      let password: String = Password::new()
-        .with_prompt(prompt("Password"))
-        .interact()
-        .map_err(|e| TauConfigError::Input(e.to_string()))?;
-
-      let listen_port: u16 = Input::new()
-        .with_prompt(prompt("Source port"))
-        .default(8000)
-        .interact_text()
-        .map_err(|e| TauConfigError::InvalidPort(e.to_string()))
-        .and_then(validate_port)?;
-      
-      let broadcast_port: u16 = Input::new()
-        .with_prompt(prompt("Broadcast port"))
-        .default(8001)
-        .interact_text()
-        .map_err(|e| TauConfigError::InvalidPort(e.to_string()))
-        .and_then(validate_port)?;
-
-      let broadcast_endpoint = Input::new()
-        .with_prompt(prompt("Mount endpoint"))
-        .default("tau.ogg".to_string())
-        .interact_text()
-        .map_err(|e| TauConfigError::InvalidEndpoint(e.to_string()))
-        .and_then(|x| validate_endpoint(x.as_ref()))?;
-
-      let cors_port: String = Input::new()
-        .with_prompt(prompt("Optional CORS allow list URLs"))
-        .allow_empty(true)
-        .interact_text()
-        .map_err(|e| TauConfigError::InvalidCorsUrl(e.to_string()))?;
-
-        
-      let cors_allow_list = if cors_port.is_empty() {
-        None 
-      } else {
-        let origins: Result<Vec<String>, &str> = cors_port
-          .split_whitespace()
-          .map(|s| if ORIGIN_RE.is_match(s) { 
-            Ok(s.to_string()) 
-          } else {
-            Err(s)
-          })
-          .collect();
-
-        match origins {
-          Ok(urls) => Some(urls),
-          Err(e) => return Err(TauConfigError::InvalidCorsUrl(e.to_string()))
-        }
-      };
+    }
+    Ok(table)
+  }
 
-      let config = Self {
-        username,
-        password,
-        listen_port,
-        broadcast_port,
-        cors_allow_list,
-        broadcast_endpoint,
-      };
+  /// Reads a config file and layers the environment and CLI arguments on top.
+  /// # Errors
+  /// Fails if the file cannot be read or does not make a valid config.
+  pub fn load(path: &PathBuf, args: &Args) -> Result<Self, TauConfigError> {
+    Self::from_layers(Self::read_table(path)?, args)
+  }
 
-      if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
+  /// Creates an instance of Config, reading from the `tower.toml` file stored on disc.
+  /// Without a file, the environment and CLI arguments are used on their own if they hold every
+  /// required field. Otherwise the user is prompted to create one, unless `--no-interactive`.
+  /// # Errors
+  /// Fails on an invalid config, or with the list of missing fields when not interactive.
+  pub fn load_or_create(args: &Args) -> Result<Self, TauConfigError> {
+    let path = Self::get_config_path(args);
+    if path.exists() && !args.reset_config {
+      return Self::load(&path, args);
+    }
+    if !args.reset_config {
+      match Self::from_layers(Table::new(), args) {
+        Err(TauConfigError::MissingField(missing)) if args.no_interactive => {
+          return Err(TauConfigError::MissingField(format!(
+            "{missing} (no config at '{}', set them with CLI flags or TAU_TOWER_* variables)",
+            path.display()
+          )));
+        },
+        Err(TauConfigError::MissingField(_)) => {},
+        res => return res,
       }
+    } else if args.no_interactive {
+      return Err(TauConfigError::Input("--reset-config requires an interactive terminal".to_string()));
+    }
+    let created = Self::create(&path)?;
+    Self::from_layers(Table::try_from(&created)?, args)
+  }
 
-      match toml::to_string_pretty(&config) {
-        Ok(toml_string) => {
-          fs::write(&path, toml_string)?;
-          crate::util::ui::config_file_created_info(&path);
-          Ok(config)
-        },
-        Err(e) => {
-          Err(TauConfigError::TomlWrite(e))
-        }
+  /// Prompts the user for a new config, and writes it to `path`.
+  fn create(path: &PathBuf) -> Result<Self, TauConfigError> {
+    println!(
+      "\n{color_bright_red}No config found at '{}'. Let's create one: {color_reset}",
+      path.display()
+    );
+    println!("{color_bright_yellow}Credentials must correspond to the source stream config{color_reset}\n");
+    let username: String = Input::new()
+      .with_prompt(prompt("Username"))
+      .interact_text()
+      .map_err(|e| TauConfigError::Input(e.to_string()))?;
+
+    let password_hash = hash_password(&prompt_password()?)?;
+
+    let listen_port: u16 = Input::new()
+      .with_prompt(prompt("Source port"))
+      .default(8000)
+      .interact_text()
+      .map_err(|e| TauConfigError::InvalidPort(e.to_string()))
+      .and_then(validate_port)?;
+    
+    let broadcast_port: u16 = Input::new()
+      .with_prompt(prompt("Broadcast port"))
+      .default(8001)
+      .interact_text()
+      .map_err(|e| TauConfigError::InvalidPort(e.to_string()))
+      .and_then(validate_port)?;
+
+    let broadcast_endpoint = Input::new()
+      .with_prompt(prompt("Mount endpoint"))
+      .default("tau.ogg".to_string())
+      .interact_text()
+      .map_err(|e| TauConfigError::InvalidEndpoint(e.to_string()))
+      .and_then(|x| validate_endpoint(x.as_ref()))?;
+
+    let cors_port: String = Input::new()
+      .with_prompt(prompt("Optional CORS allow list URLs"))
+      .allow_empty(true)
+      .interact_text()
+      .map_err(|e| TauConfigError::InvalidCorsUrl(e.to_string()))?;
+
+      
+    let cors_allow_list = if cors_port.is_empty() {
+      None 
+    } else {
+      let origins: Result<Vec<String>, &str> = cors_port
+        .split_whitespace()
+        .map(|s| if ORIGIN_RE.is_match(s) { 
+          Ok(s.to_string()) 
+        } else {
+          Err(s)
+        })
+        .collect();
+
+      match origins {
+        Ok(urls) => Some(urls),
+        Err(e) => return Err(TauConfigError::InvalidCorsUrl(e.to_string()))
+      }
+    };
+
+    let config = Self {
+      username: Some(username),
+      password: None,
+      password_hash: Some(password_hash),
+      listen_port,
+      broadcast_port,
+      cors_allow_list,
+      broadcast_endpoint,
+      users: None,
+      source_access: None,
+      listener_access: None,
+      listener_auth: None,
+      hooks: None,
+      control_socket: None,
+      unix_sockets: None,
+      ready_max_silence: None,
+      segment_window: None,
+      variants: None,
+    };
+
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+
+    match toml::to_string_pretty(&config) {
+      Ok(toml_string) => {
+        fs::write(path, toml_string)?;
+        crate::util::ui::config_file_created_info(path);
+        Ok(config)
+      },
+      Err(e) => {
+        Err(TauConfigError::TomlWrite(e))
       }
     }
   }
 }
 
+/// Sets a plaintext password, which takes over from any `password_hash` of a lower layer.
+fn set_password(table: &mut Table, password: String) {
+  table.insert("password".into(), password.into());
+  table.remove("password_hash");
+}
+
+/// Required fields missing from `table`. Source credentials are satisfied by either a top-level
+/// username and password, or a `[[users]]` table.
+fn missing_fields(table: &Table) -> Vec<String> {
+  let mut missing: Vec<String> = ["listen_port", "broadcast_port", "broadcast_endpoint"]
+    .into_iter()
+    .filter(|field| !table.contains_key(*field))
+    .map(ToString::to_string)
+    .collect();
+  let has_password = table.contains_key("password") || table.contains_key("password_hash");
+  match (table.contains_key("username"), has_password) {
+    _ if table.contains_key("users") => {},
+    (true, true) => {},
+    (true, false) => missing.push("password_hash".to_string()),
+    (false, _) => missing.push("username".to_string()),
+  }
+  missing
+}
+
+/// Prompts for a password twice, without echoing it.
+/// # Errors
+/// Fails if the terminal cannot be read from.
+pub fn prompt_password() -> Result<String, TauConfigError> {
+  Password::new()
+    .with_prompt(prompt("Password"))
+    .with_confirmation(prompt("Repeat password"), "Passwords do not match")
+    .interact()
+    .map_err(|e| TauConfigError::Input(e.to_string()))
+}
+
+/// `tau-tower.sock` in `$XDG_RUNTIME_DIR`, or in the temp directory when it is not set.
+fn default_control_socket() -> PathBuf {
+  env_nonempty("XDG_RUNTIME_DIR")
+    .map_or_else(std::env::temp_dir, PathBuf::from)
+    .join("tau-tower.sock")
+}
+
 /// Reads an environment variable, returning `None` when it is unset or empty.
 fn env_nonempty(key: &str) -> Option<String> {
   std::env::var(key).ok().filter(|value| !value.is_empty())
@@ -200,3 +522,90 @@ fn env_nonempty(key: &str) -> Option<String> {
 fn prompt(msg: &str) -> String {
   format!("{color_bright_yellow}{msg}{color_reset}")
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+  use clap::Parser;
+
+  fn args(flags: &[&str]) -> Args {
+    Args::try_parse_from(std::iter::once("tau-tower").chain(flags.iter().copied())).unwrap()
+  }
+
+  #[test]
+  fn env_is_layered_over_the_file_and_flags_over_both() {
+    let mut table: Table = toml::from_str(
+      "username = \"file\"\npassword_hash = \"$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHQ$aGFzaGhhc2hoYXNoaGFzaA\"\n\
+      listen_port = 8000\nbroadcast_port = 8001\nbroadcast_endpoint = \"tau.ogg\"\n"
+    ).unwrap();
+    let env = HashMap::from([
+      ("TAU_TOWER_USERNAME", "env"),
+      ("TAU_TOWER_PASSWORD", "from env"),
+      ("TAU_TOWER_LISTEN_PORT", "9000"),
+      ("TAU_TOWER_BROADCAST_PORT", "9001"),
+      ("TAU_TOWER_SOURCE_ACCESS", "{ allow = [\"203.0.113.0/24\"] }"),
+    ]);
+    Config::merge_env(&mut table, |key| env.get(key).map(ToString::to_string)).unwrap();
+    Config::merge_cli_args(&mut table, &args(&["--broadcast-port", "9101"]));
+    let config: Config = toml::from_str(&toml::to_string(&table).unwrap()).unwrap();
+
+    assert_eq!(config.username.as_deref(), Some("env"));
+    // a plaintext password from a higher layer replaces the hash of the file
+    assert_eq!((config.password.as_deref(), config.password_hash), (Some("from env"), None));
+    assert_eq!((config.listen_port, config.broadcast_port), (9000, 9101));
+    assert_eq!(config.broadcast_endpoint, "tau.ogg");
+    assert!(config.source_access.is_some());
+
+    let bad = HashMap::from([("TAU_TOWER_LISTEN_PORT", "[8000")]);
+    assert!(Config::merge_env(&mut table, |key| bad.get(key).map(ToString::to_string)).is_err());
+  }
+
+  #[test]
+  fn missing_fields_are_listed() {
+    let table = |text: &str| toml::from_str::<Table>(text).unwrap();
+    assert_eq!(missing_fields(&Table::new()), ["listen_port", "broadcast_port", "broadcast_endpoint", "username"]);
+    let ports = "listen_port = 1\nbroadcast_port = 2\nbroadcast_endpoint = \"tau.ogg\"\n";
+    assert_eq!(missing_fields(&table(&format!("{ports}username = \"u\""))), ["password_hash"]);
+    assert!(missing_fields(&table(&format!("{ports}username = \"u\"\npassword = \"p\""))).is_empty());
+    assert!(missing_fields(&table(&format!("{ports}[[users]]\nusername = \"u\""))).is_empty());
+  }
+
+  #[test]
+  fn variants_inherit_the_listener_auth_of_the_source() {
+    let config: Config = toml::from_str(
+      "listen_port = 1\nbroadcast_port = 2\nbroadcast_endpoint = \"tau.ogg\"\n\
+      [[variants]]\nendpoint = \"tau_low.ogg\"\nbitrate = 24000\n\
+      [[variants]]\nendpoint = \"tau_mid.ogg\"\nbitrate = 64000\n\
+      [listener_auth.\"/tau.ogg\"]\nmode = \"signed\"\nsecret = \"source\"\n\
+      [listener_auth.\"/tau_mid.ogg\"]\nmode = \"signed\"\nsecret = \"mid\"\n"
+    ).unwrap();
+    let secrets: Vec<_> = config.listener_auth().into_iter()
+      .map(|(mount, auth)| match auth {
+        ListenerAuthConfig::Signed { secret } => (mount.as_str(), secret.as_str()),
+        _ => panic!("expected signed auth"),
+      })
+      .collect();
+    assert_eq!(secrets, [("/tau.ogg", "source"), ("/tau_mid.ogg", "mid"), ("tau_low.ogg", "source")]);
+
+    // a public source leaves its variants public
+    let public: Config = toml::from_str(
+      "listen_port = 1\nbroadcast_port = 2\nbroadcast_endpoint = \"tau.ogg\"\n\
+      [[variants]]\nendpoint = \"tau_low.ogg\"\nbitrate = 24000\n"
+    ).unwrap();
+    assert!(public.listener_auth().is_empty());
+  }
+
+  #[test]
+  fn no_interactive_fails_with_the_missing_fields() {
+    let path = std::env::temp_dir().join(format!("tau-tower-test-{}/tower.toml", std::process::id()));
+    let args = args(&["--no-interactive", "-c", path.to_str().unwrap(), "-u", "u", "-l", "8000"]);
+    match Config::load_or_create(&args) {
+      Err(TauConfigError::MissingField(missing)) => {
+        assert!(missing.starts_with("broadcast_port, broadcast_endpoint, password_hash (no config at"));
+      },
+      _ => panic!("expected the missing fields"),
+    }
+    assert!(!path.exists());
+  }
+}
@@ -0,0 +1,277 @@
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use toml::{Table, Value};
+use crate::config::{AccessConfig, Config, HookConfig, TauConfigError, UnixSocketsConfig, UserConfig, VariantConfig};
+use crate::util::ip::{parse_origin, validate_endpoint, validate_port};
+
+/// Known keys of a section of `tower.toml`, read from the config structs so the two cannot drift
+/// apart. Array tables share the path of the array. The keys of `listener_auth` depend on its
+/// `mode`, and are left for serde to check.
+fn known_keys(path: &str) -> Option<&'static [&'static str]> {
+  match path {
+    "users" => Some(fields::<UserConfig>()),
+    "source_access" | "listener_access" => Some(fields::<AccessConfig>()),
+    "hooks" => Some(fields::<HookConfig>()),
+    "unix_sockets" => Some(fields::<UnixSocketsConfig>()),
+    "variants" => Some(fields::<VariantConfig>()),
+    _ => None,
+  }
+}
+
+/// The field names of a struct, as its `Deserialize` impl expects them.
+pub fn fields<T: DeserializeOwned>() -> &'static [&'static str] {
+  let mut names = FieldNames(&[]);
+  let _ = T::deserialize(&mut names);
+  names.0
+}
+
+/// A deserializer that only records the fields it is asked for.
+struct FieldNames(&'static [&'static str]);
+
+impl<'de> Deserializer<'de> for &mut FieldNames {
+  type Error = de::value::Error;
+
+  fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+    Err(de::Error::custom("not a struct"))
+  }
+
+  fn deserialize_struct<V: Visitor<'de>>(
+    self,
+    _: &'static str,
+    fields: &'static [&'static str],
+    _: V
+  ) -> Result<V::Value, Self::Error> {
+    self.0 = fields;
+    Err(de::Error::custom("fields recorded"))
+  }
+
+  serde::forward_to_deserialize_any! {
+    bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+    unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+  }
+}
+
+/// Rewrites kebab-case keys to the `snake_case` the config structs expect, so `broadcast-port`
+/// and `broadcast_port` are read alike. Mount names under `listener_auth` are left untouched.
+/// # Errors
+/// Fails if a key is given in both spellings.
+pub fn normalize_keys(table: &mut Table, path: &str) -> Result<(), TauConfigError> {
+  let keys: Vec<String> = table.keys().cloned().collect();
+  for key in keys {
+    let is_mount = path == "listener_auth";
+    let normalized = if is_mount { key.clone() } else { key.replace('-', "_") };
+    if normalized != key {
+      if table.contains_key(&normalized) {
+        return Err(TauConfigError::Input(format!(
+          "`{}` is set twice, as `{key}` and `{normalized}`", join(path, &normalized)
+        )));
+      }
+      if let Some(value) = table.remove(&key) {
+        table.insert(normalized.clone(), value);
+      }
+    }
+    let child = join(path, if is_mount { "*" } else { &normalized });
+    match table.get_mut(&normalized) {
+      Some(Value::Table(inner)) => normalize_keys(inner, &child)?,
+      Some(Value::Array(items)) => for item in items {
+        if let Value::Table(inner) = item {
+          normalize_keys(inner, &child)?;
+        }
+      },
+      _ => {},
+    }
+  }
+  Ok(())
+}
+
+/// Rejects keys that are not part of the config, suggesting the closest known key.
+/// # Errors
+/// Lists every unknown key found.
+pub fn check_unknown_keys(table: &Table, top_level: &[&str]) -> Result<(), TauConfigError> {
+  let mut unknown = Vec::new();
+  collect_unknown(table, "", top_level, &mut unknown);
+  if unknown.is_empty() {
+    return Ok(());
+  }
+  Err(TauConfigError::UnknownKeys(unknown.join("; ")))
+}
+
+/// Walks `table` at `path`, as shown to the user, against the `known` keys of the section.
+fn collect_unknown(table: &Table, path: &str, known: &[&str], unknown: &mut Vec<String>) {
+  for (key, value) in table {
+    if !known.contains(&key.as_str()) {
+      let hint = closest(key, known)
+        .map(|suggestion| format!(", did you mean `{suggestion}`?"))
+        .unwrap_or_default();
+      unknown.push(format!("unknown key `{}`{hint}", join(path, key)));
+      continue;
+    }
+    let child = join(path, key);
+    let Some(section) = known_keys(&child) else { continue };
+    match value {
+      Value::Table(inner) => collect_unknown(inner, &child, section, unknown),
+      Value::Array(items) => for (i, item) in items.iter().enumerate() {
+        if let Value::Table(inner) = item {
+          collect_unknown(inner, &format!("{child}[{i}]"), section, unknown);
+        }
+      },
+      _ => {},
+    }
+  }
+}
+
+fn join(path: &str, key: &str) -> String {
+  if path.is_empty() { key.to_string() } else { format!("{path}.{key}") }
+}
+
+/// The known key closest to `key`, if it is a plausible typo.
+fn closest<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+  let key = key.replace('-', "_");
+  known.iter()
+    .map(|k| (levenshtein(&key, k), *k))
+    .filter(|(distance, k)| *distance <= 3.max(k.len() / 3))
+    .min_by_key(|(distance, _)| *distance)
+    .map(|(_, k)| k)
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut row: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut diagonal = row[0];
+    row[0] = i + 1;
+    for (j, cb) in b.iter().enumerate() {
+      let above = row[j + 1];
+      row[j + 1] = (above + 1).min(row[j] + 1).min(diagonal + usize::from(ca != *cb));
+      diagonal = above;
+    }
+  }
+  row[b.len()]
+}
+
+impl Config {
+  /// Runs the same checks on the values as the interactive prompt and the CLI parsers do.
+  /// # Errors
+  /// Returns the first invalid value.
+  pub fn validate(&self) -> Result<(), TauConfigError> {
+    validate_port(self.listen_port)?;
+    validate_port(self.broadcast_port)?;
+    let sockets = self.unix_sockets.clone().unwrap_or_default();
+    if sockets.tcp == Some(false) && (sockets.source.is_none() || sockets.broadcast.is_none()) {
+      return Err(TauConfigError::InvalidUnixSockets(
+        "`tcp = false` needs both a `source` and a `broadcast` socket".into()
+      ));
+    }
+    if sockets.mode.is_some_and(|mode| mode > 0o777) {
+      return Err(TauConfigError::InvalidUnixSockets("`mode` must be at most 0o777".into()));
+    }
+    if sockets.source.is_some() && sockets.source == sockets.broadcast {
+      return Err(TauConfigError::InvalidUnixSockets("`source` and `broadcast` are the same path".into()));
+    }
+    if sockets.tcp != Some(false) && self.listen_port == self.broadcast_port {
+      return Err(TauConfigError::InvalidPort(format!(
+        "listen_port and broadcast_port are both {}", self.listen_port
+      )));
+    }
+    if self.segment_window.is_some_and(|window| window < 3) {
+      return Err(TauConfigError::InvalidSegmentWindow("at least 3 segments are needed for live playback".into()));
+    }
+    validate_endpoint(&self.broadcast_endpoint)?;
+    for origin in self.cors_allow_list.iter().flatten() {
+      parse_origin(origin).map_err(|_| TauConfigError::InvalidCorsUrl(origin.clone()))?;
+    }
+    self.validate_variants()
+  }
+
+  /// Each variant needs a mount of its own, whose WebM stream and segments do not overlap those
+  /// of another mount, and encoder settings libopus accepts.
+  fn validate_variants(&self) -> Result<(), TauConfigError> {
+    let stem = |endpoint: &str| {
+      let endpoint = endpoint.trim_start_matches('/');
+      endpoint.strip_suffix(".ogg").or_else(|| endpoint.strip_suffix(".webm")).unwrap_or(endpoint).to_string()
+    };
+    let mut stems = vec![(stem(&self.broadcast_endpoint), &self.broadcast_endpoint)];
+    for variant in self.variants.iter().flatten() {
+      validate_endpoint(&variant.endpoint)?;
+      let invalid = |msg: String| TauConfigError::InvalidVariant(format!("{}: {msg}", variant.endpoint));
+      let variant_stem = stem(&variant.endpoint);
+      if let Some((_, other)) = stems.iter().find(|(stem, _)| *stem == variant_stem) {
+        return Err(invalid(format!("clashes with the mount {other}")));
+      }
+      if !(6_000..=510_000).contains(&variant.bitrate) {
+        return Err(invalid(format!("bitrate {} is not between 6000 and 510000", variant.bitrate)));
+      }
+      if let Some(channels) = variant.channels.filter(|c| !(1..=2).contains(c)) {
+        return Err(invalid(format!("{channels} channels, only 1 or 2 can be encoded")));
+      }
+      if let Some(complexity) = variant.complexity.filter(|c| *c > 10) {
+        return Err(invalid(format!("complexity {complexity} is above 10")));
+      }
+      stems.push((variant_stem, &variant.endpoint));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TOP: [&str; 3] = ["broadcast_port", "listen_port", "source_access"];
+
+  #[test]
+  fn kebab_case_is_normalized() {
+    let mut table: Table = toml::from_str("broadcast-port = 1\n[source-access]\nallow = []").unwrap();
+    normalize_keys(&mut table, "").unwrap();
+    assert!(table.contains_key("broadcast_port") && table.contains_key("source_access"));
+    assert!(check_unknown_keys(&table, &TOP).is_ok());
+
+    let mut twice: Table = toml::from_str("broadcast-port = 1\nbroadcast_port = 2").unwrap();
+    assert!(normalize_keys(&mut twice, "").is_err());
+  }
+
+  #[test]
+  fn unknown_keys_get_suggestions() {
+    let table: Table = toml::from_str("broadcast_prot = 1\n[source_access]\nalow = []").unwrap();
+    let Err(TauConfigError::UnknownKeys(msg)) = check_unknown_keys(&table, &TOP) else { panic!() };
+    assert!(msg.contains("`broadcast_prot`, did you mean `broadcast_port`?"));
+    assert!(msg.contains("`source_access.alow`, did you mean `allow`?"));
+
+    // the keys of listener_auth depend on the mode, serde rejects the unknown ones
+    let text = "[listener_auth.\"tau.ogg\"]\nmode = \"signed\"\nsecert = \"x\"";
+    assert!(check_unknown_keys(&toml::from_str(text).unwrap(), &["listener_auth"]).is_ok());
+    let err = toml::from_str::<Table>(text).unwrap()["listener_auth"].clone()
+      .try_into::<std::collections::BTreeMap<String, crate::config::ListenerAuthConfig>>()
+      .unwrap_err();
+    assert!(err.to_string().contains("unknown field `secert`, expected `secret`"));
+  }
+
+  #[test]
+  fn variants_are_checked() {
+    let config = |variant: &str| toml::from_str::<Config>(&format!(
+      "username = \"u\"\npassword = \"p\"\nlisten_port = 1\nbroadcast_port = 2\n\
+      broadcast_endpoint = \"tau.ogg\"\n[[variants]]\n{variant}"
+    )).unwrap().validate();
+    assert!(config("endpoint = \"tau_low.ogg\"\nbitrate = 24000\nchannels = 1\ncomplexity = 10").is_ok());
+    for (variant, error) in [
+      // the WebM stream and segments of both would be at /tau
+      ("endpoint = \"/tau.webm\"\nbitrate = 24000", "clashes with the mount tau.ogg"),
+      ("endpoint = \"tau_low.ogg\"\nbitrate = 5000", "bitrate 5000"),
+      ("endpoint = \"tau_low.ogg\"\nbitrate = 24000\nchannels = 3", "3 channels"),
+      ("endpoint = \"tau_low.ogg\"\nbitrate = 24000\ncomplexity = 11", "complexity 11"),
+    ] {
+      let Err(TauConfigError::InvalidVariant(msg)) = config(variant) else { panic!("{variant}") };
+      assert!(msg.contains(error), "{msg}");
+    }
+    let twice = "endpoint = \"tau_low.ogg\"\nbitrate = 24000\n[[variants]]\nendpoint = \"tau_low\"\nbitrate = 32000";
+    let Err(TauConfigError::InvalidVariant(msg)) = config(twice) else { panic!() };
+    assert!(msg.contains("clashes with the mount tau_low.ogg"), "{msg}");
+  }
+
+  #[test]
+  fn known_keys_are_read_from_the_structs() {
+    assert_eq!(fields::<VariantConfig>(), ["endpoint", "bitrate", "channels", "complexity"]);
+    assert!(fields::<Config>().contains(&"segment_window"));
+    assert_eq!(known_keys("listener_access"), Some(fields::<AccessConfig>()));
+    assert_eq!(known_keys("listener_auth"), None);
+  }
+}
@@ -0,0 +1,115 @@
+use std::path::Path;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use inline_colorization::{color_reset, color_bright_yellow};
+use crate::mount::{ListenerInfo, SourceInfo};
+use crate::mount::clock::ClockInfo;
+use crate::util::ogg_headers::Codec;
+use crate::util::opus_headers::{OpusHead, OpusTags};
+use crate::util::ui::{listeners_info, status_info};
+
+/// A command sent to the running server over the control socket, as one line of JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+  Status,
+  Listeners,
+  Kick { id: u64 },
+  SetMetadata { mount: Option<String>, comments: Vec<(String, String)> },
+  Reload,
+}
+
+/// The answer to a [`Request`], as one line of JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+pub enum Reply {
+  Status(Box<Status>),
+  Listeners { listeners: Vec<ListenerInfo> },
+  Kicked { id: u64 },
+  MetadataSet { mount: String },
+  Reloaded { applied: Vec<String>, restart: Vec<String> },
+  Error { message: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+  pub version: String,
+  pub uptime_secs: u64,
+  pub mount: String,
+  pub source: Option<SourceInfo>,
+  /// Listeners of the mount and of its variants.
+  pub listeners: usize,
+  /// Whether the Ogg headers of the source have been received, so listeners can be served.
+  pub headers_received: bool,
+  /// The clock of the source's stream, once audio has been received.
+  #[serde(default)]
+  pub clock: Option<ClockInfo>,
+  #[serde(default)]
+  pub codec: Option<Codec>,
+  /// The source's Opus stream headers, if received and valid.
+  #[serde(default)]
+  pub opus_head: Option<OpusHead>,
+  #[serde(default)]
+  pub opus_tags: Option<OpusTags>,
+  /// The ICY title of an MP3 or AAC stream.
+  #[serde(default)]
+  pub stream_title: Option<String>,
+  #[serde(default)]
+  pub variants: Vec<VariantStatus>,
+}
+
+/// A mount transcoded from the source's, see `[[variants]]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VariantStatus {
+  pub mount: String,
+  pub bitrate: u32,
+  /// The channel count of the source if `None`.
+  pub channels: Option<u8>,
+  pub complexity: u8,
+  /// The clock of the variant's stream, while the source is transcoded.
+  pub clock: Option<ClockInfo>,
+}
+
+/// Sends `request` to the server listening on `socket` and prints its reply.
+/// # Errors
+/// Fails if the server cannot be reached, or replies with an error.
+pub async fn run(socket: &Path, request: &Request) -> anyhow::Result<()> {
+  match send(socket, request).await? {
+    Reply::Status(status) => status_info(&status),
+    Reply::Listeners { listeners } => listeners_info(&listeners),
+    Reply::Kicked { id } => println!("Kicked listener {id}"),
+    Reply::MetadataSet { mount } => println!("Metadata of {mount} updated for new listeners"),
+    Reply::Reloaded { applied, restart } => {
+      println!("Config reloaded");
+      if !applied.is_empty() {
+        println!("Applied: {}", applied.join(", "));
+      }
+      if !restart.is_empty() {
+        println!("{color_bright_yellow}Requires a restart to take effect: {}{color_reset}", restart.join(", "));
+      }
+    },
+    Reply::Error { message } => anyhow::bail!(message),
+  }
+  Ok(())
+}
+
+#[cfg(unix)]
+async fn send(socket: &Path, request: &Request) -> anyhow::Result<Reply> {
+  use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+  let stream = tokio::net::UnixStream::connect(socket).await
+    .with_context(|| format!("could not connect to '{}', is tau-tower running?", socket.display()))?;
+  let (read, mut write) = stream.into_split();
+  let mut line = serde_json::to_string(request)?;
+  line.push('\n');
+  write.write_all(line.as_bytes()).await?;
+
+  let mut reply = String::new();
+  BufReader::new(read).read_line(&mut reply).await?;
+  serde_json::from_str(&reply).context("invalid reply from the control socket")
+}
+
+#[cfg(not(unix))]
+async fn send(_socket: &Path, _request: &Request) -> anyhow::Result<Reply> {
+  anyhow::bail!("the control socket is only available on unix")
+}
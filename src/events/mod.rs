@@ -0,0 +1,121 @@
+use std::net::SocketAddr;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+/// Stream lifecycle events, serialized as the JSON payload of webhooks.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+  SourceConnected { mount: String, username: String, remote_addr: String },
+  SourceDisconnected { mount: String, remote_addr: String },
+  HeadersChanged { mount: String },
+  MetadataUpdated { mount: String },
+  ListenerJoined { mount: String, remote_addr: String, user_agent: Option<String> },
+  ListenerLeft { mount: String, remote_addr: String, duration_secs: u64 },
+  MountIdle { mount: String, idle_secs: u64 },
+}
+
+impl Event {
+  /// The `event` tag, as used in the `events` filter of a hook.
+  pub const fn kind(&self) -> &'static str {
+    match self {
+      Self::SourceConnected { .. } => "source_connected",
+      Self::SourceDisconnected { .. } => "source_disconnected",
+      Self::HeadersChanged { .. } => "headers_changed",
+      Self::MetadataUpdated { .. } => "metadata_updated",
+      Self::ListenerJoined { .. } => "listener_joined",
+      Self::ListenerLeft { .. } => "listener_left",
+      Self::MountIdle { .. } => "mount_idle",
+    }
+  }
+}
+
+pub const EVENT_KINDS: [&str; 7] = [
+  "source_connected",
+  "source_disconnected",
+  "headers_changed",
+  "metadata_updated",
+  "listener_joined",
+  "listener_left",
+  "mount_idle",
+];
+
+/// Fan-out of [`Event`]s to the hook sinks. Emitting without any subscriber is a no-op.
+#[derive(Clone)]
+pub struct Events {
+  tx: broadcast::Sender<Event>,
+}
+
+impl Events {
+  pub fn new() -> Self {
+    let (tx, _) = broadcast::channel(256);
+    Self { tx }
+  }
+
+  pub fn emit(&self, event: Event) {
+    let _ = self.tx.send(event);
+  }
+
+  pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+    self.tx.subscribe()
+  }
+}
+
+/// Emits [`Event::ListenerJoined`] when created and [`Event::ListenerLeft`] when dropped, so it
+/// can be tied to the lifetime of a listener's response body.
+pub struct ListenerSession {
+  events: Events,
+  mount: String,
+  remote_addr: String,
+  started: Instant,
+}
+
+impl ListenerSession {
+  pub fn start(events: &Events, mount: &str, remote_addr: SocketAddr, user_agent: Option<String>) -> Self {
+    events.emit(Event::ListenerJoined {
+      mount: mount.to_string(),
+      remote_addr: remote_addr.to_string(),
+      user_agent,
+    });
+    Self {
+      events: events.clone(),
+      mount: mount.to_string(),
+      remote_addr: remote_addr.to_string(),
+      started: Instant::now(),
+    }
+  }
+}
+
+impl Drop for ListenerSession {
+  fn drop(&mut self) {
+    self.events.emit(Event::ListenerLeft {
+      mount: std::mem::take(&mut self.mount),
+      remote_addr: std::mem::take(&mut self.remote_addr),
+      duration_secs: self.started.elapsed().as_secs(),
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn kinds_match_the_serialized_tags() {
+    let mount = String::new;
+    let events = [
+      Event::SourceConnected { mount: mount(), username: mount(), remote_addr: mount() },
+      Event::SourceDisconnected { mount: mount(), remote_addr: mount() },
+      Event::HeadersChanged { mount: mount() },
+      Event::MetadataUpdated { mount: mount() },
+      Event::ListenerJoined { mount: mount(), remote_addr: mount(), user_agent: None },
+      Event::ListenerLeft { mount: mount(), remote_addr: mount(), duration_secs: 0 },
+      Event::MountIdle { mount: mount(), idle_secs: 0 },
+    ];
+    assert_eq!(events.each_ref().map(Event::kind), EVENT_KINDS);
+    for event in &events {
+      assert_eq!(serde_json::to_value(event).unwrap()["event"], event.kind());
+    }
+  }
+}
@@ -0,0 +1,234 @@
+//! Fragmented MP4 (ISO BMFF) for Opus, as specified by "Encapsulation of Opus in ISO Base Media
+//! File Format". The Opus packets are remuxed as they are, one sample per packet, on a 48 kHz
+//! timescale.
+
+use hyper::body::Bytes;
+use crate::util::opus::SAMPLE_RATE;
+use crate::util::opus_headers::OpusHead;
+
+const TRACK_ID: u32 = 1;
+
+/// An Opus packet and its duration in 48 kHz samples.
+pub struct Sample {
+  pub duration: u32,
+  pub data: Bytes,
+}
+
+/// The initialization segment: `ftyp` and a `moov` describing a single Opus track, with the
+/// `dOps` box built from the `OpusHead` packet. `None` if `opus_head` is not a valid `OpusHead`.
+pub fn init_segment(opus_head: &[u8]) -> Option<Bytes> {
+  let head = OpusHead::parse(opus_head).ok()?;
+  let d_ops = d_ops(&head);
+  let channels = u16::from(head.channels);
+
+  let mut out = Vec::new();
+  write_box(&mut out, *b"ftyp", |b| {
+    b.extend_from_slice(b"iso6");
+    b.extend_from_slice(&0u32.to_be_bytes());
+    b.extend_from_slice(b"iso6mp41");
+  });
+  write_box(&mut out, *b"moov", |b| {
+    write_full_box(b, *b"mvhd", 0, 0, |b| {
+      // creation and modification time, timescale, duration
+      put_u32s(b, &[0, 0, 1000, 0]);
+      // rate 1.0, volume 1.0 and reserved
+      b.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+      b.extend_from_slice(&0x0100u16.to_be_bytes());
+      b.extend_from_slice(&[0; 10]);
+      put_matrix(b);
+      // pre_defined
+      b.extend_from_slice(&[0; 24]);
+      b.extend_from_slice(&(TRACK_ID + 1).to_be_bytes());
+    });
+    write_box(b, *b"trak", |b| {
+      // enabled and in movie
+      write_full_box(b, *b"tkhd", 0, 0x03, |b| {
+        put_u32s(b, &[0, 0, TRACK_ID, 0, 0, 0, 0]);
+        // layer and alternate group
+        b.extend_from_slice(&[0; 4]);
+        b.extend_from_slice(&0x0100u16.to_be_bytes());
+        b.extend_from_slice(&[0; 2]);
+        put_matrix(b);
+        // width and height
+        put_u32s(b, &[0, 0]);
+      });
+      write_box(b, *b"mdia", |b| {
+        write_full_box(b, *b"mdhd", 0, 0, |b| {
+          put_u32s(b, &[0, 0, SAMPLE_RATE, 0]);
+          // language `und`, packed as three 5-bit letters
+          b.extend_from_slice(&0x55c4u16.to_be_bytes());
+          b.extend_from_slice(&[0; 2]);
+        });
+        write_full_box(b, *b"hdlr", 0, 0, |b| {
+          b.extend_from_slice(&[0; 4]);
+          b.extend_from_slice(b"soun");
+          b.extend_from_slice(&[0; 12]);
+          b.extend_from_slice(b"SoundHandler\0");
+        });
+        write_box(b, *b"minf", |b| {
+          // balance and reserved
+          write_full_box(b, *b"smhd", 0, 0, |b| b.extend_from_slice(&[0; 4]));
+          write_box(b, *b"dinf", |b| {
+            write_full_box(b, *b"dref", 0, 0, |b| {
+              b.extend_from_slice(&1u32.to_be_bytes());
+              // media data in the same file
+              write_full_box(b, *b"url ", 0, 0x01, |_| {});
+            });
+          });
+          write_box(b, *b"stbl", |b| {
+            write_full_box(b, *b"stsd", 0, 0, |b| {
+              b.extend_from_slice(&1u32.to_be_bytes());
+              write_box(b, *b"Opus", |b| {
+                // reserved and data reference index
+                b.extend_from_slice(&[0; 6]);
+                b.extend_from_slice(&1u16.to_be_bytes());
+                b.extend_from_slice(&[0; 8]);
+                b.extend_from_slice(&channels.to_be_bytes());
+                // sample size, pre_defined and reserved
+                b.extend_from_slice(&16u16.to_be_bytes());
+                b.extend_from_slice(&[0; 4]);
+                b.extend_from_slice(&(SAMPLE_RATE << 16).to_be_bytes());
+                write_box(b, *b"dOps", |b| b.extend_from_slice(&d_ops));
+              });
+            });
+            // the samples are all in the fragments
+            write_full_box(b, *b"stts", 0, 0, |b| put_u32s(b, &[0]));
+            write_full_box(b, *b"stsc", 0, 0, |b| put_u32s(b, &[0]));
+            write_full_box(b, *b"stsz", 0, 0, |b| put_u32s(b, &[0, 0]));
+            write_full_box(b, *b"stco", 0, 0, |b| put_u32s(b, &[0]));
+          });
+        });
+      });
+    });
+    write_box(b, *b"mvex", |b| {
+      write_full_box(b, *b"trex", 0, 0, |b| put_u32s(b, &[TRACK_ID, 1, 0, 0, 0]));
+    });
+  });
+  Some(Bytes::from(out))
+}
+
+/// A media segment, `moof` and `mdat`, holding `samples` starting at `decode_time`.
+pub fn media_segment(sequence: u32, decode_time: u64, samples: &[Sample]) -> Bytes {
+  let count = u32::try_from(samples.len()).unwrap_or(u32::MAX);
+  let mut out = Vec::new();
+  write_box(&mut out, *b"moof", |b| {
+    write_full_box(b, *b"mfhd", 0, 0, |b| put_u32s(b, &[sequence]));
+    write_box(b, *b"traf", |b| {
+      // default-base-is-moof
+      write_full_box(b, *b"tfhd", 0, 0x02_0000, |b| put_u32s(b, &[TRACK_ID]));
+      write_full_box(b, *b"tfdt", 1, 0, |b| b.extend_from_slice(&decode_time.to_be_bytes()));
+      // data offset, sample duration and sample size present
+      write_full_box(b, *b"trun", 0, 0x00_0301, |b| {
+        // the data offset is patched in below, once the size of the moof is known
+        put_u32s(b, &[count, 0]);
+        for sample in samples {
+          let size = u32::try_from(sample.data.len()).unwrap_or(u32::MAX);
+          put_u32s(b, &[sample.duration, size]);
+        }
+      });
+    });
+  });
+  // the first sample follows the moof and the mdat header
+  let data_offset = u32::try_from(out.len() + 8).unwrap_or(u32::MAX);
+  // moof, mfhd, traf, tfhd, tfdt (version 1) and the trun fields up to the data offset
+  let at = 8 + 16 + 8 + 16 + 20 + 16;
+  out[at..at + 4].copy_from_slice(&data_offset.to_be_bytes());
+
+  write_box(&mut out, *b"mdat", |b| {
+    for sample in samples {
+      b.extend_from_slice(&sample.data);
+    }
+  });
+  Bytes::from(out)
+}
+
+/// The `dOps` box payload: `OpusHead` without its magic signature, with a version of 0 and
+/// big-endian fields.
+fn d_ops(head: &OpusHead) -> Vec<u8> {
+  let mut out = vec![0, head.channels];
+  out.extend_from_slice(&head.pre_skip.to_be_bytes());
+  out.extend_from_slice(&head.input_sample_rate.to_be_bytes());
+  out.extend_from_slice(&head.output_gain.to_be_bytes());
+  out.push(head.mapping_family);
+  if let Some(mapping) = &head.mapping {
+    out.extend_from_slice(&[mapping.stream_count, mapping.coupled_count]);
+    out.extend_from_slice(&mapping.table);
+  }
+  out
+}
+
+fn write_box(out: &mut Vec<u8>, kind: [u8; 4], content: impl FnOnce(&mut Vec<u8>)) {
+  let start = out.len();
+  out.extend_from_slice(&[0; 4]);
+  out.extend_from_slice(&kind);
+  content(out);
+  let size = u32::try_from(out.len() - start).unwrap_or(u32::MAX);
+  out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(out: &mut Vec<u8>, kind: [u8; 4], version: u8, flags: u32, content: impl FnOnce(&mut Vec<u8>)) {
+  write_box(out, kind, |b| {
+    b.extend_from_slice(&((u32::from(version) << 24) | flags).to_be_bytes());
+    content(b);
+  });
+}
+
+fn put_u32s(out: &mut Vec<u8>, values: &[u32]) {
+  for value in values {
+    out.extend_from_slice(&value.to_be_bytes());
+  }
+}
+
+/// The identity transformation matrix of `mvhd` and `tkhd`.
+fn put_matrix(out: &mut Vec<u8>) {
+  put_u32s(out, &[0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000]);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const HEAD: &[u8] = b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00";
+
+  /// The boxes at the top level of `data`, as kind and size.
+  fn boxes(data: &[u8]) -> Vec<(String, usize)> {
+    let mut boxes = Vec::new();
+    let mut at = 0;
+    while at < data.len() {
+      let size = u32::from_be_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+      boxes.push((String::from_utf8_lossy(&data[at + 4..at + 8]).into_owned(), size));
+      at += size;
+    }
+    assert_eq!(at, data.len());
+    boxes
+  }
+
+  #[test]
+  fn builds_dops_from_opus_head() {
+    let payload = d_ops(&OpusHead::parse(HEAD).unwrap());
+    assert_eq!(payload, [0, 2, 0x01, 0x38, 0, 0, 0xbb, 0x80, 0, 0, 0]);
+    assert!(init_segment(b"OpusTags").is_none());
+
+    let init = init_segment(HEAD).unwrap();
+    let kinds: Vec<_> = boxes(&init).into_iter().map(|(kind, _)| kind).collect();
+    assert_eq!(kinds, ["ftyp", "moov"]);
+    let at = init.windows(4).position(|w| w == b"dOps").unwrap();
+    assert_eq!(&init[at + 4..at + 15], payload.as_slice());
+  }
+
+  #[test]
+  fn points_trun_at_the_mdat_payload() {
+    let samples = [
+      Sample { duration: 960, data: Bytes::from_static(b"abc") },
+      Sample { duration: 480, data: Bytes::from_static(b"de") },
+    ];
+    let segment = media_segment(7, 96_000, &samples);
+    let boxes = boxes(&segment);
+    assert_eq!(boxes[0].0, "moof");
+    assert_eq!(boxes[1], ("mdat".to_string(), 13));
+
+    let at = segment.windows(4).position(|w| w == b"trun").unwrap();
+    let offset = u32::from_be_bytes(segment[at + 12..at + 16].try_into().unwrap()) as usize;
+    assert_eq!(&segment[offset..], b"abcde");
+  }
+}
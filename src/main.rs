@@ -8,39 +8,64 @@
 #![warn(clippy::pedantic)]
 #![warn(clippy::nursery)]
 
+mod control;
+mod events;
+mod fmp4;
+mod mount;
 mod server;
 mod threads;
+mod transcode;
+mod webm;
 mod config;
 mod args;
 mod util;
 
-use std::net::{Ipv4Addr, SocketAddr};
-use anyhow::Ok;
-use tokio::sync::{RwLock, broadcast};
+use std::net::Ipv4Addr;
+use anyhow::{Context, Ok};
 use tokio::task;
 use std::sync::Arc;
-use hyper::body::Bytes;
 use clap::Parser;
 
-use crate::threads::{http, ws};
-use crate::util::credentials::Credentials;
-use crate::util::ui::server_started_info;
-use crate::config::Config;
-use crate::args::Args;
+use crate::server::ServerState;
+use crate::server::health::Health;
+use crate::events::Events;
+use crate::mount::Mount;
+use crate::transcode::Variant;
+use crate::threads::{hooks, http, reload, ws};
+use crate::threads::hooks::Hook;
+use crate::util::listener_auth::{sign_url, unix_now};
+use crate::util::credentials::{generate_token, hash_password};
+use crate::util::ui::{server_started_info, unix_socket_info};
+use crate::config::{Config, ListenerAuthConfig, prompt_password};
+use crate::config::live::Live;
+use arc_swap::ArcSwap;
+use crate::args::{Args, Command, MetadataCommand};
+use crate::control::Request;
 use crate::util::ip::{filter_mount_endpoint};
+use crate::util::socket::{Address, Listener};
+#[cfg(unix)]
+use crate::util::socket::UnixSocket;
+use crate::util::systemd;
 
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
   let args = Args::parse();
-  let config = Config::load_or_create(args.reset_config)
-    .map(Config::merge_env)
-    .map(|c| c.merge_cli_args(&args))?;
+  match &args.command {
+    None => serve(&args).await,
+    Some(command) => run_command(command, &args).await,
+  }
+}
 
-  let credentials = Credentials{
-    username: config.username.clone(), 
-    password: config.password.clone(),
-  };
+/// Runs the server until `ctrl_c` or SIGTERM, or until the source or broadcast task fails.
+async fn serve(args: &Args) -> anyhow::Result<()> {
+  let config = Config::load_or_create(args)?;
+
+  /*
+   * Credentials, access lists, listener auth and CORS origins are held behind an `ArcSwap`,
+   * so a reloaded config can be applied without dropping any connection.
+   */
+  let live = Arc::new(ArcSwap::from_pointee(Live::from_config(&config)?));
 
   /* 
    * Set the endpoint where the broadcast is served from this server
@@ -52,72 +77,113 @@ async fn main() -> anyhow::Result<()> {
   })?;
 
   /*
-   * Headers container to store OggOpus headers from source broadcast, for rebroadcasting 
-   * when a listener connects to this servers stream.
-   */
-  let header = Arc::new(RwLock::new(None)); 
-
-  /* 
    * Single producer - multiple identical streams. Used to capture the audio signal from the 
-   * broadcasting source to each listener of this server. 
-   * */ 
-  let (tx, _) = broadcast::channel::<Bytes>(1024);
+   * broadcasting source to each listener of this server, together with the OggOpus headers
+   * from the source, for rebroadcasting when a listener connects to this servers stream.
+   */
+  let mount = Mount::new(mount, config.segment_window());
+  let variants = variants(&config, &mount)?;
 
   let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
   // remote source address: 
   // let ip = Ipv4Addr::from_str(&config.ip).context("Invalid IP in config")?;
 
-  // local listening and broadcasting addresses:
-  let local_ip = std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED);
-  let listen_addr = SocketAddr::new(local_ip, config.listen_port);
-  let server_addr = SocketAddr::new(local_ip, config.broadcast_port);
-
-/*
-   * If Config contains a certain localhost port for CORS, the tautower server will restrict it to
-   * that port.
+  /*
+   * Local listening and broadcasting sockets, TCP and/or Unix domain sockets. Sockets passed by
+   * systemd socket activation take the place of those in the config.
    */
+  let activated = systemd::listen_fds()?;
+  let source_listener = match activated.source {
+    Some(listener) => listener,
+    None => Listener::bind(&config.source_binding()).await?,
+  };
+  let broadcast_listener = match activated.broadcast {
+    Some(listener) => listener,
+    None => Listener::bind(&config.broadcast_binding()).await?,
+  };
+  let paths: Vec<&str> = std::iter::once(mount.path).chain(variants.iter().map(|v| v.mount.path)).collect();
+  print_addresses(&broadcast_listener, &paths);
 
-  let allowed_origins: Arc<Option<Vec<&str>>> = Arc::new(
-    config.cors_allow_list.map(|origins| {
-      origins .into_iter()
-        .map(|s| 
-          Box::leak(s.into_boxed_str()) as &'static str
-        )
-      .collect() 
-    })
-  );
+  /* Lifecycle events, delivered to the configured webhooks and commands */
+  let hooks = config.hooks.iter()
+    .flatten()
+    .map(Hook::from_config)
+    .collect::<Result<Vec<_>, _>>()?;
+  let events = Events::new();
+  task::spawn(hooks::thread(hooks, events.subscribe(), shutdown_rx.clone()));
+  task::spawn(threads::systemd::thread(events.subscribe(), shutdown_rx.clone()));
+
+  /* Tracks whether the receiving task accepts sources, for `/healthz` */
+  let health = Health::default();
 
   /* Receiving task, listens to remote stream over WebSocket */
   let listener_task = task::spawn({
     let shutdown_rx = shutdown_rx.clone();
-    let tx = tx.clone(); 
-    let header = header.clone();
     ws::thread(
-      tx,
-      listen_addr,
-      credentials,
-      header,
+      source_listener,
+      mount.clone(),
+      live.clone(),
+      events.clone(),
+      health.clone(),
       shutdown_rx
     )
   });
 
+  /* Remuxes the source into fragmented MP4 segments for HLS */
+  task::spawn(threads::segmenter::thread(mount.clone(), shutdown_rx.clone()));
+
+  /* Transcodes the source for each variant, which is remuxed for HLS like the source */
+  for variant in &variants {
+    task::spawn(threads::transcoder::thread(mount.clone(), variant.clone(), events.clone(), shutdown_rx.clone()));
+    task::spawn(threads::segmenter::thread(variant.mount.clone(), shutdown_rx.clone()));
+  }
+
   /* Broadcasting task, broadcasts to all listeners over an http media stream */
   let server_task = task::spawn({
     http::thread(
-      server_addr,
-      tx,
-      header,
-      mount,
-      allowed_origins,
-      shutdown_rx
+      broadcast_listener,
+      ServerState {
+        mount: mount.clone(),
+        variants: variants.iter().map(|variant| variant.mount.clone()).collect(),
+        live: live.clone(),
+        events: events.clone(),
+        health,
+      },
+      shutdown_rx.clone()
     )
   });
 
-  server_started_info(
-    std::net::IpAddr::V4(Ipv4Addr::LOCALHOST),
-    config.broadcast_port,
-    mount
-  );
+  /* Reloads tower.toml on SIGHUP, `tau-tower reload` or when it changes on disk */
+  let (reload_tx, reload_rx) = tokio::sync::mpsc::channel(4);
+  task::spawn(reload::thread(
+    Config::get_config_path(args),
+    args.clone(),
+    config.clone(),
+    live,
+    reload_rx,
+    shutdown_rx.clone()
+  ));
+
+  /* Local control socket for the `status`, `listeners`, `kick`, ... subcommands */
+  #[cfg(unix)]
+  let control_task = task::spawn(threads::control::thread(
+    UnixSocket::bind(config.control_socket(), 0o600)?,
+    threads::control::ControlState {
+      mount: mount.clone(),
+      variants,
+      events,
+      started: tokio::time::Instant::now(),
+      reload_tx,
+    },
+    shutdown_rx
+  ));
+  #[cfg(not(unix))]
+  let control_task = task::spawn(std::future::pending::<anyhow::Result<()>>());
+  #[cfg(not(unix))]
+  drop((reload_tx, events, variants));
+
+  systemd::ready();
+  systemd::status(&threads::systemd::waiting_status(mount.path));
 
   /*
    * Server will shut down if ctrl_c or error in either task is throwed. 
@@ -127,10 +193,112 @@ async fn main() -> anyhow::Result<()> {
   tokio::select! {
     res = listener_task => { res??; },
     res = server_task   => { res??; },
-    _ = tokio::signal::ctrl_c() => {
+    res = control_task  => { res??; },
+    res = shutdown_signal() => {
+      res?;
+      systemd::stopping();
       shutdown_tx.send_replace(true);
       println!("\n\rShutdown signal received");
     }
   }
   Ok(())
 }
+
+/// Resolves on `ctrl_c`, or on SIGTERM as sent by `systemctl stop`.
+async fn shutdown_signal() -> std::io::Result<()> {
+  #[cfg(unix)]
+  {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+      res = tokio::signal::ctrl_c() => res,
+      _ = terminate.recv() => std::io::Result::Ok(()),
+    }
+  }
+  #[cfg(not(unix))]
+  tokio::signal::ctrl_c().await
+}
+
+fn print_addresses(listener: &Listener, mounts: &[&str]) {
+  for address in listener.addresses() {
+    for mount in mounts {
+      match &address {
+        Address::Tcp(addr) if addr.ip().is_unspecified() => {
+          server_started_info(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port(), mount);
+        },
+        Address::Tcp(addr) => server_started_info(addr.ip(), addr.port(), mount),
+        Address::Unix(Some(path)) => unix_socket_info(path, mount),
+        Address::Unix(None) => {},
+      }
+    }
+  }
+}
+
+/// The mounts re-encoding the source's stream with their own bitrate, see `[[variants]]`.
+fn variants(config: &Config, mount: &Mount) -> anyhow::Result<Vec<Variant>> {
+  config.variants.iter()
+    .flatten()
+    .map(|variant| {
+      let path = filter_mount_endpoint(&variant.endpoint)?;
+      Ok(Variant::new(Box::leak(path.into_boxed_str()), mount, variant, config.segment_window()))
+    })
+    .collect()
+}
+
+/// Runs a subcommand: the server, one of the utility commands, or a command for the running
+/// server.
+async fn run_command(command: &Command, args: &Args) -> anyhow::Result<()> {
+  let socket = || Config::client_control_socket(args);
+  match command {
+    Command::Serve => serve(args).await?,
+    Command::Status => control::run(&socket(), &Request::Status).await?,
+    Command::Listeners => control::run(&socket(), &Request::Listeners).await?,
+    Command::Kick { id } => control::run(&socket(), &Request::Kick { id: *id }).await?,
+    Command::Metadata(MetadataCommand::Set { mount, comments }) => control::run(
+      &socket(),
+      &Request::SetMetadata { mount: mount.clone(), comments: comments.clone() }
+    ).await?,
+    Command::Reload => control::run(&socket(), &Request::Reload).await?,
+    Command::HashPassword { password } => {
+      let password = match password {
+        Some(password) => password.clone(),
+        None => prompt_password()?,
+      };
+      println!("{}", hash_password(&password)?);
+    },
+    Command::GenerateToken => {
+      let (token, digest) = generate_token();
+      println!("token:        {token}\ntoken_sha256: \"{digest}\"");
+    },
+    Command::CheckConfig => {
+      let path = Config::get_config_path(args);
+      let config = if path.exists() {
+        Config::load(&path, args)
+      } else {
+        Config::from_layers(toml::Table::new(), args)
+      }.with_context(|| format!("invalid config at '{}'", path.display()))?;
+      Live::from_config(&config)?;
+      for hook in config.hooks.iter().flatten() {
+        Hook::from_config(hook)?;
+      }
+      filter_mount_endpoint(&config.broadcast_endpoint)?;
+      println!("Config at '{}' is valid", path.display());
+    },
+    Command::SignUrl { mount, expires_in, base_url } => {
+      let config = Config::load_or_create(args)?;
+      let mount = filter_mount_endpoint(mount.as_ref().unwrap_or(&config.broadcast_endpoint))?;
+      let secret = config.listener_auth()
+        .into_iter()
+        .find(|(m, _)| filter_mount_endpoint(m).is_ok_and(|m| m == mount))
+        .and_then(|(_, auth)| match auth {
+          ListenerAuthConfig::Signed { secret } => Some(secret),
+          ListenerAuthConfig::Basic { .. } | ListenerAuthConfig::Url { .. } => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("mount {mount} does not use signed listener auth"))?;
+      let expires = unix_now().checked_add(*expires_in)
+        .ok_or_else(|| anyhow::anyhow!("--expires-in {expires_in} is too far in the future"))?;
+      let url = sign_url(secret, &mount, expires);
+      println!("{}{url}", base_url.as_deref().unwrap_or_default().trim_end_matches('/'));
+    },
+  }
+  Ok(())
+}
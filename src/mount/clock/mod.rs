@@ -0,0 +1,163 @@
+use std::sync::{Arc, Mutex};
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use crate::util::ogg_headers::{Codec, HeaderCapture};
+use crate::util::ogg_packets::{PacketReader, PageHeader};
+use crate::util::opus::packet_samples;
+use crate::util::opus_headers::OpusHead;
+use super::lock;
+
+/// The stream clock of a mount, following the granule positions of the source's Ogg pages. It
+/// reports on the source's stream for `status`; the segmenter keeps its own decode time, which
+/// has to go on counting between sources.
+#[derive(Clone, Default)]
+pub struct StreamClock {
+  inner: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+  /// Splits the pages into packets, joining those spanning pages.
+  reader: PacketReader,
+  codec: Option<Codec>,
+  /// Rate of the granule positions, 48 kHz for Opus and the sample rate for Vorbis and FLAC.
+  rate: u32,
+  pre_skip: u64,
+  /// The header pages still to come, before the audio.
+  headers: Option<HeaderCapture>,
+  serial: Option<u32>,
+  next_sequence: Option<u32>,
+  /// Granule position at the start of the first audio page, and when that page arrived.
+  start: Option<(u64, Instant)>,
+  granule: Option<u64>,
+  pages_missed: u64,
+}
+
+/// The stream clock as shown by `tau-tower status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClockInfo {
+  /// Playback position of the source's stream: its last granule position, minus the pre-skip
+  /// for Opus.
+  pub position_secs: f64,
+  /// Audio received since the first audio page of the stream.
+  pub received_secs: f64,
+  /// How far the audio received is ahead of (positive) or behind (negative) the wall clock
+  /// time since the first audio page.
+  pub drift_secs: f64,
+  /// Pages skipped in the page sequence, lost or never sent by the source.
+  pub pages_missed: u64,
+}
+
+impl StreamClock {
+  /// Advances the clock to `page`. An identification header starts the clock over.
+  pub fn page(&self, page: &Bytes) {
+    let Some(header) = PageHeader::parse(page) else { return };
+    let mut state = lock(&self.inner);
+    let packets = state.reader.push(page);
+    if let Some(id) = packets.first()
+      && let Some(codec) = Codec::detect(id) {
+      let mut headers = HeaderCapture::default();
+      headers.push(page);
+      *state = State {
+        reader: std::mem::take(&mut state.reader),
+        codec: Some(codec),
+        rate: codec.sample_rate(id).unwrap_or_default(),
+        pre_skip: OpusHead::parse(id).map_or(0, |head| u64::from(head.pre_skip)),
+        headers: Some(headers),
+        serial: Some(header.serial),
+        next_sequence: Some(header.sequence.wrapping_add(1)),
+        ..State::default()
+      };
+      return;
+    }
+    if state.serial == Some(header.serial)
+      && let Some(expected) = state.next_sequence
+      && header.sequence > expected {
+      state.pages_missed += u64::from(header.sequence - expected);
+    }
+    state.serial = Some(header.serial);
+    state.next_sequence = Some(header.sequence.wrapping_add(1));
+
+    if let Some(headers) = &mut state.headers {
+      if headers.push(page).is_some() {
+        state.headers = None;
+      }
+      return;
+    }
+    // no packet ends on the page
+    let Some(granule) = header.granule_position else { return };
+    if state.start.is_none() {
+      // the duration of Vorbis and FLAC packets takes more than their first bytes to tell, so
+      // their clock starts at the end of the first audio page
+      let samples = if state.codec == Some(Codec::Opus) {
+        packets.iter().filter_map(|p| packet_samples(p)).map(u64::from).sum::<u64>()
+      } else {
+        0
+      };
+      state.start = Some((granule.saturating_sub(samples), Instant::now()));
+    }
+    state.granule = Some(granule);
+  }
+
+  /// Forgets the stream, when its source disconnects.
+  pub fn reset(&self) {
+    *lock(&self.inner) = State::default();
+  }
+
+  /// `None` until the first audio page of a stream.
+  #[allow(clippy::cast_precision_loss)]
+  pub fn info(&self) -> Option<ClockInfo> {
+    let state = lock(&self.inner);
+    let ((start, started), granule) = (state.start?, state.granule?);
+    if state.rate == 0 {
+      return None;
+    }
+    let seconds = |samples: u64| samples as f64 / f64::from(state.rate);
+    let received_secs = seconds(granule.saturating_sub(start));
+    Some(ClockInfo {
+      position_secs: seconds(granule.saturating_sub(state.pre_skip)),
+      received_secs,
+      drift_secs: received_secs - started.elapsed().as_secs_f64(),
+      pages_missed: state.pages_missed,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn page(flags: u8, granule: i64, sequence: u32, packet: &[u8]) -> Bytes {
+    let mut page = b"OggS\0".to_vec();
+    page.push(flags);
+    page.extend_from_slice(&granule.to_le_bytes());
+    page.extend_from_slice(&7u32.to_le_bytes());
+    page.extend_from_slice(&sequence.to_le_bytes());
+    page.extend_from_slice(&[0; 4]);
+    page.extend_from_slice(&[1, u8::try_from(packet.len()).unwrap()]);
+    page.extend_from_slice(packet);
+    Bytes::from(page)
+  }
+
+  #[test]
+  fn follows_granule_positions() {
+    let clock = StreamClock::default();
+    clock.page(&page(0x02, 0, 0, b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00"));
+    clock.page(&page(0, 0, 1, b"OpusTags"));
+    assert!(clock.info().is_none());
+
+    // a stream starting at 1s, with one 20 ms packet per page
+    clock.page(&page(0, 48_960, 2, &[0xFC]));
+    clock.page(&page(0, 49_920, 3, &[0xFC]));
+    // a page without a packet ending on it does not move the clock
+    clock.page(&page(0, -1, 4, &[]));
+    clock.page(&page(0, 51_840, 7, &[0xFC]));
+
+    let info = clock.info().unwrap();
+    assert!((info.position_secs - 1.0735).abs() < 1e-9);
+    assert!((info.received_secs - 0.08).abs() < 1e-9);
+    assert!(info.drift_secs > 0.0 && info.drift_secs <= 0.08);
+    assert_eq!(info.pages_missed, 2);
+  }
+}
@@ -0,0 +1,204 @@
+pub mod clock;
+pub mod segments;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{RwLock, broadcast, oneshot};
+use tokio::time::Instant;
+use crate::util::ogg_headers::OggHeaders;
+use clock::StreamClock;
+use segments::Segments;
+
+/// A broadcast mount: the single producer - multiple consumer channel carrying the Ogg pages
+/// of the source, the headers captured from it for listeners joining mid-stream, and the
+/// segments remuxed from it for HLS, and the clock of its stream.
+#[derive(Clone)]
+pub struct Mount {
+  pub path: &'static str,
+  pub tx: broadcast::Sender<Bytes>,
+  pub ogg_headers: Arc<RwLock<Option<OggHeaders>>>,
+  pub listeners: Listeners,
+  pub segments: Segments,
+  pub clock: StreamClock,
+  /// The title sent to ICY listeners of MP3 and AAC streams, set with `metadata set`.
+  stream_title: Arc<Mutex<Option<String>>>,
+  source: Arc<Mutex<Option<Source>>>,
+  last_page: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Mount {
+  pub fn new(path: &'static str, segment_window: usize) -> Self {
+    let (tx, _) = broadcast::channel::<Bytes>(1024);
+    Self {
+      path,
+      tx,
+      ogg_headers: Arc::new(RwLock::new(None)),
+      listeners: Listeners::default(),
+      segments: Segments::new(segment_window),
+      clock: StreamClock::default(),
+      stream_title: Arc::new(Mutex::new(None)),
+      source: Arc::new(Mutex::new(None)),
+      last_page: Arc::new(Mutex::new(None)),
+    }
+  }
+
+  /// The mount of a variant of this one's stream, sharing its listeners.
+  pub fn variant(&self, path: &'static str, segment_window: usize) -> Self {
+    Self { listeners: self.listeners.clone(), ..Self::new(path, segment_window) }
+  }
+
+  pub fn page_received(&self, page: &Bytes) {
+    *lock(&self.last_page) = Some(Instant::now());
+    self.clock.page(page);
+  }
+
+  pub fn set_stream_title(&self, title: String) {
+    *lock(&self.stream_title) = Some(title);
+  }
+
+  pub fn stream_title(&self) -> Option<String> {
+    lock(&self.stream_title).clone()
+  }
+
+  /// Time since the source last sent a page, `None` if it never did.
+  pub fn last_page_age(&self) -> Option<std::time::Duration> {
+    lock(&self.last_page).map(|at| at.elapsed())
+  }
+
+  pub fn source_connected(&self, username: String, remote_addr: SocketAddr) {
+    *lock(&self.source) = Some(Source { username, remote_addr, started: Instant::now() });
+  }
+
+  /// Marks the source streaming to `source` as streaming to this variant of it too.
+  pub fn source_connected_as(&self, source: &Self) {
+    let connected = lock(&source.source).as_ref().map(|s| (s.username.clone(), s.remote_addr));
+    if let Some((username, remote_addr)) = connected {
+      self.source_connected(username, remote_addr);
+    }
+  }
+
+  pub fn source_disconnected(&self) {
+    *lock(&self.source) = None;
+    *lock(&self.stream_title) = None;
+    self.clock.reset();
+  }
+
+  /// The source currently streaming to this mount, if any.
+  pub fn source(&self) -> Option<SourceInfo> {
+    lock(&self.source).as_ref().map(|source| SourceInfo {
+      username: source.username.clone(),
+      remote_addr: source.remote_addr.to_string(),
+      connected_secs: source.started.elapsed().as_secs(),
+    })
+  }
+}
+
+struct Source {
+  username: String,
+  remote_addr: SocketAddr,
+  started: Instant,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SourceInfo {
+  pub username: String,
+  pub remote_addr: String,
+  pub connected_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListenerInfo {
+  pub id: u64,
+  pub remote_addr: String,
+  pub user_agent: Option<String>,
+  pub connected_secs: u64,
+}
+
+struct Listener {
+  remote_addr: SocketAddr,
+  user_agent: Option<String>,
+  started: Instant,
+  kick: oneshot::Sender<()>,
+}
+
+/// The listeners connected to a mount, so they can be listed and kicked from the control socket.
+#[derive(Clone, Default)]
+pub struct Listeners {
+  next_id: Arc<AtomicU64>,
+  inner: Arc<Mutex<BTreeMap<u64, Listener>>>,
+}
+
+impl Listeners {
+  /// Registers a listener until the returned [`Registration`] is dropped. The receiver resolves
+  /// when the listener is kicked.
+  pub fn register(&self, remote_addr: SocketAddr, user_agent: Option<String>) -> (Registration, oneshot::Receiver<()>) {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+    let (kick, kicked) = oneshot::channel();
+    lock(&self.inner).insert(id, Listener { remote_addr, user_agent, started: Instant::now(), kick });
+    (Registration { id, listeners: self.clone() }, kicked)
+  }
+
+  pub fn list(&self) -> Vec<ListenerInfo> {
+    lock(&self.inner).iter()
+      .map(|(id, listener)| ListenerInfo {
+        id: *id,
+        remote_addr: listener.remote_addr.to_string(),
+        user_agent: listener.user_agent.clone(),
+        connected_secs: listener.started.elapsed().as_secs(),
+      })
+      .collect()
+  }
+
+  pub fn count(&self) -> usize {
+    lock(&self.inner).len()
+  }
+
+  /// Ends the stream of listener `id`, returns `false` if no such listener is connected.
+  pub fn kick(&self, id: u64) -> bool {
+    lock(&self.inner).remove(&id).is_some_and(|listener| listener.kick.send(()).is_ok())
+  }
+}
+
+/// Keeps a listener in [`Listeners`] for as long as its response body is alive.
+pub struct Registration {
+  id: u64,
+  listeners: Listeners,
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    lock(&self.listeners.inner).remove(&self.id);
+  }
+}
+
+/// The registries hold no invariants a panicking holder could break, so poisoning is ignored.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn kicked_listener_is_removed_and_notified() {
+    let listeners = Listeners::default();
+    let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+    let (first, kicked) = listeners.register(addr, None);
+    let (second, _) = listeners.register(addr, Some("mpv".into()));
+    assert_eq!(listeners.count(), 2);
+
+    assert!(listeners.kick(first.id));
+    assert!(kicked.await.is_ok());
+    assert!(!listeners.kick(first.id));
+    assert_eq!(listeners.list()[0].user_agent.as_deref(), Some("mpv"));
+
+    drop(second);
+    drop(first);
+    assert_eq!(listeners.count(), 0);
+  }
+}
@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use hyper::body::Bytes;
+use super::lock;
+
+/// The sliding window of fragmented MP4 segments of a mount, remuxed from the source by the
+/// segmenter for the HLS playlist and the DASH manifest.
+#[derive(Clone)]
+pub struct Segments {
+  inner: Arc<Mutex<Window>>,
+}
+
+struct Window {
+  /// Segments kept in memory, beyond those listed in the playlist for players that are a little
+  /// behind.
+  size: usize,
+  /// Initialization segments still referenced by a segment in the window, by id.
+  inits: BTreeMap<u64, Init>,
+  segments: VecDeque<Segment>,
+  next_sequence: u64,
+  next_init: u64,
+  /// Discontinuities that slid out of the window.
+  discontinuity_sequence: u64,
+  availability_start: Option<SystemTime>,
+}
+
+struct Init {
+  data: Bytes,
+  start: u64,
+}
+
+pub struct Segment {
+  pub sequence: u64,
+  pub init: u64,
+  /// Decode time of the first sample, in 48 kHz samples.
+  pub start: u64,
+  /// Duration in 48 kHz samples.
+  pub duration: u64,
+  /// Whether the segment starts a new source stream.
+  pub discontinuity: bool,
+  pub data: Bytes,
+}
+
+/// A segment as listed in the playlist and manifest.
+pub struct SegmentInfo {
+  pub sequence: u64,
+  pub init: u64,
+  /// Decode time at which the init segment came into use, where its DASH period starts.
+  pub init_start: u64,
+  pub start: u64,
+  pub duration: u64,
+  pub discontinuity: bool,
+  pub size: usize,
+}
+
+/// The segments in the window, with what the playlist and manifest need to describe them.
+pub struct Listing {
+  pub segments: Vec<SegmentInfo>,
+  /// The discontinuity sequence of the first segment.
+  pub discontinuity_sequence: u64,
+  /// Wall clock time of decode time 0.
+  pub availability_start: Option<SystemTime>,
+  /// Number of segments the window holds.
+  pub window: usize,
+}
+
+impl Segments {
+  pub fn new(window: usize) -> Self {
+    Self {
+      inner: Arc::new(Mutex::new(Window {
+        size: window,
+        inits: BTreeMap::new(),
+        segments: VecDeque::new(),
+        next_sequence: 0,
+        next_init: 0,
+        discontinuity_sequence: 0,
+        availability_start: None,
+      })),
+    }
+  }
+
+  /// Anchors decode time 0 to the wall clock, for the DASH `availabilityStartTime`.
+  pub fn set_availability_start(&self, at: SystemTime) {
+    lock(&self.inner).availability_start = Some(at);
+  }
+
+  /// Adds an initialization segment used from decode time `start`, returning its id.
+  pub fn add_init(&self, data: Bytes, start: u64) -> u64 {
+    let mut window = lock(&self.inner);
+    window.next_init += 1;
+    let id = window.next_init;
+    window.inits.insert(id, Init { data, start });
+    id
+  }
+
+  /// The sequence number the next segment gets.
+  pub fn next_sequence(&self) -> u64 {
+    lock(&self.inner).next_sequence
+  }
+
+  /// Appends a segment, dropping the oldest one once the window is full.
+  pub fn push(&self, segment: Segment) {
+    let mut window = lock(&self.inner);
+    window.next_sequence = segment.sequence + 1;
+    window.segments.push_back(segment);
+    while window.segments.len() > window.size
+      && let Some(old) = window.segments.pop_front() {
+      if old.discontinuity {
+        window.discontinuity_sequence += 1;
+      }
+      // keep the init of the next segment, and any added since
+      let first = window.segments.front().map_or(old.init, |s| s.init);
+      window.inits.retain(|id, _| *id >= first);
+    }
+    drop(window);
+  }
+
+  pub fn list(&self) -> Listing {
+    let window = lock(&self.inner);
+    let segments = window.segments.iter()
+      .map(|s| SegmentInfo {
+        sequence: s.sequence,
+        init: s.init,
+        init_start: window.inits.get(&s.init).map_or(s.start, |init| init.start),
+        start: s.start,
+        duration: s.duration,
+        discontinuity: s.discontinuity,
+        size: s.data.len(),
+      })
+      .collect();
+    Listing {
+      segments,
+      discontinuity_sequence: window.discontinuity_sequence,
+      availability_start: window.availability_start,
+      window: window.size,
+    }
+  }
+
+  pub fn segment(&self, sequence: u64) -> Option<Bytes> {
+    let window = lock(&self.inner);
+    let first = window.segments.front()?.sequence;
+    let index = usize::try_from(sequence.checked_sub(first)?).ok()?;
+    window.segments.get(index).map(|s| s.data.clone())
+  }
+
+  pub fn init(&self, id: u64) -> Option<Bytes> {
+    lock(&self.inner).inits.get(&id).map(|init| init.data.clone())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const WINDOW: usize = 10;
+
+  fn segment(segments: &Segments, init: u64, discontinuity: bool) -> Segment {
+    let sequence = segments.next_sequence();
+    Segment { sequence, init, start: sequence * 96_000, duration: 96_000, discontinuity, data: Bytes::new() }
+  }
+
+  #[test]
+  fn slides_segments_and_inits_out() {
+    let segments = Segments::new(WINDOW);
+    let first = segments.add_init(Bytes::from_static(b"first"), 0);
+    segments.push(segment(&segments, first, false));
+    let second = segments.add_init(Bytes::from_static(b"second"), 96_000);
+    segments.push(segment(&segments, second, true));
+    assert!(segments.init(first).is_some());
+    for _ in 0..WINDOW {
+      segments.push(segment(&segments, second, false));
+    }
+
+    let listing = segments.list();
+    assert_eq!(listing.discontinuity_sequence, 1);
+    assert_eq!(listing.segments.len(), WINDOW);
+    assert_eq!(listing.segments[0].sequence, 2);
+    assert_eq!(listing.segments[0].init_start, 96_000);
+    assert!(segments.segment(1).is_none());
+    assert!(segments.segment(2).is_some());
+    assert!(segments.init(first).is_none());
+    assert!(segments.init(second).is_some());
+  }
+}
@@ -0,0 +1,146 @@
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::mount::segments::{Listing, SegmentInfo};
+use crate::threads::segmenter::SEGMENT_DURATION;
+use crate::util::opus::SAMPLE_RATE;
+
+/// A live (`dynamic`) DASH manifest of the segments in the window, which must not be empty.
+/// Each init segment gets its own period, starting at the decode time it came into use, so the
+/// period ids and starts stay put as the window slides. The segment times count from the
+/// `availabilityStartTime`, which the segmenter anchors to the wall clock.
+pub(super) fn manifest(listing: &Listing, query: Option<&str>, now: SystemTime) -> String {
+  let query = query.map(|q| {
+    // `$` starts a template identifier
+    format!("?{q}").replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;").replace('$', "$$")
+  }).unwrap_or_default();
+  let segment_secs = SEGMENT_DURATION.as_secs();
+  let bandwidth = listing.segments.iter()
+    .filter(|s| s.duration > 0)
+    .map(|s| s.size as u64 * 8 * u64::from(SAMPLE_RATE) / s.duration)
+    .max()
+    .unwrap_or_default();
+
+  let mut out = String::new();
+  let _ = writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+  let _ = writeln!(
+    out,
+    r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011" type="dynamic" availabilityStartTime="{}" publishTime="{}" minimumUpdatePeriod="PT{segment_secs}S" minBufferTime="PT{segment_secs}S" timeShiftBufferDepth="PT{}S" suggestedPresentationDelay="PT{}S" maxSegmentDuration="PT{}S">"#,
+    datetime(listing.availability_start.unwrap_or(now)),
+    datetime(now),
+    listing.window as u64 * segment_secs,
+    3 * segment_secs,
+    2 * segment_secs,
+  );
+  for period in listing.segments.chunk_by(|a, b| a.init == b.init) {
+    let first = &period[0];
+    let _ = writeln!(out, r#"  <Period id="{}" start="{}">"#, first.init, duration(first.init_start));
+    let _ = writeln!(out, r#"    <AdaptationSet id="1" contentType="audio" mimeType="audio/mp4" lang="und" segmentAlignment="true" startWithSAP="1">"#);
+    let _ = writeln!(out, r#"      <Representation id="opus" codecs="opus" audioSamplingRate="{SAMPLE_RATE}" bandwidth="{bandwidth}">"#);
+    let _ = writeln!(
+      out,
+      r#"        <SegmentTemplate timescale="{SAMPLE_RATE}" presentationTimeOffset="{}" initialization="init-{}.mp4{query}" media="segment-$Number$.m4s{query}" startNumber="{}">"#,
+      first.init_start, first.init, first.sequence,
+    );
+    let _ = writeln!(out, "          <SegmentTimeline>");
+    for (start, duration, repeat) in timeline(period) {
+      let repeat = if repeat > 0 { format!(r#" r="{repeat}""#) } else { String::new() };
+      let _ = writeln!(out, r#"            <S t="{start}" d="{duration}"{repeat}/>"#);
+    }
+    let _ = writeln!(out, "          </SegmentTimeline>");
+    let _ = writeln!(out, "        </SegmentTemplate>");
+    let _ = writeln!(out, "      </Representation>");
+    let _ = writeln!(out, "    </AdaptationSet>");
+    let _ = writeln!(out, "  </Period>");
+  }
+  // the player's clock is synced to the server's, the segments being anchored to the latter
+  let _ = writeln!(out, r#"  <UTCTiming schemeIdUri="urn:mpeg:dash:utc:direct:2014" value="{}"/>"#, datetime(now));
+  let _ = writeln!(out, "</MPD>");
+  out
+}
+
+/// The `SegmentTimeline` entries of `segments`: start, duration and repeat count of each run of
+/// back to back segments of the same duration.
+fn timeline(segments: &[SegmentInfo]) -> Vec<(u64, u64, u64)> {
+  let mut runs: Vec<(u64, u64, u64)> = Vec::new();
+  for segment in segments {
+    match runs.last_mut() {
+      Some((start, duration, repeat))
+        if *duration == segment.duration && *start + *duration * (*repeat + 1) == segment.start => *repeat += 1,
+      _ => runs.push((segment.start, segment.duration, 0)),
+    }
+  }
+  runs
+}
+
+/// An `xs:duration` of `samples` at 48 kHz.
+fn duration(samples: u64) -> String {
+  let (secs, rest) = (samples / u64::from(SAMPLE_RATE), samples % u64::from(SAMPLE_RATE));
+  format!("PT{secs}.{:03}S", rest * 1000 / u64::from(SAMPLE_RATE))
+}
+
+/// An `xs:dateTime` in UTC, with milliseconds.
+fn datetime(at: SystemTime) -> String {
+  let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+  let secs = since_epoch.as_secs();
+  let (days, time) = (secs / 86_400, secs % 86_400);
+  // civil date from days since 1970-01-01, by Howard Hinnant's algorithm
+  let z = days + 719_468;
+  let era = z / 146_097;
+  let doe = z - era * 146_097;
+  let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + u64::from(month <= 2);
+  format!(
+    "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+    time / 3600, time % 3600 / 60, time % 60, since_epoch.subsec_millis()
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  fn segment(sequence: u64, init: u64, start: u64) -> SegmentInfo {
+    SegmentInfo { sequence, init, init_start: 0, start, duration: 96_000, discontinuity: false, size: 4000 }
+  }
+
+  #[test]
+  fn formats_dates_and_durations() {
+    assert_eq!(datetime(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    let leap_day = UNIX_EPOCH + Duration::from_millis(1_709_227_425_250);
+    assert_eq!(datetime(leap_day), "2024-02-29T17:23:45.250Z");
+    assert_eq!(duration(96_000), "PT2.000S");
+    assert_eq!(duration(4_824_000), "PT100.500S");
+  }
+
+  #[test]
+  fn splits_periods_by_init() {
+    let mut segments = vec![segment(4, 1, 192_000), segment(5, 1, 288_000), segment(6, 1, 480_000)];
+    segments.extend([segment(7, 2, 960_000), segment(8, 2, 1_056_000)]);
+    for s in &mut segments[3..] {
+      s.init_start = 960_000;
+    }
+    let listing = Listing {
+      segments,
+      discontinuity_sequence: 0,
+      availability_start: Some(UNIX_EPOCH),
+      window: 10,
+    };
+    let mpd = manifest(&listing, Some("expires=1&sig=a$b"), UNIX_EPOCH + Duration::from_secs(25));
+
+    assert!(mpd.contains(r#"availabilityStartTime="1970-01-01T00:00:00.000Z" publishTime="1970-01-01T00:00:25.000Z""#));
+    assert!(mpd.contains(r#"timeShiftBufferDepth="PT20S""#));
+    assert!(mpd.contains(r#"bandwidth="16000""#));
+    assert!(mpd.contains(r#"<Period id="1" start="PT0.000S">"#));
+    assert!(mpd.contains(r#"initialization="init-1.mp4?expires=1&amp;sig=a$$b" media="segment-$Number$.m4s?expires=1&amp;sig=a$$b" startNumber="4">"#));
+    // the gap before segment 6 starts a new run
+    assert!(mpd.contains("<S t=\"192000\" d=\"96000\" r=\"1\"/>\n            <S t=\"480000\" d=\"96000\"/>"));
+    assert!(mpd.contains(r#"<Period id="2" start="PT20.000S">"#));
+    assert!(mpd.contains(r#"presentationTimeOffset="960000" initialization="init-2.mp4"#));
+    assert!(mpd.contains(r#"<S t="960000" d="96000" r="1"/>"#));
+  }
+}
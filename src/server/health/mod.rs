@@ -0,0 +1,133 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use hyper::StatusCode;
+use serde_json::{Value, json};
+use crate::mount::Mount;
+use crate::util::ip::filter_mount_endpoint;
+
+/// Whether the tasks serving each side are accepting connections, for `/healthz`.
+#[derive(Clone, Default)]
+pub struct Health {
+  source: Arc<AtomicBool>,
+}
+
+impl Health {
+  /// Marks the source side as accepting connections until the guard is dropped.
+  pub fn source_listening(&self) -> Listening {
+    self.source.store(true, Ordering::Relaxed);
+    Listening(self.source.clone())
+  }
+}
+
+/// Held by the source task while it runs.
+pub struct Listening(Arc<AtomicBool>);
+
+impl Listening {
+  /// Reports whether the last attempt to accept a source connection succeeded. Accepting fails
+  /// e.g. when the process runs out of file descriptors, while the task itself keeps running.
+  pub fn accepting(&self, ok: bool) {
+    self.0.store(ok, Ordering::Relaxed);
+  }
+}
+
+impl Drop for Listening {
+  fn drop(&mut self) {
+    self.0.store(false, Ordering::Relaxed);
+  }
+}
+
+/// `/healthz`: the process is up and both sides accept connections. The source side does while
+/// its task runs and its last accept did not fail. The broadcast side necessarily does, as it
+/// answers this request.
+pub fn healthz(health: &Health) -> (StatusCode, Value) {
+  let source = health.source.load(Ordering::Relaxed);
+  let reasons: Vec<&str> = (!source).then_some("source listener is not accepting connections")
+    .into_iter()
+    .collect();
+  report(&reasons, json!({ "listeners": { "source": source, "broadcast": true } }))
+}
+
+/// `/readyz?mount=`: the mount has a connected source, has its stream headers, and received
+/// audio within `max_silence`. `mounts` starts with the broadcast endpoint, checked without
+/// `mount`, followed by its variants.
+pub async fn readyz(mounts: &[&Mount], requested: Option<&str>, max_silence: Duration) -> (StatusCode, Value) {
+  let mount = requested.map_or_else(|| mounts.first(), |requested| {
+    let path = filter_mount_endpoint(requested).ok();
+    mounts.iter().find(|mount| path.as_deref() == Some(mount.path))
+  });
+  let Some(mount) = mount else {
+    let requested = requested.unwrap_or_default();
+    return (StatusCode::NOT_FOUND, json!({ "status": "fail", "reasons": [format!("unknown mount {requested}")] }));
+  };
+
+  let source = mount.source();
+  let headers = mount.ogg_headers.read().await.is_some();
+  let silence = mount.last_page_age();
+  let mut reasons = Vec::new();
+  if source.is_none() {
+    reasons.push("no source connected".to_string());
+  }
+  if !headers {
+    reasons.push("no stream headers received".to_string());
+  }
+  match silence {
+    None => reasons.push("no audio received".to_string()),
+    Some(age) if age > max_silence => reasons.push(format!("no audio received for {}s", age.as_secs())),
+    Some(_) => {},
+  }
+  report(&reasons, json!({
+    "mount": mount.path,
+    "source": source.map(|s| s.username),
+    "headers": headers,
+    "last_audio_secs": silence.map(|age| age.as_secs()),
+  }))
+}
+
+fn report<R: serde::Serialize>(reasons: &[R], mut details: Value) -> (StatusCode, Value) {
+  let (status, text) = if reasons.is_empty() {
+    (StatusCode::OK, "ok")
+  } else {
+    (StatusCode::SERVICE_UNAVAILABLE, "fail")
+  };
+  details["status"] = json!(text);
+  if !reasons.is_empty() {
+    details["reasons"] = json!(reasons);
+  }
+  (status, details)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn readyz_lists_every_failed_check() {
+    let mount = Mount::new("/tau.ogg", 10);
+    let variant = mount.variant("/tau_low.ogg", 10);
+    let (status, body) = readyz(&[&mount, &variant], Some("tau.ogg"), Duration::from_secs(10)).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["reasons"].as_array().unwrap().len(), 3);
+
+    let (status, body) = readyz(&[&mount, &variant], Some("tau_low.ogg"), Duration::from_secs(10)).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["mount"], "/tau_low.ogg");
+
+    let (status, _) = readyz(&[&mount, &variant], Some("/other.ogg"), Duration::from_secs(10)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+  }
+
+  #[test]
+  fn healthz_follows_source_listener() {
+    let health = Health::default();
+    assert_eq!(healthz(&health).0, StatusCode::SERVICE_UNAVAILABLE);
+    let listening = health.source_listening();
+    assert_eq!(healthz(&health).0, StatusCode::OK);
+    listening.accepting(false);
+    assert_eq!(healthz(&health).0, StatusCode::SERVICE_UNAVAILABLE);
+    listening.accepting(true);
+    assert_eq!(healthz(&health).0, StatusCode::OK);
+    drop(listening);
+    assert_eq!(healthz(&health).0, StatusCode::SERVICE_UNAVAILABLE);
+  }
+}
@@ -0,0 +1,113 @@
+use std::fmt::Write;
+use crate::mount::segments::{Listing, SegmentInfo};
+use crate::threads::segmenter::SEGMENT_DURATION;
+use crate::util::opus::SAMPLE_RATE;
+
+/// Segments listed in the playlist, the rest of the window stays available to players that
+/// are behind.
+const PLAYLIST_SEGMENTS: usize = 6;
+
+/// A live media playlist of the last segments in the window, which must not be empty.
+pub(super) fn playlist(listing: &Listing, query: Option<&str>) -> String {
+  let segments = &listing.segments;
+  let skipped = segments.len().saturating_sub(PLAYLIST_SEGMENTS);
+  // a discontinuity of the first listed segment has no tag, it counts as already passed
+  let discontinuity_sequence = listing.discontinuity_sequence
+    + segments[..=skipped].iter().filter(|s| s.discontinuity).count() as u64;
+  let segments = &segments[skipped..];
+  let query = query.map(|q| format!("?{q}")).unwrap_or_default();
+  #[allow(clippy::cast_precision_loss)]
+  let seconds = |s: &SegmentInfo| s.duration as f64 / f64::from(SAMPLE_RATE);
+  // every EXTINF, rounded, has to be at most the target duration
+  #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+  let target = segments.iter()
+    .map(|s| seconds(s).round() as u64)
+    .fold(SEGMENT_DURATION.as_secs(), u64::max);
+
+  let mut out = String::new();
+  let _ = writeln!(out, "#EXTM3U");
+  let _ = writeln!(out, "#EXT-X-VERSION:7");
+  let _ = writeln!(out, "#EXT-X-TARGETDURATION:{target}");
+  let _ = writeln!(out, "#EXT-X-MEDIA-SEQUENCE:{}", segments[0].sequence);
+  let _ = writeln!(out, "#EXT-X-DISCONTINUITY-SEQUENCE:{discontinuity_sequence}");
+  let _ = writeln!(out, "#EXT-X-INDEPENDENT-SEGMENTS");
+  let mut init = None;
+  for (i, segment) in segments.iter().enumerate() {
+    if segment.discontinuity && i > 0 {
+      let _ = writeln!(out, "#EXT-X-DISCONTINUITY");
+    }
+    if init != Some(segment.init) {
+      let _ = writeln!(out, "#EXT-X-MAP:URI=\"init-{}.mp4{query}\"", segment.init);
+      init = Some(segment.init);
+    }
+    let _ = writeln!(out, "#EXTINF:{:.3},", seconds(segment));
+    let _ = writeln!(out, "segment-{}.m4s{query}", segment.sequence);
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn segment(sequence: u64, init: u64, discontinuity: bool) -> SegmentInfo {
+    let start = sequence * 96_960;
+    SegmentInfo { sequence, init, init_start: 0, start, duration: 96_960, discontinuity, size: 0 }
+  }
+
+  #[test]
+  fn lists_the_last_segments() {
+    let mut segments: Vec<_> = (0..8).map(|i| segment(i, 1, false)).collect();
+    segments[1].discontinuity = true;
+    segments[6].discontinuity = true;
+    segments[6].init = 2;
+    segments[7].init = 2;
+    let listing = Listing { segments, discontinuity_sequence: 4, availability_start: None, window: 8 };
+
+    let playlist = playlist(&listing, Some("token=abc"));
+    let expected = "\
+      #EXTM3U\n\
+      #EXT-X-VERSION:7\n\
+      #EXT-X-TARGETDURATION:2\n\
+      #EXT-X-MEDIA-SEQUENCE:2\n\
+      #EXT-X-DISCONTINUITY-SEQUENCE:5\n\
+      #EXT-X-INDEPENDENT-SEGMENTS\n\
+      #EXT-X-MAP:URI=\"init-1.mp4?token=abc\"\n\
+      #EXTINF:2.020,\n\
+      segment-2.m4s?token=abc\n";
+    assert!(playlist.starts_with(expected));
+    assert!(playlist.contains("#EXT-X-DISCONTINUITY\n#EXT-X-MAP:URI=\"init-2.mp4?token=abc\"\n#EXTINF:2.020,\nsegment-6.m4s"));
+    assert_eq!(playlist.matches("#EXTINF").count(), PLAYLIST_SEGMENTS);
+  }
+
+  #[test]
+  fn discontinuity_numbers_hold_as_the_window_slides() {
+    // a new stream every 5 segments
+    let stream_segment = |i: u64| segment(i, i / 5, i.is_multiple_of(5) && i > 0);
+    let mut numbers = std::collections::HashMap::new();
+    for end in 1..=20_u64 {
+      // the window of the mount counts the discontinuities that slid out of it
+      let start = end.saturating_sub(8);
+      let discontinuity_sequence = (0..start).filter(|&i| stream_segment(i).discontinuity).count() as u64;
+      let listing = Listing {
+        segments: (start..end).map(stream_segment).collect(),
+        discontinuity_sequence,
+        availability_start: None,
+        window: 8,
+      };
+      let playlist = playlist(&listing, None);
+      let mut number: u64 = playlist.lines()
+        .find_map(|l| l.strip_prefix("#EXT-X-DISCONTINUITY-SEQUENCE:"))
+        .unwrap().parse().unwrap();
+      for line in playlist.lines() {
+        if line == "#EXT-X-DISCONTINUITY" {
+          number += 1;
+        } else if let Some(sequence) = line.strip_prefix("segment-") {
+          let sequence = sequence.trim_end_matches(".m4s").to_string();
+          assert_eq!(*numbers.entry(sequence.clone()).or_insert(number), number, "segment {sequence} at {end}");
+        }
+      }
+    }
+    assert_eq!(numbers["19"], 3);
+  }
+}
@@ -0,0 +1,86 @@
+use hyper::body::Bytes;
+
+/// Audio bytes between two metadata blocks, sent as `icy-metaint`.
+pub(super) const METAINT: usize = 16_000;
+/// A metadata block is at most 255 times 16 bytes.
+const MAX_METADATA: usize = 255 * 16;
+
+/// Interleaves `SHOUTcast` metadata blocks into the audio sent to a listener that asked for them
+/// with `Icy-MetaData: 1`: after every [`METAINT`] bytes, a length byte counting 16-byte units,
+/// and the `StreamTitle` when it changed, or an empty block when it did not.
+pub(super) struct IcyWriter {
+  /// Audio bytes until the next metadata block.
+  remaining: usize,
+  sent_title: Option<String>,
+}
+
+impl IcyWriter {
+  pub(super) const fn new() -> Self {
+    Self { remaining: METAINT, sent_title: None }
+  }
+
+  pub(super) fn push(&mut self, mut chunk: &[u8], title: Option<&str>) -> Bytes {
+    let mut out = Vec::with_capacity(chunk.len() + 1);
+    while chunk.len() >= self.remaining {
+      let (audio, rest) = chunk.split_at(self.remaining);
+      out.extend_from_slice(audio);
+      self.metadata(&mut out, title);
+      self.remaining = METAINT;
+      chunk = rest;
+    }
+    out.extend_from_slice(chunk);
+    self.remaining -= chunk.len();
+    Bytes::from(out)
+  }
+
+  fn metadata(&mut self, out: &mut Vec<u8>, title: Option<&str>) {
+    if title == self.sent_title.as_deref() {
+      out.push(0);
+      return;
+    }
+    self.sent_title = title.map(String::from);
+    let title = title.unwrap_or_default();
+    // room for `StreamTitle='';`, cut on a character boundary
+    let mut end = title.len().min(MAX_METADATA - 15);
+    while !title.is_char_boundary(end) {
+      end -= 1;
+    }
+    let mut block = format!("StreamTitle='{}';", &title[..end]).into_bytes();
+    let units = block.len().div_ceil(16);
+    block.resize(units * 16, 0);
+    out.push(u8::try_from(units).unwrap_or(u8::MAX));
+    out.extend_from_slice(&block);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn inserts_metadata_every_metaint() {
+    let mut writer = IcyWriter::new();
+    let audio = vec![0xAA; METAINT - 10];
+    assert_eq!(writer.push(&audio, Some("Night Show")), audio);
+
+    let out = writer.push(&[0xBB; 20], Some("Night Show"));
+    assert_eq!(&out[..10], [0xBB; 10]);
+    // `StreamTitle='Night Show';` takes 25 bytes, two units
+    assert_eq!(out[10], 2);
+    assert_eq!(&out[11..36], b"StreamTitle='Night Show';");
+    assert_eq!(&out[36..43], [0; 7]);
+    assert_eq!(&out[43..], [0xBB; 10]);
+
+    // an unchanged title is an empty block
+    let out = writer.push(&vec![0xCC; METAINT], Some("Night Show"));
+    assert_eq!(out.len(), METAINT + 1);
+    assert_eq!(out[METAINT - 10], 0);
+
+    // a cleared title is sent once as an empty one
+    let out = writer.push(&vec![0xDD; METAINT], None);
+    assert_eq!(&out[METAINT - 10..METAINT + 7], b"\x01StreamTitle='';\0");
+    let out = writer.push(&vec![0xDD; METAINT], None);
+    assert_eq!(out.len(), METAINT + 1);
+    assert_eq!(out[METAINT - 10], 0);
+  }
+}
@@ -1,59 +1,385 @@
+mod dash;
+pub mod health;
+mod hls;
+mod icy;
 mod responses;
+mod segments;
+mod websocket;
 
 use std::sync::Arc;
 use std::convert::Infallible;
-use tokio::sync::{RwLock, broadcast};
-use http_body_util::{BodyExt, combinators::BoxBody};
+use std::net::SocketAddr;
+use http_body_util::{BodyExt, Empty, combinators::BoxBody};
 use hyper::{ 
   Method, 
   Request,
   Response,
   Result,
   body::{Bytes, Incoming}, 
+  header::{HeaderValue, ORIGIN, USER_AGENT},
 };
 
-use crate::util::ogg_headers::OggHeaders;
+use crate::events::{Events, ListenerSession};
+use crate::mount::Mount;
+use crate::config::live::Live;
+use crate::util::listener_auth::{Denied, ListenerAuth};
+use crate::util::ogg_headers::Codec;
+use health::Health;
+use arc_swap::ArcSwap;
 use responses::{
+  build_icy_body,
   build_stream_body,
+  build_webm_body,
   default_response,
   stream_response,
   four_oh_four,
+  method_not_allowed,
+  bad_request,
+  unauthorized,
+  forbidden,
+  json_response,
   apply_cors,
-  cors_preflight_response
+  matching_origin,
+  cors_preflight_response,
+  HttpResponse,
 };
 
+/// State shared by every listener connection on the broadcast port.
+#[derive(Clone)]
+pub struct ServerState {
+  pub mount: Mount,
+  /// The mounts transcoded from `mount`, served alike.
+  pub variants: Vec<Mount>,
+  pub live: Arc<ArcSwap<Live>>,
+  pub events: Events,
+  pub health: Health,
+}
+
+/// Routes by path first: known paths answer GET and HEAD, and `405` for any other method.
+/// CORS headers are applied to every response.
 pub async fn handle_request(
-  req: Request<Incoming>,
-  tx: broadcast::Sender<Bytes>,
-  ogg_headers: Arc<RwLock<Option<OggHeaders>>>,
-  mount: &'static str,
-  allowed_origins: Arc<Option<Vec<&'static str>>>,
+  mut req: Request<Incoming>,
+  state: ServerState,
+  peer: SocketAddr,
 ) -> Result<Response<BoxBody<Bytes, Infallible>>> {
-  let res = match (req.method(), req.uri().path()) {
-    (&Method::GET, path) if path == mount => {
-      let mut res = stream_response(
-        build_stream_body(&tx, ogg_headers).await
-      ); 
-      apply_cors(&req, &mut res, allowed_origins.as_deref());
-      res
+  let live = state.live.load_full();
+  let allowed_origins = live.allowed_origins.as_deref();
+  let path = req.uri().path();
+  let mount = std::iter::once(&state.mount)
+    .chain(&state.variants)
+    .find(|mount| serves(mount, path));
+  let known = mount.is_some() || matches!(path, "/" | "/index.html" | "/healthz" | "/readyz");
+  let mount = mount.unwrap_or(&state.mount).clone();
+  let mut res = match *req.method() {
+    Method::OPTIONS => return Ok(cors_preflight_response(&req, allowed_origins)),
+    _ if !known => four_oh_four(),
+    Method::GET | Method::HEAD => route(&mut req, &mount, &state, &live, peer).await,
+    _ => method_not_allowed(),
+  };
+  apply_cors(&req, &mut res, allowed_origins);
+  Ok(res)
+}
+
+/// Answers GET or HEAD on a known path, of `mount` for its streams and segments. hyper leaves
+/// out the body of a HEAD response, so only the stream, which would subscribe to the broadcast,
+/// needs its own handling. The mount path also takes WebSocket upgrades, for players that
+/// cannot use a long-lived HTTP response, and is served remuxed into WebM next to it, for Media
+/// Source Extensions.
+async fn route(
+  req: &mut Request<Incoming>,
+  mount: &Mount,
+  state: &ServerState,
+  live: &Live,
+  peer: SocketAddr
+) -> HttpResponse {
+  let ServerState { events, health, .. } = state;
+  // HLS and DASH are authenticated as the mount on the playlist and manifest, whose segment URIs
+  // then carry a session instead of the credentials; a playlist requested with its session keeps
+  // it until it expires
+  if let Some(file) = segments::File::parse(mount.path, req.uri().path()) {
+    // as for WebM, only Opus is remuxed
+    if mount.ogg_headers.read().await.as_ref().is_some_and(|h| h.codec != Codec::Opus) {
+      return four_oh_four();
+    }
+    let query = req.uri().query();
+    if ListenerAuth::has_session(mount.path, query) {
+      return segments::respond(mount, &file, query);
+    }
+    return match live.listener_auth.start_session(mount.path, req.uri(), req.headers(), peer).await {
+      Err(Denied::Unauthorized) => unauthorized(),
+      Err(Denied::Forbidden) => forbidden(),
+      Ok(session) => segments::respond(mount, &file, session.as_deref().or(query)),
+    };
+  }
+  match req.uri().path() {
+    path if path == mount.path || path == webm_path(mount.path) => {
+      let webm = path != mount.path;
+      let codec = mount.ogg_headers.read().await.as_ref().map(|h| h.codec);
+      // WebM, like HLS and DASH, only carries Opus
+      if webm && codec.is_some_and(|c| c != Codec::Opus) {
+        return four_oh_four();
+      }
+      let content_type = if webm { "audio/webm" } else { codec.map_or("audio/ogg", Codec::content_type) };
+      let websocket = !webm && websocket::is_upgrade(req);
+      let icy = !websocket
+        && matches!(codec, Some(Codec::Mp3 | Codec::Aac))
+        && req.headers().get("icy-metadata").is_some_and(|v| v == "1");
+      // WebSockets are not covered by CORS, browsers leave it to the server to check the origin
+      if websocket && !websocket_origin_allowed(req, live.allowed_origins.as_deref()) {
+        return forbidden();
+      }
+      if req.method() == Method::HEAD {
+        return match live.listener_auth.check_without_listener(mount.path, req.uri(), req.headers(), peer).await {
+          Err(Denied::Unauthorized) => unauthorized(),
+          Err(Denied::Forbidden) => forbidden(),
+          Ok(()) => with_icy_metaint(stream_response(content_type, Empty::new().boxed()), icy),
+        };
+      }
+      // a bad handshake is refused before the listener is counted or announced
+      let accept_key = match websocket.then(|| websocket::accept_key(req)).transpose() {
+        Err(code) => return bad_request(code),
+        Ok(accept_key) => accept_key,
+      };
+      let url_session = match live.listener_auth.check(mount.path, req.uri(), req.headers(), peer).await {
+        Err(Denied::Unauthorized) => return unauthorized(),
+        Err(Denied::Forbidden) => return forbidden(),
+        Ok(url_session) => url_session,
+      };
+      let user_agent = req.headers().get(USER_AGENT)
+        .and_then(|ua| ua.to_str().ok())
+        .map(ToString::to_string);
+      let (registration, kicked) = mount.listeners.register(peer, user_agent.clone());
+      let session = ListenerSession::start(events, mount.path, peer, user_agent);
+      if let Some(accept_key) = accept_key {
+        return websocket::upgrade(req, accept_key, mount, kicked, (session, registration, url_session));
+      }
+      let session = (session, registration, url_session);
+      let body = if webm {
+        build_webm_body(mount, kicked, session).await
+      } else if icy {
+        build_icy_body(mount, kicked, session).await
+      } else {
+        build_stream_body(mount, kicked, session).await
+      };
+      with_icy_metaint(stream_response(content_type, body), icy)
+    },
+    "/healthz" => {
+      let (status, body) = health::healthz(health);
+      json_response(status, &body)
     },
-    (&Method::GET, "/" | "/index.html") => {
+    "/readyz" => {
+      let requested = req.uri().query()
+        .and_then(|q| form_urlencoded::parse(q.as_bytes()).find(|(k, _)| k == "mount"))
+        .map(|(_, v)| v.into_owned());
+      let mounts: Vec<_> = std::iter::once(&state.mount).chain(&state.variants).collect();
+      let (status, body) = health::readyz(&mounts, requested.as_deref(), live.ready_max_silence).await;
+      json_response(status, &body)
+    },
+    _ => {
       let html = format!(
         "\
           <html>\
           <body>\
           <div>\
           <p>localhost link to audio stream</p>\
-          <a href=\"{mount}\">Audio Stream</a>\
+          <a href=\"{}\">Audio Stream</a>\
           </div>\
           </body>\
           </html>\
-          ");
+          ", mount.path);
       let body = http_body_util::Full::new(Bytes::from(html)).boxed();
       default_response(body)
     },
-    (&Method::OPTIONS, _) => cors_preflight_response(&req, allowed_origins.as_deref()),
-    _ =>  four_oh_four()
-  };
-  Ok(res)
+  }
+}
+
+/// Whether `path` is the stream of `mount`, its WebM stream or one of its segments.
+fn serves(mount: &Mount, path: &str) -> bool {
+  path == mount.path || path == webm_path(mount.path) || segments::File::parse(mount.path, path).is_some()
+}
+
+/// Tells an ICY listener how often metadata blocks come.
+fn with_icy_metaint(mut res: HttpResponse, icy: bool) -> HttpResponse {
+  if icy {
+    res.headers_mut().insert("icy-metaint", HeaderValue::from(icy::METAINT));
+  }
+  res
+}
+
+/// The WebM stream of a mount, `/tau.webm` next to `/tau.ogg`.
+fn webm_path(mount: &str) -> String {
+  format!("{}.webm", mount.strip_suffix(".ogg").unwrap_or(mount))
+}
+
+/// With a CORS allow list, WebSocket connections from pages of other origins are refused.
+/// Clients that are not browsers send no `Origin` and are let through, as for the HTTP stream.
+fn websocket_origin_allowed(req: &Request<Incoming>, allowed_origins: Option<&[String]>) -> bool {
+  match (allowed_origins, req.headers().get(ORIGIN)) {
+    (Some(origins), Some(origin)) => matching_origin(origins, origin).is_some(),
+    _ => true,
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use hyper::{HeaderMap, StatusCode, Uri, header::{ACCESS_CONTROL_ALLOW_ORIGIN, ALLOW, CONTENT_TYPE}};
+  use hyper::server::conn::http1;
+  use hyper::service::service_fn;
+  use hyper_util::rt::TokioIo;
+  use tokio::net::TcpListener;
+  use crate::config::Config;
+  use crate::util::http_client::https_client;
+  use crate::util::ogg_headers::OggHeaders;
+  use crate::util::listener_auth::{sign_url, unix_now};
+
+  /// Server state for `/tau.ogg`, with `extra` appended to a minimal config.
+  pub fn state(extra: &str) -> ServerState {
+    let config: Config = toml::from_str(&format!(
+      "username = \"u\"\npassword = \"p\"\nlisten_port = 1\nbroadcast_port = 2\n\
+      broadcast_endpoint = \"tau.ogg\"\n{extra}"
+    )).unwrap();
+    ServerState {
+      mount: Mount::new("/tau.ogg", 10),
+      variants: Vec::new(),
+      live: Arc::new(ArcSwap::from_pointee(Live::from_config(&config).unwrap())),
+      events: Events::new(),
+      health: Health::default(),
+    }
+  }
+
+  /// Serves `state` over HTTP/1.1 on a local port.
+  async fn serve(state: ServerState) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      loop {
+        let (stream, peer) = listener.accept().await.unwrap();
+        let state = state.clone();
+        tokio::spawn(http1::Builder::new().serve_connection(
+          TokioIo::new(stream),
+          service_fn(move |req| handle_request(req, state.clone(), peer)),
+        ));
+      }
+    });
+    addr
+  }
+
+  async fn request(addr: SocketAddr, method: Method, path: &str, origin: Option<&str>) -> Response<Incoming> {
+    let mut req = Request::builder().method(method).uri(format!("http://{addr}{path}"));
+    if let Some(origin) = origin {
+      req = req.header(ORIGIN, origin);
+    }
+    https_client().request(req.body(http_body_util::Full::new(Bytes::new())).unwrap()).await.unwrap()
+  }
+
+  #[tokio::test]
+  async fn head_answers_without_making_a_listener() {
+    // the url authenticator is unreachable, a HEAD must not call it
+    let state = state("[listener_auth.\"tau.ogg\"]\nmode = \"url\"\nlistener_add = \"http://127.0.0.1:1/add\"");
+    let mut events = state.events.subscribe();
+    let addr = serve(state.clone()).await;
+
+    let res = request(addr, Method::HEAD, "/tau.ogg", None).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = request(addr, Method::HEAD, "/tau.ogg?user=member&pass=secret", None).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[CONTENT_TYPE], "audio/ogg");
+    assert_eq!(state.mount.listeners.count(), 0);
+    assert!(events.try_recv().is_err());
+  }
+
+  #[tokio::test]
+  async fn bad_websocket_handshakes_make_no_listener() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let state = state("");
+    let mut events = state.events.subscribe();
+    let addr = serve(state.clone()).await;
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(
+      b"GET /tau.ogg HTTP/1.1\r\nHost: tau\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+      Sec-WebSocket-Version: 8\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+    ).await.unwrap();
+    let mut head = [0; 12];
+    stream.read_exact(&mut head).await.unwrap();
+    assert_eq!(&head, b"HTTP/1.1 400");
+    assert_eq!(state.mount.listeners.count(), 0);
+    assert!(events.try_recv().is_err());
+  }
+
+  #[tokio::test]
+  async fn segments_take_the_session_of_the_playlist() {
+    let state = state("[listener_auth.\"tau.ogg\"]\nmode = \"signed\"\nsecret = \"s\"");
+    let signed: Uri = sign_url("s", "/tau.ogg", unix_now() + 60).parse().unwrap();
+    let peer = "127.0.0.1:5000".parse().unwrap();
+    let session = state.live.load().listener_auth
+      .start_session("/tau.ogg", &signed, &HeaderMap::new(), peer).await
+      .unwrap().unwrap();
+    let addr = serve(state).await;
+
+    let status = |path: String| async move { request(addr, Method::GET, &path, None).await.status() };
+    assert_eq!(status("/tau/segment-1.m4s".into()).await, StatusCode::FORBIDDEN);
+    // no segment was made, but the request got past authentication
+    assert_eq!(status(format!("/tau/segment-1.m4s?{session}")).await, StatusCode::NOT_FOUND);
+    assert_eq!(status(format!("/tau/init-1.mp4?{session}")).await, StatusCode::NOT_FOUND);
+    // no segment was made yet, the playlist is not ready
+    assert_eq!(status(format!("/tau/playlist.m3u8?{session}")).await, StatusCode::SERVICE_UNAVAILABLE);
+  }
+
+  #[tokio::test]
+  async fn only_opus_is_served_as_hls_dash_and_webm() {
+    let state = state("");
+    let id = Bytes::from_static(b"\x01vorbis");
+    *state.mount.ogg_headers.write().await = Some(OggHeaders { codec: Codec::Vorbis, pages: vec![id] });
+    let addr = serve(state).await;
+    for path in ["/tau/playlist.m3u8", "/tau/manifest.mpd", "/tau/segment-1.m4s", "/tau.webm"] {
+      assert_eq!(request(addr, Method::GET, path, None).await.status(), StatusCode::NOT_FOUND, "{path}");
+    }
+  }
+
+  #[tokio::test]
+  async fn variants_are_served_with_the_auth_of_the_source() {
+    let mut state = state(
+      "[[variants]]\nendpoint = \"tau_low.ogg\"\nbitrate = 24000\n\
+      [listener_auth.\"tau.ogg\"]\nmode = \"signed\"\nsecret = \"s\""
+    );
+    state.variants.push(Mount::new("/tau_low.ogg", 3));
+    let addr = serve(state).await;
+    let status = |path: String| async move { request(addr, Method::GET, &path, None).await.status() };
+
+    for path in ["/tau_low.ogg", "/tau_low.webm", "/tau_low/playlist.m3u8", "/tau_low/segment-1.m4s"] {
+      assert_eq!(status(path.into()).await, StatusCode::FORBIDDEN, "{path}");
+    }
+    let expires = crate::util::listener_auth::unix_now() + 60;
+    let signed = crate::util::listener_auth::sign_url("s", "/tau_low.ogg", expires);
+    let query = signed.split_once('?').unwrap().1;
+    // no source has streamed yet, the variant's playlist is routed but empty
+    assert_eq!(status(format!("/tau_low/playlist.m3u8?{query}")).await, StatusCode::SERVICE_UNAVAILABLE);
+    // the signature of the variant is not that of the source
+    assert_eq!(status(format!("/tau/playlist.m3u8?{query}")).await, StatusCode::FORBIDDEN);
+    assert_eq!(status("/tau_low/unknown".into()).await, StatusCode::NOT_FOUND);
+  }
+
+  #[tokio::test]
+  async fn other_methods_get_405_with_allow() {
+    let addr = serve(state("")).await;
+    for path in ["/tau.ogg", "/healthz", "/tau/playlist.m3u8"] {
+      let res = request(addr, Method::POST, path, None).await;
+      assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED, "{path}");
+      assert_eq!(res.headers()[ALLOW], "GET, HEAD, OPTIONS");
+    }
+    assert_eq!(request(addr, Method::POST, "/nothing", None).await.status(), StatusCode::NOT_FOUND);
+  }
+
+  #[tokio::test]
+  async fn cors_headers_are_on_every_response() {
+    let addr = serve(state("cors_allow_list = [\"https://radio.example\"]")).await;
+    for (method, path) in [(Method::GET, "/healthz"), (Method::GET, "/nothing"), (Method::DELETE, "/tau.ogg")] {
+      let res = request(addr, method, path, Some("https://radio.example")).await;
+      assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "https://radio.example", "{path}");
+    }
+    let res = request(addr, Method::GET, "/healthz", Some("https://other.example")).await;
+    assert!(res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+  }
 }
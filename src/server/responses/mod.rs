@@ -12,13 +12,18 @@ use hyper::{
     X_CONTENT_TYPE_OPTIONS,
     CACHE_CONTROL,
     CONTENT_TYPE,
-    CONNECTION,
     ORIGIN,
     VARY,
+    WWW_AUTHENTICATE,
+    ALLOW,
+    CONNECTION,
+    UPGRADE,
+    SEC_WEBSOCKET_ACCEPT,
+    RETRY_AFTER,
     HeaderValue, 
   }
 };
-use tokio::sync::{RwLock, broadcast};
+use tokio::sync::{RwLock, oneshot};
 use futures_util::{Stream, stream};
 use http_body_util::{
   BodyExt,
@@ -26,48 +31,92 @@ use http_body_util::{
   Full,
   combinators::BoxBody
 };
+use crate::mount::Mount;
 use crate::util::ogg_headers::OggHeaders;
+use crate::webm::Remuxer;
+use super::icy::IcyWriter;
 
-type HttpResponse = Response<BoxBody<Bytes, Infallible>>;
+pub(super) type HttpResponse = Response<BoxBody<Bytes, Infallible>>;
 
+/// Methods answered on every known path.
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
 
-/// Builds the HTTP audio stream from Tokio `BroadcastStream`.
-/// It waits for the headers of the Ogg Opus stream to be available and takes care of prepending
-/// them to each new consumer stream. 
+
+/// Builds the HTTP audio stream from the pages of [`page_stream`].
 pub(super) async fn build_stream_body(
-  tx: &broadcast::Sender<Bytes>, 
-  ogg_header: Arc<RwLock<Option<OggHeaders>>>) 
+  mount: &Mount,
+  kicked: oneshot::Receiver<()>,
+  session: impl Send + Sync + 'static)
+-> BoxBody<Bytes, Infallible> {
+  let stream = page_stream(mount, kicked, session).await
+    .map(|chunk| Ok::<Frame<Bytes>, Infallible>(Frame::data(chunk)));
+
+  BodyExt::boxed(StreamBody::new(stream))
+}
+
+/// Builds the WebM stream, remuxed for each listener from the pages of [`page_stream`]. It ends
+/// when the source switches to a stream the WebM headers already sent do not describe.
+pub(super) async fn build_webm_body(
+  mount: &Mount,
+  kicked: oneshot::Receiver<()>,
+  session: impl Send + Sync + 'static)
 -> BoxBody<Bytes, Infallible> {
-  let rx = tx.subscribe();
+  let stream = page_stream(mount, kicked, session).await
+    .scan(Remuxer::default(), |remuxer, page| futures_util::future::ready(remuxer.push(&page)))
+    .filter(|chunk| futures_util::future::ready(!chunk.is_empty()))
+    .map(|chunk| Ok::<Frame<Bytes>, Infallible>(Frame::data(chunk)));
+
+  BodyExt::boxed(StreamBody::new(stream))
+}
+
+/// The MP3 or AAC stream with ICY metadata blocks carrying the mount's stream title.
+pub(super) async fn build_icy_body(
+  mount: &Mount,
+  kicked: oneshot::Receiver<()>,
+  session: impl Send + Sync + 'static)
+-> BoxBody<Bytes, Infallible> {
+  let titles = mount.clone();
+  let stream = page_stream(mount, kicked, session).await
+    .scan(IcyWriter::new(), move |writer, chunk| {
+      futures_util::future::ready(Some(writer.push(&chunk, titles.stream_title().as_deref())))
+    })
+    .map(|chunk| Ok::<Frame<Bytes>, Infallible>(Frame::data(chunk)));
+
+  BodyExt::boxed(StreamBody::new(stream))
+}
+
+/// Subscribes a listener to the mount's Tokio `BroadcastStream`.
+/// It waits for the headers of the Ogg stream to be available and takes care of prepending
+/// them to each new consumer stream. 
+/// The `session` is held by the stream, and dropped with it when the listener goes away. The
+/// stream ends once `kicked` resolves.
+pub(super) async fn page_stream(
+  mount: &Mount,
+  kicked: oneshot::Receiver<()>,
+  session: impl Send + Sync + 'static)
+-> impl Stream<Item = Bytes> + Send + 'static {
+  let rx = mount.tx.subscribe();
   let stream = tokio_stream::wrappers::BroadcastStream::new(rx)
     .filter_map(
-      |msg| 
-      async move { msg.ok() }
-    )
-    .map(
-      |chunk| 
-        Ok::<Frame<Bytes>, Infallible>( 
-          Frame::data( chunk)
-        )
-    )
-    .take_while(
-      |res|
-        futures_util::future::ready(res.is_ok())
+      move |msg| {
+        let _session = &session;
+        // a listener that fell behind skips the pages it missed
+        futures_util::future::ready(msg.ok())
+      }
     );
 
   // wait for headers to be populated
-  let headers = wait_for_ogg_headers(&ogg_header).await;
+  let headers = wait_for_ogg_headers(&mount.ogg_headers).await;
   // prepend the ogg headers to the stream body
-  let stream = prepare_header_stream(headers)
-    .chain(stream);
-
-  BodyExt::boxed(StreamBody::new(stream))
+  prepare_header_stream(headers)
+    .chain(stream)
+    .take_until(kicked)
 }
 
-/// Prepares the Ogg Opus headers, captured from the source stream, to be broadcast on every new
+/// Prepares the Ogg headers, captured from the source stream, to be broadcast on every new
 /// listener connection.
-pub(super) fn prepare_header_stream(header: OggHeaders) -> impl Stream<Item = core::result::Result<Frame<Bytes>, Infallible>> {
-  stream::iter([Ok(Frame::data(header.head)), Ok(Frame::data(header.tags))])
+pub(super) fn prepare_header_stream(header: OggHeaders) -> impl Stream<Item = Bytes> {
+  stream::iter(header.pages)
 }
 
 
@@ -82,12 +131,11 @@ pub(super) async fn wait_for_ogg_headers(header: &Arc<RwLock<Option<OggHeaders>>
   }
 }
 
-pub(super) fn stream_response(body: BoxBody<Bytes, Infallible>) -> HttpResponse {
+pub(super) fn stream_response(content_type: &'static str, body: BoxBody<Bytes, Infallible>) -> HttpResponse {
   match Response::builder()
   .status(StatusCode::OK)
-  .header(CONTENT_TYPE, "audio/ogg")
+  .header(CONTENT_TYPE, content_type)
   .header(CACHE_CONTROL, "no-cache")
-  .header(CONNECTION, "keep-alive")
   .header(X_CONTENT_TYPE_OPTIONS, "nosniff")
   .body(body) {
     Ok(res) => res,
@@ -97,7 +145,7 @@ pub(super) fn stream_response(body: BoxBody<Bytes, Infallible>) -> HttpResponse
 
 pub(super) fn cors_preflight_response(
   req: &Request<Incoming>,
-  allowed_origin: Option<&[&'static str]>) -> HttpResponse {
+  allowed_origin: Option<&[String]>) -> HttpResponse {
   let forbidden = || match Response::builder()
     .status(StatusCode::FORBIDDEN)
     .body(BoxBody::new(Empty::<Bytes>::new())) {
@@ -113,18 +161,14 @@ pub(super) fn cors_preflight_response(
     return forbidden();
   };
   
-  let allowed = match origins {
-    ["*"] => "*", 
-    _ => match origins.iter().find(|&&o| o.as_bytes() == request_origin.as_bytes()) {
-      Some(&o) => o,
-      None => return forbidden(),
-    }
+  let Some(allowed) = matching_origin(origins, request_origin) else {
+    return forbidden();
   };
 
   match Response::builder() 
     .status(StatusCode::NO_CONTENT)
     .header(ACCESS_CONTROL_ALLOW_ORIGIN, allowed)
-    .header(ACCESS_CONTROL_ALLOW_METHODS,"GET, OPTIONS")
+    .header(ACCESS_CONTROL_ALLOW_METHODS, ALLOWED_METHODS)
     .header(ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, Authorization")
     .header(ACCESS_CONTROL_EXPOSE_HEADERS,"Content-Type")
     .header(ACCESS_CONTROL_MAX_AGE, "86400")
@@ -137,23 +181,26 @@ pub(super) fn cors_preflight_response(
 pub(super) fn apply_cors(
   req: &Request<Incoming>, 
   res: &mut HttpResponse, 
-  allowed_origins: Option<&[&'static str]>
+  allowed_origins: Option<&[String]>
 ) {
   let Some(origins) = allowed_origins else { return; };
   let Some(request_origin) = req.headers().get(ORIGIN) else { return; };
 
-  let allowed = match origins {
-    ["*"] => "*", 
-    _ => match origins.iter().find(|&&o| o.as_bytes() == request_origin.as_bytes()) {
-      Some(&o) => o,
-      None => return
-    }
-  };
+  let Some(allowed) = matching_origin(origins, request_origin) else { return; };
+  let Ok(allowed) = HeaderValue::from_str(allowed) else { return; };
 
-  res.headers_mut().insert( ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static(allowed));
+  res.headers_mut().insert( ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
   res.headers_mut().append( VARY, HeaderValue::from_static("Origin"));
 }
 
+/// The entry of the CORS allow list matching `request_origin`, `*` if any origin is allowed.
+pub(super) fn matching_origin<'a>(origins: &'a [String], request_origin: &HeaderValue) -> Option<&'a str> {
+  match origins {
+    [only] if only == "*" => Some("*"),
+    _ => origins.iter().find(|o| o.as_bytes() == request_origin.as_bytes()).map(String::as_str),
+  }
+}
+
 pub(super) fn default_response(body: BoxBody<Bytes, Infallible>) -> HttpResponse {
   match Response::builder()
   .status(StatusCode::OK)
@@ -164,6 +211,17 @@ pub(super) fn default_response(body: BoxBody<Bytes, Infallible>) -> HttpResponse
   }
 }
 
+pub(super) fn json_response(status: StatusCode, body: &serde_json::Value) -> HttpResponse {
+  match Response::builder()
+    .status(status)
+    .header(CONTENT_TYPE, "application/json")
+    .header(CACHE_CONTROL, "no-store")
+    .body(Full::new(Bytes::from(body.to_string())).boxed()) {
+    Ok(res) => res,
+    Err(e) => unreachable!("unable to build json response: {e}")
+  }
+}
+
 pub(super) fn four_oh_four() -> HttpResponse {
   match Response::builder()
     .status(StatusCode::NOT_FOUND)
@@ -176,3 +234,95 @@ pub(super) fn four_oh_four() -> HttpResponse {
     Err(e) => unreachable!("unable to build 404 response: {e}")
   }
 }
+
+pub(super) fn method_not_allowed() -> HttpResponse {
+  match Response::builder()
+    .status(StatusCode::METHOD_NOT_ALLOWED)
+    .header(ALLOW, ALLOWED_METHODS)
+    .body(
+      Full::new("METHOD_NOT_ALLOWED".into())
+        .map_err(|e| match e {})
+        .boxed(),
+  ) {
+    Ok(res) => res,
+    Err(e) => unreachable!("unable to build 405 response: {e}")
+  }
+}
+
+pub(super) fn unauthorized() -> HttpResponse {
+  match Response::builder()
+    .status(StatusCode::UNAUTHORIZED)
+    .header(WWW_AUTHENTICATE, "Basic realm=\"tau-tower\"")
+    .body(
+      Full::new("UNAUTHORIZED".into())
+        .map_err(|e| match e {})
+        .boxed(),
+  ) {
+    Ok(res) => res,
+    Err(e) => unreachable!("unable to build 401 response: {e}")
+  }
+}
+
+pub(super) fn forbidden() -> HttpResponse {
+  match Response::builder()
+    .status(StatusCode::FORBIDDEN)
+    .body(
+      Full::new("FORBIDDEN".into())
+        .map_err(|e| match e {})
+        .boxed(),
+  ) {
+    Ok(res) => res,
+    Err(e) => unreachable!("unable to build 403 response: {e}")
+  }
+}
+
+pub(super) fn bad_request(reason: &'static str) -> HttpResponse {
+  match Response::builder()
+    .status(StatusCode::BAD_REQUEST)
+    .body(
+      Full::new(reason.into())
+        .map_err(|e| match e {})
+        .boxed(),
+  ) {
+    Ok(res) => res,
+    Err(e) => unreachable!("unable to build 400 response: {e}")
+  }
+}
+
+pub(super) fn switching_protocols(accept_key: String) -> HttpResponse {
+  match Response::builder()
+    .status(StatusCode::SWITCHING_PROTOCOLS)
+    .header(CONNECTION, "Upgrade")
+    .header(UPGRADE, "websocket")
+    .header(SEC_WEBSOCKET_ACCEPT, accept_key)
+    .body(BoxBody::new(Empty::<Bytes>::new())) {
+    Ok(res) => res,
+    Err(e) => unreachable!("unable to build 101 response: {e}")
+  }
+}
+
+pub(super) fn segments_response(status: StatusCode, content_type: &'static str, cache_control: &'static str, body: Bytes) -> HttpResponse {
+  match Response::builder()
+    .status(status)
+    .header(CONTENT_TYPE, content_type)
+    .header(CACHE_CONTROL, cache_control)
+    .body(Full::new(body).boxed()) {
+    Ok(res) => res,
+    Err(e) => unreachable!("unable to build segments response: {e}")
+  }
+}
+
+/// For a stream that has not started yet, players are told to retry shortly.
+pub(super) fn service_unavailable() -> HttpResponse {
+  match Response::builder()
+    .status(StatusCode::SERVICE_UNAVAILABLE)
+    .header(RETRY_AFTER, "2")
+    .body(
+      Full::new("SERVICE_UNAVAILABLE".into())
+        .map_err(|e| match e {})
+        .boxed(),
+  ) {
+    Ok(res) => res,
+    Err(e) => unreachable!("unable to build 503 response: {e}")
+  }
+}
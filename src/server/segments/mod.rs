@@ -0,0 +1,70 @@
+use std::time::SystemTime;
+use hyper::StatusCode;
+use crate::mount::Mount;
+use super::{dash, hls};
+use super::responses::{HttpResponse, four_oh_four, segments_response, service_unavailable};
+
+/// A file of the HLS and DASH streams of a mount, served below the mount path without its
+/// extension: `/tau/playlist.m3u8` and `/tau/manifest.mpd` for `/tau.ogg`. Both share the init
+/// and media segments.
+pub(super) enum File {
+  Playlist,
+  Manifest,
+  Init(u64),
+  Segment(u64),
+}
+
+impl File {
+  pub(super) fn parse(mount: &str, path: &str) -> Option<Self> {
+    let base = mount.strip_suffix(".ogg").unwrap_or(mount);
+    let name = path.strip_prefix(base)?.strip_prefix('/')?;
+    match name {
+      "playlist.m3u8" => return Some(Self::Playlist),
+      "manifest.mpd" => return Some(Self::Manifest),
+      _ => {},
+    }
+    let number = |prefix: &str, suffix: &str| name.strip_prefix(prefix)?.strip_suffix(suffix)?.parse().ok();
+    number("init-", ".mp4").map(Self::Init)
+      .or_else(|| number("segment-", ".m4s").map(Self::Segment))
+  }
+}
+
+/// Serves `file`. `query`, the session of a protected mount or else the query string of the
+/// request, is passed on to the URIs in the playlist and manifest.
+pub(super) fn respond(mount: &Mount, file: &File, query: Option<&str>) -> HttpResponse {
+  match file {
+    File::Playlist | File::Manifest => {
+      let listing = mount.segments.list();
+      if listing.segments.is_empty() {
+        return service_unavailable();
+      }
+      let (content_type, body) = match file {
+        File::Playlist => ("application/vnd.apple.mpegurl", hls::playlist(&listing, query)),
+        _ => ("application/dash+xml", dash::manifest(&listing, query, SystemTime::now())),
+      };
+      segments_response(StatusCode::OK, content_type, "no-cache", body.into())
+    },
+    File::Init(id) => mount.segments.init(*id).map_or_else(four_oh_four, |init| {
+      segments_response(StatusCode::OK, "audio/mp4", "max-age=60", init)
+    }),
+    File::Segment(sequence) => mount.segments.segment(*sequence).map_or_else(four_oh_four, |segment| {
+      segments_response(StatusCode::OK, "audio/mp4", "max-age=60", segment)
+    }),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_file_names_below_the_mount() {
+    assert!(matches!(File::parse("/tau.ogg", "/tau/playlist.m3u8"), Some(File::Playlist)));
+    assert!(matches!(File::parse("/tau.ogg", "/tau/manifest.mpd"), Some(File::Manifest)));
+    assert!(matches!(File::parse("/tau.ogg", "/tau/init-3.mp4"), Some(File::Init(3))));
+    assert!(matches!(File::parse("/tau.ogg", "/tau/segment-12.m4s"), Some(File::Segment(12))));
+    assert!(File::parse("/tau.ogg", "/tau/segment-x.m4s").is_none());
+    assert!(File::parse("/tau.ogg", "/tauplaylist.m3u8").is_none());
+    assert!(File::parse("/tau.ogg", "/tau.ogg").is_none());
+  }
+}
@@ -0,0 +1,117 @@
+use futures_util::{SinkExt, StreamExt};
+use hyper::{
+  Method,
+  Request,
+  Version,
+  body::Incoming,
+  header::{CONNECTION, HeaderMap, HeaderName, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE},
+};
+use hyper_util::rt::TokioIo;
+use tokio::sync::oneshot;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role, frame::coding::CloseCode};
+
+use crate::mount::Mount;
+use super::responses::{HttpResponse, page_stream, switching_protocols};
+
+/// Whether `req` asks to upgrade to a WebSocket. Only HTTP/1.1 has the `Upgrade` mechanism.
+pub(super) fn is_upgrade(req: &Request<Incoming>) -> bool {
+  req.version() == Version::HTTP_11 && has_token(req.headers(), &UPGRADE, "websocket")
+}
+
+/// Checks the handshake of an upgrade request, before any listener is registered for it, and
+/// returns the `Sec-WebSocket-Accept` key, or the error code of the bad request otherwise.
+pub(super) fn accept_key(req: &Request<Incoming>) -> Result<String, &'static str> {
+  if req.method() != Method::GET || !has_token(req.headers(), &CONNECTION, "upgrade") {
+    return Err("BAD_WEBSOCKET_HANDSHAKE");
+  }
+  if req.headers().get(SEC_WEBSOCKET_VERSION).is_none_or(|v| v != "13") {
+    return Err("UNSUPPORTED_WEBSOCKET_VERSION");
+  }
+  let key = req.headers().get(SEC_WEBSOCKET_KEY).ok_or("BAD_WEBSOCKET_HANDSHAKE")?;
+  Ok(derive_accept_key(key.as_bytes()))
+}
+
+/// Completes the WebSocket handshake, checked with [`accept_key`], and hands the connection to
+/// a task sending the Ogg pages of the mount, the headers first, as binary messages. The same
+/// broadcast fan-out as the HTTP stream is used, so the `session` and `kicked` behave the same
+/// way.
+pub(super) fn upgrade(
+  req: &mut Request<Incoming>,
+  accept_key: String,
+  mount: &Mount,
+  kicked: oneshot::Receiver<()>,
+  session: impl Send + Sync + 'static,
+) -> HttpResponse {
+  let on_upgrade = hyper::upgrade::on(req);
+  let mount = mount.clone();
+  tokio::spawn(async move {
+    match on_upgrade.await {
+      Ok(upgraded) => {
+        let ws = WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await;
+        send_pages(ws, &mount, kicked, session).await;
+      }
+      Err(e) => eprintln!("WebSocket upgrade failed: {e}"),
+    }
+  });
+  switching_protocols(accept_key)
+}
+
+/// Sends the pages until the listener closes the connection or is kicked. Messages from the
+/// listener are only read to answer pings and notice the close.
+async fn send_pages<S>(
+  ws: WebSocketStream<S>,
+  mount: &Mount,
+  kicked: oneshot::Receiver<()>,
+  session: impl Send + Sync + 'static,
+)
+where
+  S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+  let (mut sink, mut incoming) = ws.split();
+  let forward = async {
+    let mut pages = std::pin::pin!(page_stream(mount, kicked, session).await);
+    while let Some(page) = pages.next().await {
+      sink.send(Message::Binary(page)).await?;
+    }
+    // the page stream only ends when the listener is kicked
+    let close = CloseFrame { code: CloseCode::Normal, reason: "kicked".into() };
+    sink.send(Message::Close(Some(close))).await
+  };
+  let closed = async {
+    while let Some(Ok(msg)) = incoming.next().await {
+      if msg.is_close() {
+        break;
+      }
+    }
+  };
+  tokio::select! {
+    _ = forward => {},
+    () = closed => {},
+  }
+}
+
+/// Whether the comma separated header `name` contains `token`, ignoring case.
+fn has_token(headers: &HeaderMap, name: &HeaderName, token: &str) -> bool {
+  headers.get_all(name).iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::header::HeaderValue;
+
+  #[test]
+  fn finds_tokens_in_lists() {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, Upgrade"));
+    assert!(has_token(&headers, &CONNECTION, "upgrade"));
+    assert!(!has_token(&headers, &CONNECTION, "close"));
+    assert!(!has_token(&headers, &UPGRADE, "websocket"));
+  }
+}
@@ -0,0 +1,185 @@
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use crate::control::{Reply, Request, Status, VariantStatus};
+use crate::events::{Event, Events};
+use crate::mount::Mount;
+use crate::threads::TIMEOUT;
+use crate::transcode::Variant;
+use crate::util::ip::filter_mount_endpoint;
+use crate::util::ogg_headers::{Codec, OggHeaders, set_opus_tags};
+use crate::util::socket::UnixSocket;
+
+/// State the control socket acts on.
+#[derive(Clone)]
+pub struct ControlState {
+  pub mount: Mount,
+  pub variants: Vec<Variant>,
+  pub events: Events,
+  pub started: Instant,
+  /// Asks the reload thread to reload `tower.toml`, and receives the outcome.
+  pub reload_tx: mpsc::Sender<oneshot::Sender<Reply>>,
+}
+
+/// Serves the `status`, `listeners`, ... subcommands on a Unix socket only the user running
+/// tower can connect to, so the server can be managed without exposing an admin port.
+pub async fn thread(
+  server: UnixSocket,
+  state: ControlState,
+  mut shutdown_rx: tokio::sync::watch::Receiver<bool>
+) -> anyhow::Result<()> {
+  loop {
+    tokio::select! {
+      _ = shutdown_rx.changed() => break,
+      conn = server.listener.accept() => match conn {
+        Ok((stream, _)) => { tokio::spawn(serve(stream, state.clone())); },
+        Err(e) => {
+          eprintln!("{e}");
+          tokio::time::sleep(TIMEOUT).await;
+        }
+      }
+    }
+  }
+
+  anyhow::Ok(())
+}
+
+async fn serve(stream: UnixStream, state: ControlState) {
+  let (read, mut write) = stream.into_split();
+  let mut lines = BufReader::new(read).lines();
+  while let Ok(Some(line)) = lines.next_line().await {
+    let reply = match serde_json::from_str::<Request>(&line) {
+      Ok(request) => handle(request, &state).await,
+      Err(e) => Reply::Error { message: format!("invalid request: {e}") },
+    };
+    let Ok(mut reply) = serde_json::to_string(&reply) else { break };
+    reply.push('\n');
+    if write.write_all(reply.as_bytes()).await.is_err() {
+      break;
+    }
+  }
+}
+
+async fn handle(request: Request, state: &ControlState) -> Reply {
+  let mount = &state.mount;
+  match request {
+    Request::Status => {
+      let headers = mount.ogg_headers.read().await.clone();
+      Reply::Status(Box::new(Status {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_secs: state.started.elapsed().as_secs(),
+        mount: mount.path.to_string(),
+        source: mount.source(),
+        listeners: mount.listeners.count(),
+        headers_received: headers.is_some(),
+        clock: mount.clock.info(),
+        codec: headers.as_ref().map(|h| h.codec),
+        opus_head: headers.as_ref().and_then(|h| h.opus_head().ok()),
+        opus_tags: headers.as_ref().and_then(|h| h.opus_tags().ok()),
+        stream_title: mount.stream_title(),
+        variants: state.variants.iter()
+          .map(|variant| VariantStatus {
+            mount: variant.mount.path.to_string(),
+            bitrate: variant.bitrate,
+            channels: variant.channels,
+            complexity: variant.complexity,
+            clock: variant.mount.clock.info(),
+          })
+          .collect(),
+      }))
+    },
+    Request::Listeners => Reply::Listeners { listeners: mount.listeners.list() },
+    Request::Kick { id } => if mount.listeners.kick(id) {
+      println!("Kicked listener {id}");
+      Reply::Kicked { id }
+    } else {
+      error(format!("no listener with id {id}"))
+    },
+    Request::SetMetadata { mount: requested, comments } => {
+      if let Some(requested) = requested
+        && filter_mount_endpoint(&requested).ok().as_deref() != Some(mount.path) {
+        return error(format!("unknown mount {requested}"));
+      }
+      let mut headers = mount.ogg_headers.write().await;
+      let Some(current) = headers.as_ref() else {
+        return error("no source has sent the stream headers yet".into());
+      };
+      match current.codec {
+        Codec::Opus => {},
+        Codec::Mp3 | Codec::Aac => {
+          drop(headers);
+          let Some(title) = stream_title(&comments) else {
+            return error("the stream title of MP3 and AAC streams is set from TITLE, and ARTIST".into());
+          };
+          mount.set_stream_title(title);
+          state.events.emit(Event::MetadataUpdated { mount: mount.path.to_string() });
+          return Reply::MetadataSet { mount: mount.path.to_string() };
+        },
+        codec => return error(format!("metadata cannot be set on {codec} streams")),
+      }
+      let mut pages = current.pages.clone();
+      // the source may have put OpusTags on the page of OpusHead
+      let Some(page) = pages.get_mut(1) else {
+        return error("the OpusTags header of the source is not on a page of its own".into());
+      };
+      *page = match set_opus_tags(page, &comments) {
+        Ok(tags) => tags,
+        Err(e) => return error(format!("the OpusTags header of the source could not be rewritten: {e}")),
+      };
+      *headers = Some(OggHeaders { codec: current.codec, pages });
+      drop(headers);
+      state.events.emit(Event::MetadataUpdated { mount: mount.path.to_string() });
+      Reply::MetadataSet { mount: mount.path.to_string() }
+    },
+    Request::Reload => {
+      let (tx, rx) = oneshot::channel();
+      if state.reload_tx.send(tx).await.is_err() {
+        return error("the reload task is not running".into());
+      }
+      rx.await.unwrap_or_else(|_| error("the reload task is not running".into()))
+    },
+  }
+}
+
+/// The ICY `StreamTitle` for `comments`, `ARTIST - TITLE` or just the title.
+fn stream_title(comments: &[(String, String)]) -> Option<String> {
+  let field = |name: &str| comments.iter()
+    .find(|(field, _)| field.eq_ignore_ascii_case(name))
+    .map(|(_, value)| value.as_str());
+  let title = field("TITLE")?;
+  Some(field("ARTIST").map_or_else(|| title.to_string(), |artist| format!("{artist} - {title}")))
+}
+
+const fn error(message: String) -> Reply {
+  Reply::Error { message }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::body::Bytes;
+
+  #[tokio::test]
+  async fn metadata_is_refused_for_headers_sharing_a_page() {
+    let head = b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00";
+    let tags = b"OpusTags\x04\x00\x00\x00test\x00\x00\x00\x00";
+    let mut page = b"OggS\0\x02".to_vec();
+    page.extend_from_slice(&[0; 20]);
+    page.extend_from_slice(&[2, 19, 20]);
+    page.extend_from_slice(head);
+    page.extend_from_slice(tags);
+    let mount = Mount::new("/tau.ogg", 10);
+    *mount.ogg_headers.write().await = Some(OggHeaders { codec: Codec::Opus, pages: vec![Bytes::from(page)] });
+    let state = ControlState {
+      mount,
+      variants: Vec::new(),
+      events: Events::new(),
+      started: Instant::now(),
+      reload_tx: mpsc::channel(1).0,
+    };
+
+    let request = Request::SetMetadata { mount: None, comments: vec![("TITLE".into(), "New".into())] };
+    assert!(matches!(handle(request, &state).await, Reply::Error { .. }));
+  }
+}
@@ -0,0 +1,257 @@
+use std::time::Duration;
+use http_body_util::Full;
+use hyper::{Request, Uri, body::Bytes, header::{CONTENT_TYPE, USER_AGENT}};
+use serde_json::Value;
+use tokio::process::Command;
+use tokio::sync::{broadcast, mpsc};
+use crate::config::{HookConfig, TauConfigError};
+use crate::events::{EVENT_KINDS, Event};
+use crate::util::http_client::{HttpsClient, https_client};
+use crate::util::listener_auth::unix_now;
+
+const DEFAULT_RETRIES: u32 = 3;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time a hook command may run before it is killed, so it cannot hold up its queue.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+const RETRY_BASE: Duration = Duration::from_secs(1);
+/// Events queued per hook before new ones are dropped, so a dead sink cannot grow memory.
+const QUEUE_SIZE: usize = 256;
+
+enum Sink {
+  Webhook { url: Uri, retries: u32 },
+  Command(String),
+}
+
+/// A configured event sink, together with the event kinds it fires on.
+pub struct Hook {
+  events: Option<Vec<String>>,
+  sink: Sink,
+}
+
+impl Hook {
+  /// # Errors
+  /// Fails unless exactly one of `webhook` and `command` is set, or on an unknown event kind.
+  pub fn from_config(config: &HookConfig) -> Result<Self, TauConfigError> {
+    if let Some(unknown) = config.events.iter().flatten().find(|e| !EVENT_KINDS.contains(&e.as_str())) {
+      return Err(TauConfigError::InvalidHook(format!(
+        "unknown event '{unknown}', expected one of: {}", EVENT_KINDS.join(", ")
+      )));
+    }
+    let sink = match (&config.webhook, &config.command) {
+      (Some(url), None) => Sink::Webhook {
+        url: url.parse().map_err(|e| TauConfigError::InvalidHook(format!("{url}: {e}")))?,
+        retries: config.retries.unwrap_or(DEFAULT_RETRIES),
+      },
+      (None, Some(command)) => Sink::Command(command.clone()),
+      _ => return Err(TauConfigError::InvalidHook(
+        "a hook needs either `webhook` or `command`".to_string()
+      )),
+    };
+    Ok(Self { events: config.events.clone(), sink })
+  }
+}
+
+fn wants(events: Option<&[String]>, event: &Event) -> bool {
+  events.is_none_or(|events| events.iter().any(|e| e == event.kind()))
+}
+
+/// Delivers lifecycle events to the configured hooks. Every hook gets its own queue and worker,
+/// so its deliveries stay in order without holding up the other hooks.
+pub async fn thread(
+  hooks: Vec<Hook>,
+  mut events: broadcast::Receiver<Event>,
+  mut shutdown_rx: tokio::sync::watch::Receiver<bool>
+) -> anyhow::Result<()> {
+  let client = https_client();
+  let workers: Vec<_> = hooks.into_iter().map(|hook| {
+    let (tx, rx) = mpsc::channel::<Value>(QUEUE_SIZE);
+    tokio::spawn(deliver(hook.sink, rx, client.clone()));
+    (hook.events, tx)
+  }).collect();
+
+  loop {
+    tokio::select! {
+      _ = shutdown_rx.changed() => break,
+      event = events.recv() => match event {
+        Ok(event) => {
+          let payload = payload(&event);
+          for (_, tx) in workers.iter().filter(|(events, _)| wants(events.as_deref(), &event)) {
+            if tx.try_send(payload.clone()).is_err() {
+              eprintln!("Hook queue full, dropping {} event", event.kind());
+            }
+          }
+        },
+        Err(broadcast::error::RecvError::Lagged(n)) => eprintln!("Hooks missed {n} events"),
+        Err(broadcast::error::RecvError::Closed) => break,
+      }
+    }
+  }
+
+  anyhow::Ok(())
+}
+
+/// The JSON payload of an event, with a unix `timestamp` added.
+fn payload(event: &Event) -> Value {
+  let mut value = serde_json::to_value(event).unwrap_or(Value::Null);
+  if let Value::Object(fields) = &mut value {
+    fields.insert("timestamp".to_string(), unix_now().into());
+  }
+  value
+}
+
+async fn deliver(sink: Sink, mut rx: mpsc::Receiver<Value>, client: HttpsClient) {
+  while let Some(payload) = rx.recv().await {
+    match &sink {
+      Sink::Webhook { url, retries } => post(&client, url, &payload, *retries).await,
+      Sink::Command(command) => run(command, &payload, COMMAND_TIMEOUT).await,
+    }
+  }
+}
+
+async fn post(client: &HttpsClient, url: &Uri, payload: &Value, retries: u32) {
+  let body = Bytes::from(payload.to_string());
+  let mut delay = RETRY_BASE;
+  for attempt in 0..=retries {
+    let req = Request::post(url.clone())
+      .header(CONTENT_TYPE, "application/json")
+      .header(USER_AGENT, concat!("tau-tower/", env!("CARGO_PKG_VERSION")))
+      .body(Full::new(body.clone()));
+    let Ok(req) = req else { return };
+
+    match tokio::time::timeout(WEBHOOK_TIMEOUT, client.request(req)).await {
+      Ok(Ok(res)) if res.status().is_success() => return,
+      Ok(Ok(res)) => eprintln!("Webhook {url} answered {}", res.status()),
+      Ok(Err(e)) => eprintln!("Webhook {url} failed: {e}"),
+      Err(_) => eprintln!("Webhook {url} timed out"),
+    }
+    if attempt < retries {
+      tokio::time::sleep(delay).await;
+      delay *= 2;
+    }
+  }
+  eprintln!("Giving up on webhook {url} after {} attempts", retries + 1);
+}
+
+/// Runs `command` through `sh -c`, with `TAU_EVENT` and a `TAU_<FIELD>` variable per field. The
+/// command is killed once it runs longer than `limit`.
+async fn run(command: &str, payload: &Value, limit: Duration) {
+  let mut cmd = Command::new("sh");
+  cmd.arg("-c").arg(command).kill_on_drop(true);
+  if let Value::Object(fields) = payload {
+    for (key, value) in fields {
+      let value = match value {
+        Value::Null => continue,
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+      };
+      cmd.env(format!("TAU_{}", key.to_uppercase()), value);
+    }
+  }
+  match tokio::time::timeout(limit, cmd.status()).await {
+    Ok(Ok(status)) if status.success() => {},
+    Ok(Ok(status)) => eprintln!("Hook command '{command}' exited with {status}"),
+    Ok(Err(e)) => eprintln!("Could not run hook command '{command}': {e}"),
+    Err(_) => eprintln!("Hook command '{command}' timed out after {}s", limit.as_secs()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::convert::Infallible;
+  use std::net::SocketAddr;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use http_body_util::BodyExt;
+  use hyper::{Response, StatusCode, body::Incoming, server::conn::http1, service::service_fn};
+  use hyper_util::rt::TokioIo;
+  use tokio::net::TcpListener;
+  use crate::events::Events;
+
+  /// Answers 500 to the first `failures` requests and 200 after, reporting every body received.
+  async fn stub_server(failures: usize) -> (SocketAddr, mpsc::UnboundedReceiver<Value>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let seen = Arc::new(AtomicUsize::new(0));
+    tokio::spawn(async move {
+      loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (tx, seen) = (tx.clone(), seen.clone());
+        tokio::spawn(http1::Builder::new().serve_connection(
+          TokioIo::new(stream),
+          service_fn(move |req: Request<Incoming>| {
+            let (tx, seen) = (tx.clone(), seen.clone());
+            async move {
+              let body = req.into_body().collect().await.unwrap().to_bytes();
+              tx.send(serde_json::from_slice(&body).unwrap()).unwrap();
+              let status = if seen.fetch_add(1, Ordering::SeqCst) < failures {
+                StatusCode::INTERNAL_SERVER_ERROR
+              } else {
+                StatusCode::OK
+              };
+              Ok::<_, Infallible>(Response::builder().status(status).body(Full::new(Bytes::new())).unwrap())
+            }
+          }),
+        ));
+      }
+    });
+    (addr, rx)
+  }
+
+  fn webhook(addr: SocketAddr, events: Option<&[&str]>) -> HookConfig {
+    HookConfig {
+      events: events.map(|events| events.iter().map(ToString::to_string).collect()),
+      webhook: Some(format!("http://{addr}/hook")),
+      command: None,
+      retries: Some(1),
+    }
+  }
+
+  #[test]
+  fn rejects_unknown_events_and_ambiguous_sinks() {
+    let addr = "127.0.0.1:1".parse().unwrap();
+    assert!(Hook::from_config(&webhook(addr, Some(&["source_connected"]))).is_ok());
+    assert!(Hook::from_config(&webhook(addr, Some(&["source_connect"]))).is_err());
+    let both = HookConfig { command: Some("true".into()), ..webhook(addr, None) };
+    assert!(Hook::from_config(&both).is_err());
+  }
+
+  #[tokio::test]
+  async fn delivers_only_the_events_a_hook_wants() {
+    let (addr, mut rx) = stub_server(0).await;
+    let hook = Hook::from_config(&webhook(addr, Some(&["mount_idle"]))).unwrap();
+    let events = Events::new();
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let task = tokio::spawn(thread(vec![hook], events.subscribe(), shutdown_rx));
+
+    events.emit(Event::HeadersChanged { mount: "/tau.ogg".into() });
+    events.emit(Event::MountIdle { mount: "/tau.ogg".into(), idle_secs: 10 });
+    let payload = rx.recv().await.unwrap();
+    assert_eq!(payload["event"], "mount_idle");
+    assert_eq!(payload["mount"], "/tau.ogg");
+    assert!(payload["timestamp"].as_u64().is_some());
+
+    shutdown_tx.send(true).unwrap();
+    task.await.unwrap().unwrap();
+    assert!(rx.try_recv().is_err());
+  }
+
+  #[tokio::test]
+  async fn retries_failed_webhooks() {
+    let (addr, mut rx) = stub_server(1).await;
+    let payload = payload(&Event::HeadersChanged { mount: "/tau.ogg".into() });
+    post(&https_client(), &format!("http://{addr}/hook").parse().unwrap(), &payload, 1).await;
+    assert_eq!(rx.recv().await.unwrap(), payload);
+    assert_eq!(rx.recv().await.unwrap(), payload);
+    assert!(rx.try_recv().is_err());
+  }
+
+  #[tokio::test]
+  async fn hanging_commands_are_killed() {
+    let payload = payload(&Event::HeadersChanged { mount: "/tau.ogg".into() });
+    let started = std::time::Instant::now();
+    run("sleep 30", &payload, Duration::from_millis(100)).await;
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+}
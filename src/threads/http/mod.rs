@@ -1,52 +1,59 @@
 
+use std::time::Duration;
 use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
-use tokio::{net::TcpListener, sync::{RwLock, broadcast}};
-use hyper::server::conn::http1;
-use std::sync::Arc;
-use hyper::body::Bytes;
-use crate::server::handle_request;
-use crate::util::ogg_headers::OggHeaders;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use crate::server::{ServerState, handle_request};
+use crate::util::socket::Listener;
 
 use super::TIMEOUT;
 
+/// Interval of HTTP/2 pings, so listeners that went away without closing their connection are
+/// noticed even though the stream never stops sending.
+const H2_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(20);
+const H2_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(20);
+/// Audio buffered per HTTP/2 stream when the listener's flow control window is exhausted.
+/// Beyond it the stream is not polled, and the listener falls behind on the broadcast channel.
+const H2_MAX_SEND_BUF_SIZE: usize = 256 * 1024;
+
+/// Serves the listeners on the broadcast sockets, bound from the config or passed by systemd.
+/// HTTP/1.1 and HTTP/2, both over TLS from a proxy and as cleartext h2c with prior knowledge,
+/// are told apart from the first bytes of each connection. HTTP/1.1 connections may upgrade
+/// to a WebSocket on the mount path.
 pub async fn thread(
-  server_addr: impl tokio::net::ToSocketAddrs + std::fmt::Debug + Send + Sync,
-  tx: broadcast::Sender<Bytes>,
-  header: Arc<RwLock<Option<OggHeaders>>>,
-  mount: &'static str,
-  allowed_origins: Arc<Option<Vec<&'static str>>>,
+  server: Listener,
+  state: ServerState,
   mut shutdown_rx: tokio::sync::watch::Receiver<bool>
 ) -> anyhow::Result<()> {
-  let listener = match TcpListener::bind(&server_addr).await {
-    Ok(tl) => tl,
-    Err(e) => {
-      anyhow::bail!("Could not create TcpListener: {e} {server_addr:#?}");
-    }
-  };
+  let mut builder = auto::Builder::new(TokioExecutor::new());
+  builder.http2()
+    .timer(TokioTimer::new())
+    .keep_alive_interval(H2_KEEP_ALIVE_INTERVAL)
+    .keep_alive_timeout(H2_KEEP_ALIVE_TIMEOUT)
+    .max_send_buf_size(H2_MAX_SEND_BUF_SIZE);
 
   loop {
     tokio::select! {
       _ = shutdown_rx.changed() => break,
-      conn = listener.accept() => match conn {
+      conn = server.accept() => match conn {
         Err(e) => {
           eprintln!("Accept error: {e}");
           tokio::time::sleep(TIMEOUT).await; // avoid busy loop
         }
-        Ok((stream, _peer)) => {
-          let _ = stream.set_nodelay(true);
+        Ok((_, peer)) if !state.live.load().listener_access.admit(peer.ip(), "listener") => {}
+        Ok((stream, peer)) => {
+          stream.set_nodelay();
           let io = TokioIo::new(stream);
 
           tokio::task::spawn({
-            let tx = tx.clone();
-            let ogg_headers = header.clone();
-            let allowed_origins = allowed_origins.clone();
+            let state = state.clone();
+            let builder = builder.clone();
             async move {
-              if let Err(err) = http1::Builder::new()
-                .serve_connection(
+              if let Err(err) = builder
+                .serve_connection_with_upgrades(
                   io,
                   service_fn(move |req| {
-                    handle_request(req, tx.clone(), ogg_headers.clone(), mount, allowed_origins.clone())
+                    handle_request(req, state.clone(), peer)
                   }),
                 )
                 .await
@@ -62,3 +69,39 @@ pub async fn thread(
 
   anyhow::Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use http_body_util::Full;
+  use hyper::{Request, StatusCode, Version, body::Bytes, header::CONNECTION};
+  use hyper_util::client::legacy::{Client, connect::HttpConnector};
+  use crate::server::tests::state;
+  use crate::util::ogg_headers::{Codec, OggHeaders};
+  use crate::util::socket::{Address, Binding};
+
+  #[tokio::test]
+  async fn serves_http1_and_h2c_on_one_port() {
+    let binding = Binding { tcp: Some("127.0.0.1:0".parse().unwrap()), unix: None, mode: 0o660 };
+    let listener = Listener::bind(&binding).await.unwrap();
+    let [Address::Tcp(addr)] = listener.addresses()[..] else { panic!("not bound to TCP") };
+    let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let state = state("");
+    // the stream answers once the mount has its headers
+    *state.mount.ogg_headers.write().await = Some(OggHeaders { codec: Codec::Opus, pages: Vec::new() });
+    tokio::spawn(thread(listener, state, shutdown_rx));
+
+    for http2 in [false, true] {
+      let client: Client<_, Full<Bytes>> = Client::builder(TokioExecutor::new())
+        .http2_only(http2)
+        .build(HttpConnector::new());
+      for path in ["/", "/tau.ogg"] {
+        let req = Request::get(format!("http://{addr}{path}")).body(Full::default()).unwrap();
+        let res = client.request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK, "{path}");
+        assert_eq!(res.version(), if http2 { Version::HTTP_2 } else { Version::HTTP_11 });
+        assert!(res.headers().get(CONNECTION).is_none());
+      }
+    }
+  }
+}
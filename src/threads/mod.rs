@@ -1,6 +1,13 @@
 pub mod ws;
 // pub mod udp;
 pub mod http;
+pub mod hooks;
+pub mod reload;
+pub mod segmenter;
+pub mod transcoder;
+pub mod systemd;
+#[cfg(unix)]
+pub mod control;
 
 use std::time::Duration;
 
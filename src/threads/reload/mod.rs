@@ -0,0 +1,133 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use arc_swap::ArcSwap;
+use tokio::sync::{mpsc, oneshot};
+use inline_colorization::{color_reset, color_bright_red, color_bright_yellow};
+use crate::args::Args;
+use crate::config::Config;
+use crate::config::live::{Live, changed_settings};
+use crate::control::Reply;
+use crate::util::systemd;
+
+/// How often `tower.toml` is checked for modifications.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reloads `tower.toml` on SIGHUP, a `tau-tower reload` request or when the file changes,
+/// re-applying the environment and CLI overrides, and swaps the new [`Live`] settings in once the
+/// whole config has validated.
+pub async fn thread(
+  path: PathBuf,
+  args: Args,
+  startup: Config,
+  live: Arc<ArcSwap<Live>>,
+  mut requests: mpsc::Receiver<oneshot::Sender<Reply>>,
+  mut shutdown_rx: tokio::sync::watch::Receiver<bool>
+) -> anyhow::Result<()> {
+  let mut running = startup.clone();
+  let mut hangup = hangup_signal()?;
+  let mut modified = modified_at(&path);
+  let mut poll = tokio::time::interval(POLL_INTERVAL);
+
+  loop {
+    let requester = tokio::select! {
+      _ = shutdown_rx.changed() => break,
+      _ = hangup.recv() => {
+        println!("{color_bright_yellow}SIGHUP received, reloading config{color_reset}");
+        modified = modified_at(&path);
+        None
+      },
+      Some(requester) = requests.recv() => {
+        println!("{color_bright_yellow}Reload requested on the control socket{color_reset}");
+        modified = modified_at(&path);
+        Some(requester)
+      },
+      _ = poll.tick() => {
+        let now = modified_at(&path);
+        if now == modified {
+          continue;
+        }
+        modified = now;
+        println!("{color_bright_yellow}{} changed, reloading config{color_reset}", path.display());
+        None
+      },
+    };
+
+    systemd::reloading();
+    let reply = match reload(&path, &args) {
+      Ok((config, new_live)) => {
+        let (applied, _) = changed_settings(&running, &config);
+        // compared against startup, since these still hold the values bound at startup
+        let (_, restart) = changed_settings(&startup, &config);
+        live.store(Arc::new(new_live));
+        if !applied.is_empty() {
+          println!("Applied: {}", applied.join(", "));
+        }
+        if !restart.is_empty() {
+          let restart: Vec<_> = restart.iter().map(|name| restart_note(name)).collect();
+          println!("{color_bright_yellow}Requires a restart to take effect: {}{color_reset}", restart.join(", "));
+        }
+        running = config;
+        Reply::Reloaded {
+          applied: applied.into_iter().map(String::from).collect(),
+          restart: restart.into_iter().map(restart_note).collect(),
+        }
+      },
+      Err(e) => {
+        eprintln!("{color_bright_red}Config not reloaded, keeping the running one: {e}{color_reset}");
+        Reply::Error { message: format!("config not reloaded, keeping the running one: {e}") }
+      },
+    };
+    systemd::ready();
+    if let Some(requester) = requester {
+      let _ = requester.send(reply);
+    }
+  }
+
+  anyhow::Ok(())
+}
+
+/// Names a setting requiring a restart, pointing out those that set up the mounts, as mounts are
+/// never added, removed or renamed on a running server.
+fn restart_note(name: &str) -> String {
+  match name {
+    "broadcast_endpoint" | "variants" => format!("{name} (mounts are not reloadable)"),
+    name => name.to_string(),
+  }
+}
+
+/// Loads the config as at startup: without `tower.toml`, from the environment and CLI alone.
+fn reload(path: &PathBuf, args: &Args) -> anyhow::Result<(Config, Live)> {
+  let config = if path.exists() {
+    Config::load(path, args)?
+  } else {
+    Config::from_layers(toml::Table::new(), args)?
+  };
+  let live = Live::from_config(&config)?;
+  Ok((config, live))
+}
+
+fn modified_at(path: &PathBuf) -> Option<SystemTime> {
+  std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(unix)]
+fn hangup_signal() -> std::io::Result<tokio::signal::unix::Signal> {
+  tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+}
+
+/// SIGHUP does not exist outside of unix, the config is then only reloaded on file changes.
+#[cfg(not(unix))]
+fn hangup_signal() -> std::io::Result<NoSignal> {
+  Ok(NoSignal)
+}
+
+#[cfg(not(unix))]
+struct NoSignal;
+
+#[cfg(not(unix))]
+impl NoSignal {
+  async fn recv(&mut self) -> Option<()> {
+    std::future::pending().await
+  }
+}
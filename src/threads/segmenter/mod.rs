@@ -0,0 +1,111 @@
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+use hyper::body::Bytes;
+use tokio::sync::broadcast::error::RecvError;
+use crate::fmp4::{self, Sample};
+use crate::mount::Mount;
+use crate::mount::segments::Segment;
+use crate::util::ogg_packets::PacketReader;
+use crate::util::opus::{SAMPLE_RATE, packet_samples};
+
+/// Duration after which a segment is cut, at the next packet boundary.
+pub const SEGMENT_DURATION: Duration = Duration::from_secs(2);
+
+/// Remuxes the Opus packets of the source into fragmented MP4 segments for HLS. It follows the
+/// broadcast like a listener, so the segments are cut from the pages `ws::receive_data` sends.
+pub async fn thread(
+  mount: Mount,
+  mut shutdown_rx: tokio::sync::watch::Receiver<bool>
+) -> anyhow::Result<()> {
+  let mut rx = mount.tx.subscribe();
+  let mut segmenter = Segmenter::default();
+  loop {
+    tokio::select! {
+      _ = shutdown_rx.changed() => break,
+      page = rx.recv() => match page {
+        Ok(page) => segmenter.push(&mount, &page),
+        Err(RecvError::Lagged(_)) => segmenter.reader.reset(),
+        Err(RecvError::Closed) => break,
+      }
+    }
+  }
+  anyhow::Ok(())
+}
+
+#[derive(Default)]
+struct Segmenter {
+  reader: PacketReader,
+  /// Id of the init segment of the current source stream, `None` before its `OpusHead`.
+  init: Option<u64>,
+  opus_head: Option<Bytes>,
+  /// When the first source started, at decode time 0.
+  started: Option<Instant>,
+  /// Decode time of the next segment, in 48 kHz samples. It keeps counting across sources, and
+  /// skips ahead over the time without one, so it stays in step with the wall clock.
+  decode_time: u64,
+  samples: Vec<Sample>,
+  duration: u64,
+  discontinuity: bool,
+}
+
+impl Segmenter {
+  fn push(&mut self, mount: &Mount, page: &Bytes) {
+    for packet in self.reader.push(page) {
+      if packet.starts_with(b"OpusHead") {
+        self.new_stream(mount, packet);
+      } else if self.init.is_some()
+        && !packet.starts_with(b"OpusTags")
+        && let Some(duration) = packet_samples(&packet) {
+        self.duration += u64::from(duration);
+        self.samples.push(Sample { duration, data: packet });
+        if self.duration >= SEGMENT_DURATION.as_secs() * u64::from(SAMPLE_RATE) {
+          self.cut(mount);
+        }
+      }
+    }
+  }
+
+  /// A source (re)connected: the audio so far is cut into a last segment, and the next one is
+  /// marked as a discontinuity, with a new init segment if the stream parameters changed.
+  fn new_stream(&mut self, mount: &Mount, opus_head: Bytes) {
+    self.cut(mount);
+    if let Some(started) = self.started {
+      let elapsed = started.elapsed().as_micros() * u128::from(SAMPLE_RATE) / 1_000_000;
+      self.decode_time = self.decode_time.max(u64::try_from(elapsed).unwrap_or(u64::MAX));
+    } else {
+      self.started = Some(Instant::now());
+      mount.segments.set_availability_start(SystemTime::now());
+    }
+    self.discontinuity = self.decode_time > 0;
+    if self.init.is_some() && self.opus_head.as_ref() == Some(&opus_head) {
+      return;
+    }
+    self.init = fmp4::init_segment(&opus_head).map(|init| mount.segments.add_init(init, self.decode_time));
+    if self.init.is_none() {
+      eprintln!("Could not remux the stream on {} for HLS: unsupported OpusHead", mount.path);
+    }
+    self.opus_head = Some(opus_head);
+  }
+
+  fn cut(&mut self, mount: &Mount) {
+    let Some(init) = self.init else { return };
+    if self.samples.is_empty() {
+      return;
+    }
+    let sequence = mount.segments.next_sequence();
+    // the fragment sequence number only has to increase within a stream
+    let fragment = u32::try_from(sequence % u64::from(u32::MAX)).unwrap_or_default() + 1;
+    let data = fmp4::media_segment(fragment, self.decode_time, &self.samples);
+    mount.segments.push(Segment {
+      sequence,
+      init,
+      start: self.decode_time,
+      duration: self.duration,
+      discontinuity: std::mem::take(&mut self.discontinuity),
+      data,
+    });
+    self.decode_time += self.duration;
+    self.samples.clear();
+    self.duration = 0;
+  }
+}
@@ -0,0 +1,38 @@
+use tokio::sync::broadcast;
+use crate::events::Event;
+use crate::util::systemd;
+
+/// Keeps the `systemctl status` line in step with the source, and pings the systemd watchdog
+/// when the unit has `WatchdogSec=` set.
+pub async fn thread(
+  mut events: broadcast::Receiver<Event>,
+  mut shutdown_rx: tokio::sync::watch::Receiver<bool>
+) -> anyhow::Result<()> {
+  let watchdog = systemd::watchdog_interval();
+  // systemd recommends pinging at half the timeout
+  let mut ping = tokio::time::interval(watchdog.unwrap_or_default().max(std::time::Duration::from_secs(2)) / 2);
+
+  loop {
+    tokio::select! {
+      _ = shutdown_rx.changed() => break,
+      _ = ping.tick(), if watchdog.is_some() => systemd::watchdog(),
+      event = events.recv() => match event {
+        Ok(Event::SourceConnected { mount, username, .. }) => {
+          systemd::status(&format!("Source '{username}' live on {mount}"));
+        },
+        Ok(Event::SourceDisconnected { mount, .. }) => systemd::status(&waiting_status(&mount)),
+        Ok(Event::MountIdle { mount, idle_secs }) => {
+          systemd::status(&format!("Source on {mount} idle for {idle_secs}s"));
+        },
+        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {},
+        Err(broadcast::error::RecvError::Closed) => break,
+      }
+    }
+  }
+
+  anyhow::Ok(())
+}
+
+pub fn waiting_status(mount: &str) -> String {
+  format!("Waiting for a source on {mount}")
+}
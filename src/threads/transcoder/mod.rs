@@ -0,0 +1,206 @@
+use hyper::body::Bytes;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use crate::events::{Event, Events};
+use crate::mount::Mount;
+use crate::transcode::{Output, TranscodeError, Transcoder, Variant};
+use crate::util::ogg_headers::set_opus_tags;
+
+/// Source pages queued for the encoder before the broadcast is left to lag.
+const QUEUED_JOBS: usize = 64;
+
+/// Work for the encoder thread, in the order of the source's broadcast.
+enum Job {
+  Page(Bytes),
+  /// Source pages were missed.
+  Lagged,
+  /// The source disconnected.
+  Stop,
+}
+
+/// What the encoder thread made of a [`Job`].
+enum Done {
+  Output(Output),
+  Failed(TranscodeError),
+  Stopped,
+}
+
+/// Transcodes the source's stream onto a variant's mount. The variant starts a stream with each
+/// stream of the source and stops when the source disconnects, and it follows the broadcast like
+/// a listener, as the segmenter does. Encoding runs on a thread of its own, off the runtime.
+pub async fn thread(
+  source: Mount,
+  variant: Variant,
+  events: Events,
+  mut shutdown_rx: tokio::sync::watch::Receiver<bool>
+) -> anyhow::Result<()> {
+  let mut rx = source.tx.subscribe();
+  let mut lifecycle = events.subscribe();
+  let mount = &variant.mount;
+  let (jobs, mut done) = encoder(mount.path, Transcoder::new(&variant))?;
+  loop {
+    // a disconnect is queued before any page of the next source, which can only follow it
+    let job = tokio::select! {
+      biased;
+      _ = shutdown_rx.changed() => break,
+      done = done.recv() => {
+        match done {
+          Some(Done::Output(output)) => publish(&source, mount, &events, output).await,
+          Some(Done::Failed(e)) => {
+            eprintln!("Could not transcode the stream on {} for {}: {e}", source.path, mount.path);
+          },
+          Some(Done::Stopped) => mount.source_disconnected(),
+          None => anyhow::bail!("the encoder of {} stopped", mount.path),
+        }
+        continue;
+      },
+      event = lifecycle.recv() => match event {
+        Ok(Event::SourceDisconnected { mount: path, .. }) if path == source.path => Job::Stop,
+        Ok(Event::MetadataUpdated { mount: path }) if path == source.path => {
+          update_tags(&source, mount, &events).await;
+          continue;
+        },
+        // missed events are made up for from the state of the source
+        Err(RecvError::Lagged(_)) if source.source().is_none() && mount.source().is_some() => Job::Stop,
+        Err(RecvError::Lagged(_)) => {
+          update_tags(&source, mount, &events).await;
+          continue;
+        },
+        Err(RecvError::Closed) => break,
+        _ => continue,
+      },
+      page = rx.recv() => match page {
+        Ok(page) => Job::Page(page),
+        Err(RecvError::Lagged(_)) => Job::Lagged,
+        Err(RecvError::Closed) => break,
+      },
+    };
+    if jobs.send(job).await.is_err() {
+      anyhow::bail!("the encoder of {} stopped", mount.path);
+    }
+  }
+  anyhow::Ok(())
+}
+
+/// Starts the thread running `transcoder`, as encoding is CPU bound. It takes the jobs in order
+/// and ends once the sender is dropped. Its results are not bounded, so that it never waits on
+/// the task feeding it.
+fn encoder(
+  path: &str,
+  mut transcoder: Transcoder
+) -> anyhow::Result<(mpsc::Sender<Job>, mpsc::UnboundedReceiver<Done>)> {
+  let (jobs_tx, mut jobs) = mpsc::channel(QUEUED_JOBS);
+  let (done_tx, done) = mpsc::unbounded_channel();
+  std::thread::Builder::new()
+    .name(format!("transcoder {path}"))
+    .spawn(move || {
+      while let Some(job) = jobs.blocking_recv() {
+        let done = match job {
+          Job::Page(page) => transcoder.push(&page).map_or_else(Done::Failed, Done::Output),
+          Job::Lagged => {
+            transcoder.reset_reader();
+            continue;
+          },
+          Job::Stop => {
+            transcoder.stop();
+            Done::Stopped
+          },
+        };
+        if done_tx.send(done).is_err() {
+          break;
+        }
+      }
+    })?;
+  Ok((jobs_tx, done))
+}
+
+/// Broadcasts the pages of the variant, after making a new stream's headers those sent to
+/// listeners joining, and its source that of the stream it is made from.
+async fn publish(source: &Mount, mount: &Mount, events: &Events, output: Output) {
+  if let Some(headers) = output.headers {
+    mount.source_connected_as(source);
+    *mount.ogg_headers.write().await = Some(headers);
+    events.emit(Event::HeadersChanged { mount: mount.path.to_string() });
+  }
+  for page in output.pages {
+    mount.page_received(&page);
+    // without listeners, the page is dropped
+    let _ = mount.tx.send(page);
+  }
+}
+
+/// Carries the comments of the source, as set with `metadata set`, over to the variant.
+async fn update_tags(source: &Mount, mount: &Mount, events: &Events) {
+  let comments = source.ogg_headers.read().await.as_ref()
+    .and_then(|headers| headers.opus_tags().ok())
+    .map(|tags| tags.comments);
+  let mut headers = mount.ogg_headers.write().await;
+  let (Some(comments), Some(current)) = (comments, headers.as_mut()) else { return };
+  let Some(page) = current.pages.get_mut(1) else { return };
+  match set_opus_tags(page, &comments) {
+    Ok(tags) => *page = tags,
+    Err(e) => {
+      eprintln!("Could not update the OpusTags header of {}: {e}", mount.path);
+      return;
+    },
+  }
+  drop(headers);
+  events.emit(Event::MetadataUpdated { mount: mount.path.to_string() });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use crate::transcode::tests::source;
+
+  /// Waits for `condition`, failing the test after a few seconds.
+  async fn until(mut condition: impl FnMut() -> bool) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+      while !condition() {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+      }
+    }).await.unwrap();
+  }
+
+  // a current-thread runtime, on which the encoder must not block the task
+  #[tokio::test]
+  async fn follows_the_source_until_shutdown() {
+    let source_mount = Mount::new("/tau.ogg", 3);
+    let variant = Variant {
+      mount: source_mount.variant("/tau-low.ogg", 3),
+      bitrate: 24_000,
+      channels: None,
+      complexity: 0,
+    };
+    let events = Events::new();
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let task = tokio::spawn(thread(source_mount.clone(), variant.clone(), events.clone(), shutdown_rx));
+    until(|| source_mount.tx.receiver_count() == 1).await;
+
+    for lagged in [false, true] {
+      source_mount.source_connected("night-show".into(), "127.0.0.1:5000".parse().unwrap());
+      for page in source(25) {
+        source_mount.tx.send(page).unwrap();
+      }
+      until(|| variant.mount.clock.info().is_some_and(|clock| clock.received_secs > 0.4)).await;
+      assert!(variant.mount.ogg_headers.read().await.is_some());
+      assert_eq!(variant.mount.source().unwrap().username, "night-show");
+
+      source_mount.source_disconnected();
+      if lagged {
+        // the disconnect is lost among events the task had no time to read
+        for _ in 0..300 {
+          events.emit(Event::HeadersChanged { mount: "/other.ogg".into() });
+        }
+      } else {
+        events.emit(Event::SourceDisconnected { mount: "/tau.ogg".into(), remote_addr: String::new() });
+      }
+      until(|| variant.mount.clock.info().is_none()).await;
+      assert!(variant.mount.source().is_none());
+    }
+
+    shutdown_tx.send(true).unwrap();
+    task.await.unwrap().unwrap();
+  }
+}
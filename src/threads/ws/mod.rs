@@ -1,59 +1,87 @@
-use hyper::{Request, Response, StatusCode};
-use tokio::sync::broadcast;
+use hyper::{Request, Response, StatusCode, Version, header::AUTHORIZATION};
+use std::net::SocketAddr;
 use std::sync::Arc;
 
-use hyper::body::Bytes;
-use tokio_tungstenite::{accept_hdr_async, WebSocketStream};
-use tokio::sync::RwLock;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Semaphore;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{self, Message, handshake::server::{create_response, write_response}, protocol::Role};
 use futures_util::StreamExt;
-use std::net::SocketAddr;
 use std::time::Duration;
 
-use tokio::net::{TcpListener, TcpStream};
 use tokio::time::Instant;
+use crate::events::{Event, Events};
+use crate::mount::Mount;
+use crate::server::health::Health;
 use crate::threads::LOG_TIMEOUT;
-use crate::util::credentials::Credentials;
-use crate::util::ogg_headers::{OggHeaderType, OggHeaders, parse_ogg_headers};
+use crate::config::live::Live;
+use arc_swap::ArcSwap;
+use crate::util::credentials::{AuthError, Credentials, Identity};
+use crate::util::lockout::{Attempt, Lockout};
+use crate::util::mpeg_frames::FrameReader;
+use crate::util::ogg_headers::{Codec, HeaderCapture, OggHeaders};
+use crate::util::socket::{Listener, Stream};
 
 const TIMEOUT: Duration = Duration::from_millis(50);
+/// Time a connection gets to complete the WebSocket handshake before it is dropped and its
+/// address locked out.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Largest handshake request head read from a source.
+const MAX_REQUEST: usize = 16 * 1024;
+/// Password verifications run at once, across all addresses, each taking a blocking thread.
+const MAX_VERIFICATIONS: usize = 4;
+/// Silence from a connected source after which the mount is reported idle.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
 
-/// Creates a WebSocket receiver listening to the sender of the ogg opus stream.
+/// Creates a WebSocket receiver listening to the sender of the ogg opus stream, on the source
+/// sockets bound from the config or passed by systemd.
 /// Appending the ogg opus blocks to a producer/consumer object.
 pub async fn thread(
-  tx: broadcast::Sender<Bytes>,
-  listen_addr: SocketAddr,
-  credentials: Credentials,
-  header: Arc<RwLock<Option<OggHeaders>>>,
+  server: Listener,
+  mount: Mount,
+  live: Arc<ArcSwap<Live>>,
+  events: Events,
+  health: Health,
   mut shutdown_rx: tokio::sync::watch::Receiver<bool>
 ) -> anyhow::Result<()> {
-  let server = match TcpListener::bind(listen_addr).await {
-    Ok(s) => s,
-    Err(e) => {
-      anyhow::bail!("Could not bind to source address: {e}");
-    }
-  };
+  let lockout = Arc::new(Lockout::default());
+  let verifications = Arc::new(Semaphore::new(MAX_VERIFICATIONS));
+  // sources are served one at a time, a second one waits for the first to disconnect
+  let slot = Arc::new(tokio::sync::Mutex::new(()));
+  let listening = health.source_listening();
 
   loop {
     tokio::select! {
       _ = shutdown_rx.changed() => break,
-      conn = server.accept() => match conn {
-        Err(e) => {
-          eprintln!("{e}");
-          tokio::time::sleep(TIMEOUT).await;
-        }
-        Ok((stream, addr)) => {
-          match accept_hdr_async(stream, |req: &Request<_>, res: hyper::Response<()>| {
-            // unbox large error
-            validate_headers(req, res, &credentials)
-          })
-          .await
-          {
-            Ok(mut ws_stream) => {
-              receive_data(&mut ws_stream, header.clone(), tx.clone()).await;
-            }
-            Err(e) => {
-              eprintln!("Handshake failed from {addr}: {e}");
-            }
+      conn = server.accept() => {
+        listening.accepting(conn.is_ok());
+        match conn {
+          Err(e) => {
+            eprintln!("{e}");
+            tokio::time::sleep(TIMEOUT).await;
+          }
+          Ok((_, addr)) if !live.load().source_access.admit(addr.ip(), "source") => {}
+          Ok((stream, addr)) if !stream.is_unix() && lockout.locked(addr.ip()).is_some() => {
+            eprintln!("Dropped source connection from {addr}: locked out after failed attempts");
+          }
+          Ok((stream, addr)) => {
+            let attempt = if stream.is_unix() {
+              None
+            } else if let Some(attempt) = lockout.attempt(addr.ip()) {
+              Some(attempt)
+            } else {
+              eprintln!("Dropped source connection from {addr}: another handshake from it is in progress");
+              continue;
+            };
+            // each handshake runs on its own, so an idle connection cannot hold up the others
+            tokio::spawn(serve_source(stream, addr, SourceState {
+              mount: mount.clone(),
+              live: live.load_full(),
+              events: events.clone(),
+              attempt,
+              verifications: verifications.clone(),
+              slot: slot.clone(),
+            }));
           }
         }
       }
@@ -63,63 +91,318 @@ pub async fn thread(
   anyhow::Ok(())
 }
 
+/// What a source connection needs from the ingest loop.
+struct SourceState {
+  mount: Mount,
+  live: Arc<Live>,
+  events: Events,
+  /// The attempt of the address, `None` for Unix socket peers, which all share one address and
+  /// are not locked out.
+  attempt: Option<Attempt>,
+  verifications: Arc<Semaphore>,
+  slot: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// Authenticates a source and receives its stream once no other source is connected. Rejected
+/// credentials and timed out handshakes count towards the lockout of the address, unless it came
+/// over a Unix socket.
+async fn serve_source(stream: Stream, addr: SocketAddr, state: SourceState) {
+  let SourceState { mount, live, events, attempt, verifications, slot } = state;
+  let handshake = handshake(stream, &live, &verifications, mount.path);
+  match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+    Ok(Ok((mut ws_stream, username))) => {
+      if let Some(attempt) = attempt {
+        attempt.succeeded();
+      }
+      let _slot = slot.lock().await;
+      mount.source_connected(username.clone(), addr);
+      events.emit(Event::SourceConnected {
+        mount: mount.path.to_string(),
+        username,
+        remote_addr: addr.to_string(),
+      });
+      receive_data(&mut ws_stream, &mount, &events).await;
+      mount.source_disconnected();
+      events.emit(Event::SourceDisconnected {
+        mount: mount.path.to_string(),
+        remote_addr: addr.to_string(),
+      });
+    }
+    Ok(Err(e)) => {
+      eprintln!("Handshake failed from {addr}: {e}");
+      if let (HandshakeError::Unauthorized(_), Some(attempt)) = (e, attempt) {
+        let locked = attempt.failed();
+        eprintln!("Locking out {addr} for {}s", locked.as_secs());
+      }
+    }
+    Err(_) => match attempt {
+      Some(attempt) => {
+        let locked = attempt.failed();
+        eprintln!("Handshake timed out from {addr}, locking it out for {}s", locked.as_secs());
+      },
+      None => eprintln!("Handshake timed out from {addr}"),
+    }
+  }
+}
+
+#[derive(Debug, thiserror::Error)]
+enum HandshakeError {
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error("malformed request: {0}")]
+  Request(String),
+  #[error(transparent)]
+  WebSocket(#[from] tungstenite::Error),
+  /// Refused for a reason other than the credentials, such as an unknown mount.
+  #[error("{0}")]
+  Rejected(String),
+  /// Missing or wrong credentials, counted towards the lockout.
+  #[error("{0}")]
+  Unauthorized(String),
+}
+
+/// Answers the WebSocket handshake of a source. The request is read here rather than by
+/// tungstenite, so that password hashes are verified on the blocking pool and not on a runtime
+/// worker, at most `verifications` at a time.
+async fn handshake(
+  mut stream: Stream,
+  live: &Arc<Live>,
+  verifications: &Semaphore,
+  mount: &'static str
+) -> Result<(WebSocketStream<Stream>, String), HandshakeError> {
+  let (req, rest) = read_request(&mut stream).await?;
+  let res = match create_response(&req) {
+    Ok(res) => res,
+    Err(e) => {
+      respond(&mut stream, &error(StatusCode::BAD_REQUEST, &format!("{e}: 400"))).await?;
+      return Err(e.into());
+    }
+  };
+  let Ok(_permit) = verifications.try_acquire() else {
+    let msg = "Too many handshakes: 503";
+    respond(&mut stream, &error(StatusCode::SERVICE_UNAVAILABLE, msg)).await?;
+    return Err(HandshakeError::Rejected(msg.to_string()));
+  };
+  let credentials = live.clone();
+  let verified = tokio::task::spawn_blocking(move || validate_headers(&req, &credentials.credentials, mount))
+    .await
+    .map_err(|e| std::io::Error::other(e.to_string()))?;
+  match verified {
+    Ok(username) => {
+      respond(&mut stream, &res.map(|()| None)).await?;
+      Ok((WebSocketStream::from_partially_read(stream, rest, Role::Server, None).await, username))
+    },
+    Err((status, msg)) => {
+      respond(&mut stream, &error(status, msg)).await?;
+      Err(match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => HandshakeError::Unauthorized(msg.to_string()),
+        _ => HandshakeError::Rejected(msg.to_string()),
+      })
+    }
+  }
+}
+
+/// Reads the head of the handshake request, returning it along with whatever followed it.
+async fn read_request(stream: &mut Stream) -> Result<(Request<()>, Vec<u8>), HandshakeError> {
+  let mut buf = Vec::with_capacity(1024);
+  loop {
+    if buf.len() >= MAX_REQUEST {
+      return Err(HandshakeError::Request("request head too large".to_string()));
+    }
+    if stream.read_buf(&mut buf).await? == 0 {
+      return Err(HandshakeError::Request("connection closed during the request".to_string()));
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Request::new(&mut headers);
+    let len = match parsed.parse(&buf) {
+      Ok(httparse::Status::Complete(len)) => len,
+      Ok(httparse::Status::Partial) => continue,
+      Err(e) => return Err(HandshakeError::Request(e.to_string())),
+    };
+    let mut req = Request::builder()
+      .method(parsed.method.unwrap_or_default())
+      .uri(parsed.path.unwrap_or_default())
+      .version(if parsed.version == Some(0) { Version::HTTP_10 } else { Version::HTTP_11 });
+    for header in parsed.headers.iter() {
+      req = req.header(header.name, header.value);
+    }
+    let req = req.body(()).map_err(|e| HandshakeError::Request(e.to_string()))?;
+    return Ok((req, buf.split_off(len)));
+  }
+}
+
+fn error(status: StatusCode, msg: &str) -> Response<Option<String>> {
+  let mut res = Response::new(Some(msg.to_string()));
+  *res.status_mut() = status;
+  res
+}
 
-#[allow(clippy::result_large_err)]
-fn validate_headers(req: &Request<()>, res: hyper::Response<()>, credentials: &Credentials) -> Result<Response<()>, Response<Option<String>>> {
-  let (Some(username), Some(password)) = (
+/// Writes the handshake response, followed by the message of an error response.
+async fn respond(stream: &mut Stream, res: &Response<Option<String>>) -> Result<(), HandshakeError> {
+  let mut head = Vec::new();
+  write_response(&mut head, res)?;
+  if let Some(body) = res.body() {
+    head.extend_from_slice(body.as_bytes());
+  }
+  stream.write_all(&head).await?;
+  stream.flush().await?;
+  Ok(())
+}
+
+/// Authenticates the source either with the `username`/`password` headers or an
+/// `Authorization: Bearer` token. The request path selects the mount, `/` meaning the default.
+fn validate_headers(
+  req: &Request<()>,
+  credentials: &Credentials,
+  mount: &str
+) -> Result<String, (StatusCode, &'static str)> {
+  let requested = match req.uri().path() {
+    "/" => mount,
+    path if path == mount => path,
+    _ => return Err((StatusCode::NOT_FOUND, "Unknown mount: 404")),
+  };
+
+  let bearer = req.headers().get(AUTHORIZATION)
+    .and_then(|a| a.to_str().ok())
+    .and_then(|a| a.strip_prefix("Bearer "))
+    .map(str::trim);
+  let identity = match (
+    bearer,
     req.headers().get("username").and_then(|u| u.to_str().ok()),
     req.headers().get("password").and_then(|p| p.to_str().ok())
-  ) else {
-    let mut res = Response::new(Some("Unauthorized access: 401".to_string()));
-    *res.status_mut() = StatusCode::UNAUTHORIZED;
-    return Err(res);
+  ) {
+    (Some(token), _, _) => Identity::Bearer(token),
+    (None, Some(username), Some(password)) => Identity::Password { username, password },
+    _ => return Err((StatusCode::UNAUTHORIZED, "Unauthorized access: 401")),
   };
 
-  if !credentials.validate(username, password) {
-    let mut res = Response::new(Some("Access forbidden: 403".to_string()));
-    *res.status_mut() = StatusCode::FORBIDDEN;
-    return Err(res);
+  match credentials.authenticate(&identity, requested) {
+    Ok(user) => {
+      println!("Source '{}' authenticated for {requested}", user.username);
+      Ok(user.username.clone())
+    },
+    Err(AuthError::Expired) => Err((StatusCode::FORBIDDEN, "Access expired: 403")),
+    Err(AuthError::MountNotPermitted) => Err((StatusCode::FORBIDDEN, "Mount not permitted: 403")),
+    Err(AuthError::Invalid) => Err((StatusCode::FORBIDDEN, "Access forbidden: 403")),
   }
-
-  Ok(res)
 }
 
-async fn receive_data(ws_stream: &mut WebSocketStream<TcpStream>, header: Arc<RwLock<Option<OggHeaders>>>, tx: broadcast::Sender<Bytes>) {
-  let mut temp_headers: (Option<Bytes>, Option<Bytes>) = (None, None);
+async fn receive_data(ws_stream: &mut WebSocketStream<Stream>, mount: &Mount, events: &Events) {
+  let mut capture = HeaderCapture::default();
+  let mut pending_headers: Option<OggHeaders> = None;
+  let mut frames: Option<FrameReader> = None;
+  let mut first_message = true;
   let mut headers_parsed = false;
   let mut last_log = Instant::now();
-  'connections: while let Some(msg) = ws_stream.next().await  {
+  let mut idle = false;
+  let mut idle_at = Instant::now() + IDLE_TIMEOUT;
+  'connections: loop {
+    let msg = match tokio::time::timeout_at(idle_at, ws_stream.next()).await {
+      Ok(Some(msg)) => msg,
+      Ok(None) => break 'connections,
+      Err(_) => {
+        if !idle {
+          events.emit(Event::MountIdle { mount: mount.path.to_string(), idle_secs: IDLE_TIMEOUT.as_secs() });
+          idle = true;
+        }
+        idle_at = Instant::now() + IDLE_TIMEOUT;
+        continue 'connections;
+      }
+    };
+
     let page = match msg {
-      Ok(m) => m.into_data(),
+      Ok(Message::Binary(page)) => {
+        mount.page_received(&page);
+        idle = false;
+        idle_at = Instant::now() + IDLE_TIMEOUT;
+        page
+      },
+      Ok(Message::Close(_)) => break 'connections,
+      // pings are answered by tungstenite, and the stream is only sent as binary messages
+      Ok(_) => continue 'connections,
       Err(e) => {
         eprintln!("Unrecognized message: {e}");
         break 'connections;
       }
     };
 
-    // short circuit if headers have already been parsed.
-    if !headers_parsed { 
-      match parse_ogg_headers(&page) {
-        OggHeaderType::Head(head) => {temp_headers.0 = Some(head);},
-        OggHeaderType::Tags(tags) => {temp_headers.1 = Some(tags);},
-        OggHeaderType::None => {}
+    // a source that does not send Ogg pages is taken for MP3 or ADTS AAC
+    if first_message {
+      first_message = false;
+      if !page.starts_with(b"OggS") {
+        frames = Some(FrameReader::default());
+      }
+    }
+    let page = if let Some(reader) = &mut frames {
+      let data = reader.push(&page);
+      if !headers_parsed && pending_headers.is_none()
+        && let Some(codec) = reader.codec() {
+        println!("{codec} stream found");
+        pending_headers = Some(OggHeaders { codec, pages: Vec::new() });
       }
-      
-      if let (Some(head), Some(tags)) = &temp_headers 
-        && let Ok(mut h) = header.try_write() 
-        && (*h).is_none() {
-        *h = Some(OggHeaders::new((head.clone(), tags.clone())));
+      data
+    } else {
+      // short circuit if headers have already been parsed.
+      if !headers_parsed
+        && let Some(headers) = capture.push(&page) {
+        if headers.codec == Codec::Opus
+          && let Err(e) = headers.opus_head().and_then(|_| headers.opus_tags()) {
+          eprintln!("Invalid stream headers from the source: {e}");
+        }
+        println!("{} stream headers found", headers.codec);
+        pending_headers = Some(headers);
+      }
+      page
+    };
+
+    if let Some(headers) = pending_headers.take() {
+      if let Ok(mut h) = mount.ogg_headers.try_write() {
+        let event = match h.as_ref() {
+          Some(prev) if *prev == headers => None,
+          Some(prev) if prev.same_stream(&headers) => Some(Event::MetadataUpdated { mount: mount.path.to_string() }),
+          _ => Some(Event::HeadersChanged { mount: mount.path.to_string() }),
+        };
+        *h = Some(headers);
         headers_parsed = true;
+        if let Some(event) = event {
+          events.emit(event);
+        }
+      } else {
+        pending_headers = Some(headers);
       }
     }
+    // frames still incomplete
+    if page.is_empty() {
+      continue 'connections;
+    }
 
-    if let Err(e) = tx.send(page) 
+    if let Err(e) = mount.tx.send(page) 
       && last_log.elapsed() > LOG_TIMEOUT {
       eprintln!("could not open client stream: {e}"); 
       // Flushing headers if connection is lost
-      temp_headers = (None, None);
+      capture.reset();
       headers_parsed = false;
       last_log = Instant::now();
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::net::{TcpListener, TcpStream};
+
+  #[tokio::test]
+  async fn reads_the_request_and_keeps_what_follows() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    client.write_all(b"GET /tau.ogg HTTP/1.1\r\nHost: tower\r\nUsername: ").await.unwrap();
+    client.write_all(b"source\r\n\r\n\x82\x00").await.unwrap();
+
+    let (req, rest) = read_request(&mut Stream::Tcp(server)).await.unwrap();
+    assert_eq!(req.uri().path(), "/tau.ogg");
+    assert_eq!(req.headers()["username"], "source");
+    assert_eq!(rest, b"\x82\x00");
+  }
+}
@@ -0,0 +1,439 @@
+//! Re-encoding of the source's Opus stream for the `[[variants]]` mounts, with libopus.
+
+use std::ffi::CStr;
+use std::ptr::NonNull;
+use hyper::body::Bytes;
+use unsafe_libopus::{
+  OPUS_APPLICATION_AUDIO,
+  OPUS_GET_LOOKAHEAD_REQUEST,
+  OPUS_SET_BITRATE_REQUEST,
+  OPUS_SET_COMPLEXITY_REQUEST,
+  OpusDecoder,
+  OpusEncoder,
+  opus_decode,
+  opus_decoder_create,
+  opus_decoder_destroy,
+  opus_encode,
+  opus_encoder_create,
+  opus_encoder_ctl,
+  opus_encoder_destroy,
+  opus_get_version_string,
+  opus_strerror,
+};
+use crate::config::VariantConfig;
+use crate::mount::Mount;
+use crate::util::ogg_headers::{Codec, OggHeaders};
+use crate::util::ogg_packets::{PacketReader, PageHeader, PageWriter};
+use crate::util::opus::{SAMPLE_RATE, packet_samples};
+use crate::util::opus_headers::{OpusHead, OpusHeaderError, OpusTags};
+
+/// Samples per channel of an encoded frame, 20 ms at 48 kHz.
+const FRAME_SAMPLES: usize = 960;
+/// Samples per channel of the longest Opus packet, 120 ms.
+const MAX_PACKET_SAMPLES: usize = 5760;
+/// The size libopus recommends for an encoded packet buffer.
+const MAX_PACKET_BYTES: usize = 4000;
+const COMPLEXITY: u8 = 10;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TranscodeError {
+  #[error("{0} failed: {1}")]
+  Opus(&'static str, String),
+
+  #[error("invalid source headers: {0}")]
+  Header(#[from] OpusHeaderError),
+
+  #[error("{0} sources cannot be transcoded, only Opus")]
+  UnsupportedCodec(Codec),
+
+  #[error("channel mapping family {0} cannot be transcoded, only mono and stereo")]
+  UnsupportedMapping(u8),
+}
+
+/// A mount carrying the source's stream re-encoded with its own settings. It shares the
+/// listeners of the source's mount, so they are listed and kicked together.
+#[derive(Clone)]
+pub struct Variant {
+  pub mount: Mount,
+  pub bitrate: u32,
+  /// The channel count of the source if `None`.
+  pub channels: Option<u8>,
+  pub complexity: u8,
+}
+
+impl Variant {
+  pub fn new(path: &'static str, source: &Mount, config: &VariantConfig, segment_window: usize) -> Self {
+    Self {
+      mount: source.variant(path, segment_window),
+      bitrate: config.bitrate,
+      channels: config.channels,
+      complexity: config.complexity.unwrap_or(COMPLEXITY),
+    }
+  }
+}
+
+/// What a source page made of a variant's stream.
+#[derive(Default)]
+pub struct Output {
+  /// The headers of a new stream, when the source started one.
+  pub headers: Option<OggHeaders>,
+  /// The pages to broadcast, starting with the header pages of a new stream.
+  pub pages: Vec<Bytes>,
+}
+
+/// Decodes the Opus packets of the source's pages and encodes them again in 20 ms frames, as a
+/// new Ogg Opus stream started on every `OpusHead` of the source.
+pub struct Transcoder {
+  bitrate: u32,
+  channels: Option<u8>,
+  complexity: u8,
+  reader: PacketReader,
+  stream: Option<Stream>,
+}
+
+struct Stream {
+  decoder: Decoder,
+  encoder: Encoder,
+  writer: PageWriter,
+  head: OpusHead,
+  channels: usize,
+  /// Whether the source's `OpusTags` is yet to come, the headers being written after it.
+  awaiting_tags: bool,
+  /// Decoded samples still to drop, the pre-skip of the source.
+  skip: usize,
+  /// Decoded, interleaved samples not encoded yet.
+  pcm: Vec<i16>,
+  granule_position: u64,
+}
+
+impl Transcoder {
+  pub fn new(variant: &Variant) -> Self {
+    Self {
+      bitrate: variant.bitrate,
+      channels: variant.channels,
+      complexity: variant.complexity,
+      reader: PacketReader::default(),
+      stream: None,
+    }
+  }
+
+  /// Transcodes a page of the source. Failing to start a stream stops the transcoding until the
+  /// next `OpusHead`.
+  /// # Errors
+  /// Fails on source headers that cannot be transcoded, or if libopus cannot be set up for them.
+  pub fn push(&mut self, page: &Bytes) -> Result<Output, TranscodeError> {
+    let mut output = Output::default();
+    for packet in self.reader.push(page) {
+      if let Some(codec) = Codec::detect(&packet) {
+        self.stream = None;
+        if codec != Codec::Opus {
+          return Err(TranscodeError::UnsupportedCodec(codec));
+        }
+        let serial = PageHeader::parse(page).map_or(0, |header| header.serial);
+        self.stream = Some(self.start(&OpusHead::parse(&packet)?, serial)?);
+        continue;
+      }
+      let Some(stream) = &mut self.stream else { continue };
+      if stream.awaiting_tags {
+        let headers = stream.headers(&OpusTags::parse(&packet)?)?;
+        output.pages.extend_from_slice(&headers.pages);
+        output.headers = Some(headers);
+      } else {
+        output.pages.extend(stream.transcode(&packet));
+      }
+    }
+    Ok(output)
+  }
+
+  /// Ends the stream, when the source disconnects.
+  pub fn stop(&mut self) {
+    self.stream = None;
+    self.reader.reset();
+  }
+
+  /// Forgets a partial packet, when source pages were missed.
+  pub fn reset_reader(&mut self) {
+    self.reader.reset();
+  }
+
+  fn start(&self, source: &OpusHead, serial: u32) -> Result<Stream, TranscodeError> {
+    if source.mapping_family != 0 {
+      return Err(TranscodeError::UnsupportedMapping(source.mapping_family));
+    }
+    // libopus downmixes or upmixes a stereo or mono stream to the decoder's channel count
+    let channels = self.channels.unwrap_or(source.channels);
+    let encoder = Encoder::new(channels, self.bitrate, self.complexity)?;
+    let head = OpusHead {
+      version: 1,
+      channels,
+      pre_skip: encoder.lookahead()?,
+      input_sample_rate: source.input_sample_rate,
+      // the decoder output is left as is, so the source's gain still applies
+      output_gain: source.output_gain,
+      mapping_family: 0,
+      mapping: None,
+    };
+    Ok(Stream {
+      decoder: Decoder::new(channels)?,
+      encoder,
+      writer: PageWriter::new(serial),
+      head,
+      channels: usize::from(channels),
+      awaiting_tags: true,
+      skip: usize::from(source.pre_skip) * usize::from(channels),
+      pcm: Vec::new(),
+      granule_position: 0,
+    })
+  }
+}
+
+impl Stream {
+  /// The header pages of the variant: a fresh `OpusHead`, and an `OpusTags` carrying the comments
+  /// of the source.
+  fn headers(&mut self, source: &OpusTags) -> Result<OggHeaders, TranscodeError> {
+    let tags = OpusTags {
+      vendor: vendor(),
+      comments: source.comments.clone(),
+      unparsed: source.unparsed.clone(),
+      extra: Vec::new(),
+    };
+    let mut pages = self.writer.push(&self.head.to_bytes(), 0);
+    pages.extend(self.writer.push(&tags.to_bytes()?, 0));
+    self.awaiting_tags = false;
+    Ok(OggHeaders { codec: Codec::Opus, pages })
+  }
+
+  /// The pages of the frames completed by `packet`. A packet that fails to decode is concealed
+  /// by libopus, keeping the timing of the stream.
+  fn transcode(&mut self, packet: &[u8]) -> Vec<Bytes> {
+    let decoded = self.decoder.decode(packet, &mut self.pcm);
+    if decoded.is_err() {
+      let samples = packet_samples(packet).map_or(FRAME_SAMPLES, |n| n as usize);
+      let _ = self.decoder.conceal(samples, &mut self.pcm);
+    }
+    let skipped = self.skip.min(self.pcm.len());
+    self.pcm.drain(..skipped);
+    self.skip -= skipped;
+
+    let frame = FRAME_SAMPLES * self.channels;
+    let mut pages = Vec::new();
+    while self.pcm.len() >= frame {
+      let encoded = self.encoder.encode(&self.pcm[..frame]);
+      self.pcm.drain(..frame);
+      // the frame still counts towards the granule position when it could not be encoded
+      self.granule_position += FRAME_SAMPLES as u64;
+      if let Ok(packet) = encoded {
+        pages.extend(self.writer.push(&packet, self.granule_position));
+      }
+    }
+    pages
+  }
+}
+
+/// The `OpusTags` vendor string, naming the libopus build the variants are encoded with.
+fn vendor() -> String {
+  // SAFETY: libopus returns a static, NUL terminated string.
+  unsafe { CStr::from_ptr(opus_get_version_string()) }.to_string_lossy().into_owned()
+}
+
+/// Maps a negative libopus return value to an error.
+fn check(call: &'static str, ret: i32) -> Result<usize, TranscodeError> {
+  usize::try_from(ret).map_err(|_| {
+    // SAFETY: libopus returns a static, NUL terminated string for any error code.
+    let message = unsafe { CStr::from_ptr(opus_strerror(ret)) };
+    TranscodeError::Opus(call, message.to_string_lossy().into_owned())
+  })
+}
+
+/// A buffer length as libopus takes it, the lengths used here being far below `i32::MAX`.
+fn c_int(len: usize) -> i32 {
+  i32::try_from(len).unwrap_or(i32::MAX)
+}
+
+/// A libopus decoder, at 48 kHz.
+struct Decoder {
+  state: NonNull<OpusDecoder>,
+  channels: usize,
+}
+
+// SAFETY: the decoder state is plain memory owned by this value, libopus keeps no reference to
+// it and it is not tied to a thread.
+unsafe impl Send for Decoder {}
+
+impl Decoder {
+  fn new(channels: u8) -> Result<Self, TranscodeError> {
+    let mut error = 0;
+    // SAFETY: `error` outlives the call, and a non-null state is owned until dropped.
+    let state = unsafe { opus_decoder_create(SAMPLE_RATE.cast_signed(), i32::from(channels), &raw mut error) };
+    check("opus_decoder_create", error)?;
+    let state = NonNull::new(state).ok_or_else(|| TranscodeError::Opus("opus_decoder_create", "no memory".into()))?;
+    Ok(Self { state, channels: usize::from(channels) })
+  }
+
+  /// Appends the interleaved samples of `packet` to `pcm`.
+  fn decode(&mut self, packet: &[u8], pcm: &mut Vec<i16>) -> Result<(), TranscodeError> {
+    let len = i32::try_from(packet.len()).map_err(|_| TranscodeError::Opus("opus_decode", "packet too large".into()))?;
+    self.decode_into(packet.as_ptr(), len, MAX_PACKET_SAMPLES, pcm)
+  }
+
+  /// Appends `samples` of packet loss concealment to `pcm`.
+  fn conceal(&mut self, samples: usize, pcm: &mut Vec<i16>) -> Result<(), TranscodeError> {
+    self.decode_into(std::ptr::null(), 0, samples.min(MAX_PACKET_SAMPLES), pcm)
+  }
+
+  fn decode_into(&mut self, data: *const u8, len: i32, samples: usize, pcm: &mut Vec<i16>) -> Result<(), TranscodeError> {
+    let start = pcm.len();
+    pcm.resize(start + samples * self.channels, 0);
+    // SAFETY: `data` holds `len` bytes, or is null for concealment, and `pcm` has room for
+    // `samples` samples per channel past `start`.
+    let decoded = unsafe {
+      opus_decode(self.state.as_ptr(), data, len, pcm[start..].as_mut_ptr(), c_int(samples), 0)
+    };
+    let decoded = check("opus_decode", decoded);
+    pcm.truncate(start + decoded.as_ref().map_or(0, |n| n * self.channels));
+    decoded.map(|_| ())
+  }
+}
+
+impl Drop for Decoder {
+  fn drop(&mut self) {
+    // SAFETY: the state was created by `opus_decoder_create` and is not used after this.
+    unsafe { opus_decoder_destroy(self.state.as_ptr()) };
+  }
+}
+
+/// A libopus encoder for music, at 48 kHz.
+struct Encoder {
+  state: NonNull<OpusEncoder>,
+}
+
+// SAFETY: as for `Decoder`.
+unsafe impl Send for Encoder {}
+
+impl Encoder {
+  fn new(channels: u8, bitrate: u32, complexity: u8) -> Result<Self, TranscodeError> {
+    let mut error = 0;
+    // SAFETY: as for `opus_decoder_create`.
+    let state = unsafe {
+      opus_encoder_create(SAMPLE_RATE.cast_signed(), i32::from(channels), OPUS_APPLICATION_AUDIO, &raw mut error)
+    };
+    check("opus_encoder_create", error)?;
+    let state = NonNull::new(state).ok_or_else(|| TranscodeError::Opus("opus_encoder_create", "no memory".into()))?;
+    let encoder = Self { state };
+    let bitrate = i32::try_from(bitrate).unwrap_or(i32::MAX);
+    // SAFETY: both requests take an `i32` argument.
+    unsafe {
+      check("OPUS_SET_BITRATE", opus_encoder_ctl!(encoder.state.as_ptr(), OPUS_SET_BITRATE_REQUEST, bitrate))?;
+      check("OPUS_SET_COMPLEXITY", opus_encoder_ctl!(encoder.state.as_ptr(), OPUS_SET_COMPLEXITY_REQUEST, i32::from(complexity)))?;
+    }
+    Ok(encoder)
+  }
+
+  /// The samples the encoder delays its input by, the pre-skip of its stream.
+  fn lookahead(&self) -> Result<u16, TranscodeError> {
+    let mut lookahead = 0i32;
+    // SAFETY: the request writes an `i32` through the reference.
+    let ret = unsafe { opus_encoder_ctl!(self.state.as_ptr(), OPUS_GET_LOOKAHEAD_REQUEST, &mut lookahead) };
+    check("OPUS_GET_LOOKAHEAD", ret)?;
+    u16::try_from(lookahead).map_err(|_| TranscodeError::Opus("OPUS_GET_LOOKAHEAD", lookahead.to_string()))
+  }
+
+  /// Encodes a frame of [`FRAME_SAMPLES`] interleaved samples per channel.
+  fn encode(&mut self, pcm: &[i16]) -> Result<Vec<u8>, TranscodeError> {
+    let mut packet = vec![0; MAX_PACKET_BYTES];
+    // SAFETY: `pcm` holds a frame for every channel the encoder was created with, and `packet`
+    // has room for `MAX_PACKET_BYTES`.
+    let len = unsafe {
+      opus_encode(
+        self.state.as_ptr(),
+        pcm.as_ptr(),
+        c_int(FRAME_SAMPLES),
+        packet.as_mut_ptr(),
+        c_int(MAX_PACKET_BYTES),
+      )
+    };
+    packet.truncate(check("opus_encode", len)?);
+    Ok(packet)
+  }
+}
+
+impl Drop for Encoder {
+  fn drop(&mut self) {
+    // SAFETY: the state was created by `opus_encoder_create` and is not used after this.
+    unsafe { opus_encoder_destroy(self.state.as_ptr()) };
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+
+  /// Pages of a stereo Opus stream of `frames` 20 ms frames of a 440 Hz tone.
+  #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+  pub fn source(frames: usize) -> Vec<Bytes> {
+    let mut encoder = Encoder::new(2, 128_000, 10).unwrap();
+    let head = OpusHead {
+      version: 1,
+      channels: 2,
+      pre_skip: encoder.lookahead().unwrap(),
+      input_sample_rate: 44_100,
+      output_gain: 0,
+      mapping_family: 0,
+      mapping: None,
+    };
+    let tags = OpusTags { vendor: "source".into(), comments: vec![("TITLE".into(), "Tone".into())], unparsed: Vec::new(), extra: Vec::new() };
+    let mut writer = PageWriter::new(42);
+    let mut pages = writer.push(&head.to_bytes(), 0);
+    pages.extend(writer.push(&tags.to_bytes().unwrap(), 0));
+    for frame in 0..frames {
+      let pcm: Vec<i16> = (0..FRAME_SAMPLES)
+        .map(|i| (((frame * FRAME_SAMPLES + i) as f32 * 440.0 * std::f32::consts::TAU / 48_000.0).sin() * 8000.0) as i16)
+        .flat_map(|s| [s, s])
+        .collect();
+      let packet = encoder.encode(&pcm).unwrap();
+      pages.extend(writer.push(&packet, ((frame + 1) * FRAME_SAMPLES) as u64));
+    }
+    pages
+  }
+
+  #[test]
+  fn re_encodes_with_fresh_headers() {
+    let variant = Variant {
+      mount: Mount::new("/tau.ogg", 3).variant("/tau-low.ogg", 3),
+      bitrate: 24_000,
+      channels: Some(1),
+      complexity: 5,
+    };
+    let mut transcoder = Transcoder::new(&variant);
+    let mut headers = None;
+    let mut pages = Vec::new();
+    for page in source(50) {
+      let output = transcoder.push(&page).unwrap();
+      headers = headers.or(output.headers);
+      pages.extend(output.pages);
+    }
+
+    let headers = headers.unwrap();
+    let head = headers.opus_head().unwrap();
+    assert_eq!((head.channels, head.input_sample_rate), (1, 44_100));
+    assert!(head.pre_skip > 0);
+    let tags = headers.opus_tags().unwrap();
+    assert_eq!(tags.vendor, vendor());
+    assert_eq!(tags.comments, [("TITLE".to_string(), "Tone".to_string())]);
+    assert_eq!(pages[..2], headers.pages);
+
+    // a second of audio, less the source's pre-skip, in 20 ms mono packets
+    let mut reader = PacketReader::default();
+    let packets: Vec<Bytes> = pages[2..].iter().flat_map(|page| reader.push(page)).collect();
+    assert_eq!(packets.len(), 49);
+    assert!(packets.iter().all(|p| packet_samples(p) == Some(960) && p[0] & 0x04 == 0));
+    let last = PageHeader::parse(pages.last().unwrap()).unwrap();
+    assert_eq!((last.granule_position, last.serial), (Some(49 * 960), 42));
+    let bytes: usize = packets.iter().map(Bytes::len).sum();
+    // 60 bytes a packet on average at 24 kb/s
+    assert!(bytes < 49 * 120);
+
+    transcoder.stop();
+    assert!(transcoder.push(&source(1)[2]).unwrap().pages.is_empty());
+  }
+}
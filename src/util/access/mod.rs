@@ -0,0 +1,103 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use ipnet::IpNet;
+use crate::config::{AccessConfig, TauConfigError};
+
+/// CIDR based allow/deny list, checked against the peer address right after `accept()`.
+/// A matching `deny` entry always wins. If the `allow` list is non-empty, only addresses
+/// inside one of its networks are let through.
+#[derive(Debug, Default)]
+pub struct AccessList {
+  allow: Vec<IpNet>,
+  deny: Vec<IpNet>,
+  rejected: AtomicU64,
+}
+
+impl AccessList {
+  /// Parses the `allow` and `deny` entries of an [`AccessConfig`].
+  /// # Errors
+  /// Returns [`TauConfigError::InvalidCidr`] if an entry is neither a CIDR network nor an IP address.
+  pub fn from_config(config: Option<&AccessConfig>) -> Result<Self, TauConfigError> {
+    let Some(config) = config else { return Ok(Self::default()) };
+    Ok(Self {
+      allow: parse_networks(config.allow.as_deref())?,
+      deny: parse_networks(config.deny.as_deref())?,
+      rejected: AtomicU64::new(0),
+    })
+  }
+
+  pub fn is_allowed(&self, ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    if self.deny.iter().any(|net| net.contains(&ip)) {
+      return false;
+    }
+    self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+  }
+
+  /// Checks `ip` against the lists, logging and counting the attempt if it is rejected.
+  pub fn admit(&self, ip: IpAddr, side: &str) -> bool {
+    if self.is_allowed(ip) {
+      return true;
+    }
+    let count = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+    eprintln!("Rejected {side} connection from {ip} (total rejected: {count})");
+    false
+  }
+}
+
+/// Parses a list of CIDR networks, bare IP addresses are treated as single host networks.
+fn parse_networks(entries: Option<&[String]>) -> Result<Vec<IpNet>, TauConfigError> {
+  entries
+    .unwrap_or_default()
+    .iter()
+    .map(|entry| parse_network(entry))
+    .collect()
+}
+
+pub fn parse_network(entry: &str) -> Result<IpNet, TauConfigError> {
+  IpNet::from_str(entry)
+    .or_else(|_| IpAddr::from_str(entry).map(IpNet::from))
+    .map_err(|_| TauConfigError::InvalidCidr(entry.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn list(allow: &[&str], deny: &[&str]) -> AccessList {
+    let config = AccessConfig {
+      allow: Some(allow.iter().map(ToString::to_string).collect()),
+      deny: Some(deny.iter().map(ToString::to_string).collect()),
+    };
+    AccessList::from_config(Some(&config)).unwrap()
+  }
+
+  #[test]
+  fn empty_list_allows_everything() {
+    let access = AccessList::from_config(None).unwrap();
+    assert!(access.is_allowed("203.0.113.7".parse().unwrap()));
+  }
+
+  #[test]
+  fn deny_wins_over_allow() {
+    let access = list(&["10.0.0.0/8"], &["10.1.0.0/16"]);
+    assert!(access.is_allowed("10.2.3.4".parse().unwrap()));
+    assert!(!access.is_allowed("10.1.3.4".parse().unwrap()));
+    assert!(!access.is_allowed("192.168.0.1".parse().unwrap()));
+  }
+
+  #[test]
+  fn bare_addresses_and_mapped_v6() {
+    let access = list(&["203.0.113.7"], &[]);
+    assert!(access.is_allowed("::ffff:203.0.113.7".parse().unwrap()));
+    assert!(!access.admit("203.0.113.8".parse().unwrap(), "test"));
+    assert_eq!(access.rejected.load(Ordering::Relaxed), 1);
+  }
+
+  #[test]
+  fn rejects_garbage() {
+    assert!(parse_network("10.0.0.0/33").is_err());
+    assert!(parse_network("example.com").is_err());
+  }
+}
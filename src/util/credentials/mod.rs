@@ -1,12 +1,304 @@
+use std::sync::LazyLock;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::{SaltString, rand_core::{OsRng, RngCore}};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use toml::value::Datetime;
+use crate::config::{Config, TauConfigError, UserConfig};
+use crate::util::ip::filter_mount_endpoint;
+use crate::util::listener_auth::unix_now;
 
-pub struct Credentials {
+/// Verified in place of a user's hash when the username is unknown, so that a failed login takes
+/// as long whether or not the user exists.
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| hash_password("").unwrap_or_default());
+
+/// The password of a user, either as a PHC / bcrypt hash or as a legacy plaintext entry.
+pub enum Secret {
+  Plain(String),
+  Hash(String),
+}
+
+/// A source user, allowed to stream to `mounts` (or every mount if `None`) until `expires`.
+pub struct User {
   pub username: String,
-  pub password: String,
+  secret: Option<Secret>,
+  token_sha256: Option<[u8; 32]>,
+  mounts: Option<Vec<String>>,
+  expires: Option<i64>,
+}
+
+/// How a source identified itself on the WebSocket handshake.
+pub enum Identity<'a> {
+  Password { username: &'a str, password: &'a str },
+  Bearer(&'a str),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+  Invalid,
+  Expired,
+  MountNotPermitted,
+}
+
+pub struct Credentials {
+  users: Vec<User>,
 }
 
 impl Credentials {
-  pub fn validate(&self, username: &str, password: &str) -> bool {
-    username == self.username && password == self.password 
+  /// Collects the top-level `username` and the `[[users]]` table of the config.
+  /// # Errors
+  /// Fails if no user is configured, a user has no way to authenticate, or a hash, token or
+  /// mount entry is malformed.
+  pub fn from_config(config: &Config) -> Result<Self, TauConfigError> {
+    let mut users = Vec::new();
+    if let Some(username) = &config.username {
+      let secret = secret(config.password.clone(), config.password_hash.clone())?
+        .ok_or_else(|| TauConfigError::MissingField("password_hash".to_string()))?;
+      users.push(User {
+        username: username.clone(),
+        secret: Some(secret),
+        token_sha256: None,
+        mounts: None,
+        expires: None,
+      });
+    }
+    for user in config.users.iter().flatten() {
+      users.push(User::from_config(user)?);
+    }
+    if users.is_empty() {
+      return Err(TauConfigError::MissingField("username".to_string()));
+    }
+    Ok(Self { users })
+  }
+
+  /// Finds the user matching `identity` and checks that it may stream to `mount`.
+  /// # Errors
+  /// Returns why the source was not let in.
+  pub fn authenticate(&self, identity: &Identity, mount: &str) -> Result<&User, AuthError> {
+    let user = match identity {
+      Identity::Password { username, password } => {
+        let user = self.find(|user| user.username.as_bytes().ct_eq(username.as_bytes()).into());
+        let verified = user.map_or_else(|| dummy_verify(password), |user| user.verify_password(password));
+        user.filter(|_| verified)
+      },
+      Identity::Bearer(token) => {
+        let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        self.find(|user| user.token_sha256.is_some_and(|hash| hash.ct_eq(&digest).into()))
+      }
+    }
+    .ok_or(AuthError::Invalid)?;
+
+    if user.expires.is_some_and(|expires| expires <= unix_now().cast_signed()) {
+      return Err(AuthError::Expired);
+    }
+    if !user.may_stream_to(mount) {
+      return Err(AuthError::MountNotPermitted);
+    }
+    Ok(user)
+  }
+
+  /// Checks every user, so the time taken does not depend on where a match is.
+  fn find(&self, matches: impl Fn(&User) -> bool) -> Option<&User> {
+    self.users.iter().fold(None, |found, user| if matches(user) { Some(user) } else { found })
+  }
+}
+
+impl User {
+  fn from_config(config: &UserConfig) -> Result<Self, TauConfigError> {
+    let secret = secret(None, config.password_hash.clone())?;
+    let token_sha256 = config.token_sha256.as_deref().map(parse_token_hash).transpose()?;
+    if secret.is_none() && token_sha256.is_none() {
+      return Err(TauConfigError::MissingField(
+        format!("password_hash or token_sha256 for user '{}'", config.username)
+      ));
+    }
+    let mounts = config.mounts.as_ref()
+      .map(|mounts| mounts.iter()
+        .map(|m| filter_mount_endpoint(m)
+          .map_err(|_| TauConfigError::InvalidEndpoint(m.clone())))
+        .collect::<Result<Vec<_>, _>>())
+      .transpose()?;
+    let expires = config.expires.as_ref()
+      .map(|datetime| datetime_to_unix(datetime)
+        .ok_or_else(|| TauConfigError::Input(format!("invalid expiry date: {datetime}"))))
+      .transpose()?;
+
+    Ok(Self { username: config.username.clone(), secret, token_sha256, mounts, expires })
+  }
+
+  fn verify_password(&self, password: &str) -> bool {
+    match &self.secret {
+      Some(Secret::Plain(plain)) => password.as_bytes().ct_eq(plain.as_bytes()).into(),
+      Some(Secret::Hash(hash)) => verify_hash(password, hash),
+      None => dummy_verify(password),
+    }
+  }
+
+  fn may_stream_to(&self, mount: &str) -> bool {
+    self.mounts.as_ref().is_none_or(|mounts| mounts.iter().any(|m| m == mount))
+  }
+}
+
+/// Picks `password_hash` over a plaintext `password`, validating the hash format.
+fn secret(password: Option<String>, password_hash: Option<String>) -> Result<Option<Secret>, TauConfigError> {
+  Ok(match (password_hash, password) {
+    (Some(hash), _) => {
+      validate_hash(&hash)?;
+      Some(Secret::Hash(hash))
+    },
+    (None, Some(password)) => Some(Secret::Plain(password)),
+    (None, None) => None,
+  })
+}
+
+/// Hashes a password with Argon2id, producing a PHC string for `password_hash` in `tower.toml`.
+/// # Errors
+/// Fails if the hasher rejects the input.
+pub fn hash_password(password: &str) -> Result<String, TauConfigError> {
+  let salt = SaltString::generate(&mut OsRng);
+  Argon2::default()
+    .hash_password(password.as_bytes(), &salt)
+    .map(|hash| hash.to_string())
+    .map_err(|e| TauConfigError::InvalidPasswordHash(e.to_string()))
+}
+
+/// Generates a random bearer token, returned together with the `token_sha256` entry for it.
+pub fn generate_token() -> (String, String) {
+  let mut bytes = [0u8; 32];
+  OsRng.fill_bytes(&mut bytes);
+  let token = hex::encode(bytes);
+  let digest = hex::encode(Sha256::digest(token.as_bytes()));
+  (token, digest)
+}
+
+fn parse_token_hash(hash: &str) -> Result<[u8; 32], TauConfigError> {
+  let mut digest = [0u8; 32];
+  hex::decode_to_slice(hash, &mut digest)
+    .map_err(|e| TauConfigError::InvalidPasswordHash(format!("token_sha256: {e}")))?;
+  Ok(digest)
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+  ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix))
+}
+
+pub fn validate_hash(hash: &str) -> Result<(), TauConfigError> {
+  if is_bcrypt(hash) {
+    return hash.parse::<bcrypt::HashParts>()
+      .map(|_| ())
+      .map_err(|e| TauConfigError::InvalidPasswordHash(e.to_string()));
+  }
+  PasswordHash::new(hash)
+    .map(|_| ())
+    .map_err(|e| TauConfigError::InvalidPasswordHash(e.to_string()))
+}
+
+pub fn verify_hash(password: &str, hash: &str) -> bool {
+  if is_bcrypt(hash) {
+    return bcrypt::verify(password, hash).unwrap_or(false);
   }
+  PasswordHash::new(hash)
+    .is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+}
+
+/// Spends the time of a hash verification, and fails.
+pub fn dummy_verify(password: &str) -> bool {
+  verify_hash(password, &DUMMY_HASH);
+  false
+}
+
+/// Converts a TOML date or datetime to unix seconds. A bare date means midnight UTC, and a
+/// datetime without offset is read as UTC.
+fn datetime_to_unix(datetime: &Datetime) -> Option<i64> {
+  let date = datetime.date?;
+  let (year, month, day) = (i64::from(date.year), i64::from(date.month), i64::from(date.day));
+  // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+  let y = if month <= 2 { year - 1 } else { year };
+  let era = y.div_euclid(400);
+  let yoe = y - era * 400;
+  let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  let days = era * 146_097 + doe - 719_468;
+
+  let seconds = datetime.time.map_or(0, |t| {
+    i64::from(t.hour) * 3600 + i64::from(t.minute) * 60 + i64::from(t.second)
+  });
+  let offset = match datetime.offset {
+    Some(toml::value::Offset::Custom { minutes }) => i64::from(minutes) * 60,
+    _ => 0,
+  };
+  Some(days * 86_400 + seconds - offset)
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn user(username: &str, hash: Option<String>, token: Option<String>, mounts: Option<Vec<String>>) -> UserConfig {
+    UserConfig {
+      username: username.into(),
+      password_hash: hash,
+      token_sha256: token,
+      mounts,
+      expires: None,
+    }
+  }
+
+  fn credentials(users: &[UserConfig]) -> Credentials {
+    let users = users.iter().map(User::from_config).collect::<Result<Vec<_>, _>>().unwrap();
+    Credentials { users }
+  }
+
+  #[test]
+  fn argon2_roundtrip() {
+    let hash = hash_password("emanresu").unwrap();
+    let creds = credentials(&[user("username", Some(hash), None, None)]);
+    let login = |u, p| creds.authenticate(&Identity::Password { username: u, password: p }, "/tau.ogg").map(|u| &u.username);
+    assert_eq!(login("username", "emanresu"), Ok(&"username".to_string()));
+    assert_eq!(login("username", "wrong"), Err(AuthError::Invalid));
+    assert_eq!(login("other", "emanresu"), Err(AuthError::Invalid));
+  }
+
+  #[test]
+  fn hash_takes_precedence_over_plaintext() {
+    let hash = hash_password("hashed").unwrap();
+    let secret = secret(Some("plain".into()), Some(hash)).unwrap();
+    let creds = Credentials {
+      users: vec![User { username: "u".into(), secret, token_sha256: None, mounts: None, expires: None }],
+    };
+    let login = |p| creds.authenticate(&Identity::Password { username: "u", password: p }, "/tau.ogg").err();
+    assert_eq!(login("hashed"), None);
+    assert_eq!(login("plain"), Some(AuthError::Invalid));
+  }
+
+  #[test]
+  fn bearer_tokens_and_mount_permissions() {
+    let (token, digest) = generate_token();
+    let creds = credentials(&[user("host", None, Some(digest), Some(vec!["late.ogg".into()]))]);
+    assert!(creds.authenticate(&Identity::Bearer(&token), "/late.ogg").is_ok());
+    assert_eq!(creds.authenticate(&Identity::Bearer(&token), "/tau.ogg").err(), Some(AuthError::MountNotPermitted));
+    assert_eq!(creds.authenticate(&Identity::Bearer("nope"), "/late.ogg").err(), Some(AuthError::Invalid));
+  }
+
+  #[test]
+  fn expired_users_are_rejected() {
+    let (token, digest) = generate_token();
+    let mut expired = user("old", None, Some(digest), None);
+    expired.expires = Some("2001-01-01".parse().unwrap());
+    let creds = credentials(&[expired]);
+    assert_eq!(creds.authenticate(&Identity::Bearer(&token), "/tau.ogg").err(), Some(AuthError::Expired));
+  }
+
+  #[test]
+  fn datetime_conversion() {
+    assert_eq!(datetime_to_unix(&"1970-01-01".parse().unwrap()), Some(0));
+    assert_eq!(datetime_to_unix(&"2000-03-01T00:00:00Z".parse().unwrap()), Some(951_868_800));
+    assert_eq!(datetime_to_unix(&"2000-03-01T01:00:00+01:00".parse().unwrap()), Some(951_868_800));
+  }
+
+  #[test]
+  fn bad_hash_is_rejected() {
+    assert!(User::from_config(&user("u", Some("not a hash".into()), None, None)).is_err());
+    assert!(User::from_config(&user("u", None, None, None)).is_err());
+  }
+}
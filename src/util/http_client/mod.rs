@@ -0,0 +1,12 @@
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper_tls::HttpsConnector;
+use hyper_util::client::legacy::{Client, connect::HttpConnector};
+use hyper_util::rt::TokioExecutor;
+
+/// Outgoing HTTP(S) client, used for webhooks and listener authentication callbacks.
+pub type HttpsClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+pub fn https_client() -> HttpsClient {
+  Client::builder(TokioExecutor::new()).build(HttpsConnector::new())
+}
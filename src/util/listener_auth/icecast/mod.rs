@@ -0,0 +1,204 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use http_body_util::Full;
+use hyper::{HeaderMap, Request, Uri, body::Bytes, header::{CONTENT_TYPE, HOST, USER_AGENT}};
+use tokio::time::Instant;
+use crate::config::TauConfigError;
+use crate::util::http_client::{HttpsClient, https_client};
+use super::{Denied, basic_credentials, query_param};
+
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Listener authentication through Icecast's `url` authenticator protocol: a form encoded
+/// `listener_add` POST per connecting listener, admitted only if the response carries an
+/// `icecast-auth-user: 1` header, and a `listener_remove` POST once the listener is gone.
+pub struct UrlAuth {
+  client: HttpsClient,
+  listener_add: Uri,
+  listener_remove: Option<Uri>,
+  next_client_id: AtomicU64,
+}
+
+/// Held for as long as an admitted listener is connected, and notifies `listener_remove` on drop.
+pub struct UrlSession {
+  client: HttpsClient,
+  listener_remove: Option<Uri>,
+  fields: Vec<(&'static str, String)>,
+  started: Instant,
+}
+
+impl UrlAuth {
+  /// # Errors
+  /// Fails if either URL cannot be parsed.
+  pub fn new(listener_add: &str, listener_remove: Option<&str>) -> Result<Self, TauConfigError> {
+    let parse = |url: &str| url.parse::<Uri>()
+      .map_err(|e| TauConfigError::InvalidEndpoint(format!("{url}: {e}")));
+    Ok(Self {
+      client: https_client(),
+      listener_add: parse(listener_add)?,
+      listener_remove: listener_remove.map(parse).transpose()?,
+      next_client_id: AtomicU64::new(1),
+    })
+  }
+
+  /// Asks the `listener_add` URL whether the listener may connect. Failing to reach it denies
+  /// the listener, rather than opening the mount to everyone.
+  /// # Errors
+  /// Returns [`Denied::Forbidden`] unless the listener was admitted.
+  pub async fn add(&self, mount: &str, uri: &Uri, headers: &HeaderMap, peer: SocketAddr) -> Result<UrlSession, Denied> {
+    let (user, pass) = credentials(uri, headers).unwrap_or_default();
+    let host = headers.get(HOST).and_then(|h| h.to_str().ok()).unwrap_or_default();
+    let (server, port) = host.rsplit_once(':').unwrap_or((host, ""));
+    let mount = uri.query().map_or_else(|| mount.to_string(), |query| format!("{mount}?{query}"));
+
+    let fields = vec![
+      ("server", server.to_string()),
+      ("port", port.to_string()),
+      ("client", self.next_client_id.fetch_add(1, Ordering::Relaxed).to_string()),
+      ("mount", mount),
+      ("user", user),
+      ("pass", pass),
+      ("ip", peer.ip().to_string()),
+      ("agent", headers.get(USER_AGENT).and_then(|a| a.to_str().ok()).unwrap_or_default().to_string()),
+    ];
+
+    let headers = post(&self.client, &self.listener_add, "listener_add", &fields).await
+      .ok_or(Denied::Forbidden)?;
+    if headers.get("icecast-auth-user").is_none_or(|v| v.as_bytes() != b"1") {
+      if let Some(message) = headers.get("icecast-auth-message").and_then(|m| m.to_str().ok()) {
+        eprintln!("Listener {peer} rejected: {message}");
+      }
+      return Err(Denied::Forbidden);
+    }
+
+    Ok(UrlSession {
+      client: self.client.clone(),
+      listener_remove: self.listener_remove.clone(),
+      fields,
+      started: Instant::now(),
+    })
+  }
+}
+
+/// The credentials forwarded to `listener_add`: HTTP Basic, or the `user` and `pass` query
+/// parameters.
+pub fn credentials(uri: &Uri, headers: &HeaderMap) -> Option<(String, String)> {
+  basic_credentials(headers).or_else(|| Some((
+    query_param(uri.query(), "user")?.to_string(),
+    query_param(uri.query(), "pass")?.to_string(),
+  )))
+}
+
+impl Drop for UrlSession {
+  fn drop(&mut self) {
+    let Some(url) = self.listener_remove.take() else { return };
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else { return };
+    let client = self.client.clone();
+    let mut fields = std::mem::take(&mut self.fields);
+    fields.push(("duration", self.started.elapsed().as_secs().to_string()));
+    runtime.spawn(async move {
+      post(&client, &url, "listener_remove", &fields).await;
+    });
+  }
+}
+
+/// Posts the form encoded `fields` with `action`, returning the response headers on a 2xx.
+async fn post(client: &HttpsClient, url: &Uri, action: &str, fields: &[(&'static str, String)]) -> Option<HeaderMap> {
+  let body = form_urlencoded::Serializer::new(String::new())
+    .append_pair("action", action)
+    .extend_pairs(fields.iter().map(|(k, v)| (*k, v.as_str())))
+    .finish();
+  let req = Request::post(url.clone())
+    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+    .body(Full::new(Bytes::from(body)))
+    .ok()?;
+
+  match tokio::time::timeout(CALLBACK_TIMEOUT, client.request(req)).await {
+    Ok(Ok(res)) if res.status().is_success() => Some(res.headers().clone()),
+    Ok(Ok(res)) => {
+      eprintln!("{action} callback {url} answered {}", res.status());
+      None
+    },
+    Ok(Err(e)) => {
+      eprintln!("{action} callback {url} failed: {e}");
+      None
+    },
+    Err(_) => {
+      eprintln!("{action} callback {url} timed out");
+      None
+    },
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use std::convert::Infallible;
+  use http_body_util::BodyExt;
+  use hyper::{Response, body::Incoming, server::conn::http1, service::service_fn};
+  use hyper_util::rt::TokioIo;
+  use tokio::net::TcpListener;
+  use tokio::sync::mpsc;
+
+  /// Admits `user=member`, and reports every form body it receives.
+  pub async fn stub_server() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+      loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let tx = tx.clone();
+        tokio::spawn(http1::Builder::new().serve_connection(
+          TokioIo::new(stream),
+          service_fn(move |req: Request<Incoming>| {
+            let tx = tx.clone();
+            async move {
+              let body = req.into_body().collect().await.unwrap().to_bytes();
+              let body = String::from_utf8(body.to_vec()).unwrap();
+              let admit = if body.contains("user=member") { "1" } else { "0" };
+              tx.send(body).unwrap();
+              Ok::<_, Infallible>(Response::builder()
+                .header("icecast-auth-user", admit)
+                .body(Full::new(Bytes::new()))
+                .unwrap())
+            }
+          }),
+        ));
+      }
+    });
+    (addr, rx)
+  }
+
+  #[tokio::test]
+  async fn admits_and_removes_listeners() {
+    let (addr, mut rx) = stub_server().await;
+    let auth = UrlAuth::new(&format!("http://{addr}/add"), Some(&format!("http://{addr}/remove"))).unwrap();
+    let peer: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(USER_AGENT, "player/1.0".parse().unwrap());
+
+    let uri: Uri = "/tau.ogg?user=member&pass=secret".parse().unwrap();
+    let session = auth.add("/tau.ogg", &uri, &headers, peer).await.unwrap();
+    let add = rx.recv().await.unwrap();
+    assert!(add.starts_with("action=listener_add"));
+    assert!(add.contains("mount=%2Ftau.ogg%3Fuser%3Dmember%26pass%3Dsecret"));
+    assert!(add.contains("ip=203.0.113.7") && add.contains("agent=player%2F1.0"));
+
+    drop(session);
+    let remove = rx.recv().await.unwrap();
+    assert!(remove.starts_with("action=listener_remove") && remove.contains("duration=0"));
+
+    let uri: Uri = "/tau.ogg?user=guest&pass=secret".parse().unwrap();
+    assert!(matches!(auth.add("/tau.ogg", &uri, &headers, peer).await, Err(Denied::Forbidden)));
+  }
+
+  #[tokio::test]
+  async fn unreachable_callback_denies() {
+    let auth = UrlAuth::new("http://127.0.0.1:1/add", None).unwrap();
+    let uri: Uri = "/tau.ogg".parse().unwrap();
+    let peer: SocketAddr = "203.0.113.7:5000".parse().unwrap();
+    assert!(matches!(auth.add("/tau.ogg", &uri, &HeaderMap::new(), peer).await, Err(Denied::Forbidden)));
+  }
+}
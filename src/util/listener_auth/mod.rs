@@ -0,0 +1,353 @@
+mod icecast;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{LazyLock, Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use hmac::{Hmac, Mac};
+use hyper::{HeaderMap, Uri, header::AUTHORIZATION};
+use sha2::Sha256;
+use tokio::sync::Semaphore;
+use crate::config::{ListenerAuthConfig, TauConfigError};
+use crate::util::credentials::{dummy_verify, validate_hash, verify_hash};
+use crate::util::ip::filter_mount_endpoint;
+use icecast::UrlAuth;
+pub use icecast::UrlSession;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs the sessions handed out in playlists and manifests. It lives as long as the process,
+/// so that a reload of the configuration does not cut players off.
+static SESSION_KEY: LazyLock<[u8; 32]> = LazyLock::new(|| {
+  let mut key = [0; 32];
+  OsRng.fill_bytes(&mut key);
+  key
+});
+
+/// Bounds the Basic password verifications running at once, each taking a blocking thread.
+static VERIFICATIONS: Semaphore = Semaphore::const_new(4);
+
+/// Seconds a session stays valid. Players get a fresh one with each playlist or manifest they
+/// reload with their credentials.
+const SESSION_LIFETIME: u64 = 600;
+
+/// A player of HLS or DASH admitted through `url` authentication: the mount, its address, and
+/// the query string and `Authorization` header its credentials came in.
+type UrlClient = (String, IpAddr, String, String);
+
+enum AuthMode {
+  /// HTTP Basic, with `(username, password_hash)` pairs.
+  Basic(Vec<(String, String)>),
+  /// HMAC-SHA256 signed, expiring URLs.
+  Signed(Vec<u8>),
+  /// Icecast `url` authenticator callbacks.
+  Url(Box<UrlAuth>),
+}
+
+/// Why a listener was not let in.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Denied {
+  /// No credentials given, answered with a `WWW-Authenticate` challenge.
+  Unauthorized,
+  Forbidden,
+}
+
+/// Listener authentication per mount. Mounts without an entry are public.
+#[derive(Default)]
+pub struct ListenerAuth {
+  mounts: HashMap<String, AuthMode>,
+  /// The [`UrlSession`] of each HLS or DASH player, held until the last session handed to it
+  /// expires, so that `listener_add` is called once per player rather than per playlist reload.
+  url_sessions: Mutex<HashMap<UrlClient, (u64, UrlSession)>>,
+}
+
+impl ListenerAuth {
+  /// # Errors
+  /// Fails on malformed mount names or password hashes.
+  pub fn from_config<'a>(
+    config: impl IntoIterator<Item = (&'a String, &'a ListenerAuthConfig)>
+  ) -> Result<Self, TauConfigError> {
+    let mut mounts = HashMap::new();
+    for (mount, auth) in config {
+      let mount = filter_mount_endpoint(mount)
+        .map_err(|_| TauConfigError::InvalidEndpoint(mount.clone()))?;
+      let mode = match auth {
+        ListenerAuthConfig::Basic { users } => {
+          for user in users {
+            validate_hash(&user.password_hash)?;
+          }
+          AuthMode::Basic(users.iter().map(|u| (u.username.clone(), u.password_hash.clone())).collect())
+        },
+        ListenerAuthConfig::Signed { secret } => AuthMode::Signed(secret.as_bytes().to_vec()),
+        ListenerAuthConfig::Url { listener_add, listener_remove } => {
+          AuthMode::Url(Box::new(UrlAuth::new(listener_add, listener_remove.as_deref())?))
+        },
+      };
+      mounts.insert(mount, mode);
+    }
+    Ok(Self { mounts, url_sessions: Mutex::default() })
+  }
+
+  /// Checks a request for `mount`. A listener admitted through `url` authentication gets a
+  /// [`UrlSession`], to be held until it disconnects.
+  /// # Errors
+  /// Returns [`Denied`] if the mount is protected and the request does not pass.
+  pub async fn check(
+    &self,
+    mount: &str,
+    uri: &Uri,
+    headers: &HeaderMap,
+    peer: SocketAddr
+  ) -> Result<Option<UrlSession>, Denied> {
+    match self.mounts.get(mount) {
+      None => Ok(None),
+      Some(AuthMode::Basic(users)) => {
+        let (username, password) = basic_credentials(headers).ok_or(Denied::Unauthorized)?;
+        let hash = users.iter().find(|(u, _)| *u == username).map(|(_, hash)| hash.clone());
+        let _permit = VERIFICATIONS.acquire().await.map_err(|_| Denied::Forbidden)?;
+        // hashing is slow by design, and is kept off the runtime workers; an unknown user takes
+        // as long, so that the timing does not tell which users exist
+        let verified = tokio::task::spawn_blocking(move || {
+          hash.map_or_else(|| dummy_verify(&password), |hash| verify_hash(&password, &hash))
+        });
+        // the browser asks again for wrong credentials
+        verified.await.is_ok_and(|verified| verified).then_some(None).ok_or(Denied::Unauthorized)
+      },
+      Some(AuthMode::Signed(secret)) => {
+        let query = uri.query();
+        let expires = query_param(query, "expires").and_then(|e| e.parse::<u64>().ok());
+        let sig = query_param(query, "sig").and_then(|s| hex::decode(s).ok());
+        match (expires, sig) {
+          (Some(expires), Some(sig)) if expires > unix_now() => {
+            mac(secret, mount, expires).verify_slice(&sig).map(|()| None).map_err(|_| Denied::Forbidden)
+          },
+          _ => Err(Denied::Forbidden),
+        }
+      },
+      Some(AuthMode::Url(auth)) => auth.add(mount, uri, headers, peer).await.map(Some),
+    }
+  }
+
+  /// Checks a request that does not make a listener, such as `HEAD`. `url` authentication only
+  /// requires credentials to be given, as its backend would count a listener joining and leaving.
+  /// # Errors
+  /// Returns [`Denied`] if the mount is protected and the request does not pass.
+  pub async fn check_without_listener(
+    &self,
+    mount: &str,
+    uri: &Uri,
+    headers: &HeaderMap,
+    peer: SocketAddr
+  ) -> Result<(), Denied> {
+    if let Some(AuthMode::Url(_)) = self.mounts.get(mount) {
+      return icecast::credentials(uri, headers).map(|_| ()).ok_or(Denied::Forbidden);
+    }
+    self.check(mount, uri, headers, peer).await.map(|_| ())
+  }
+
+  /// Checks a request for a playlist or manifest of `mount`, returning the query string given to
+  /// the init and media segments it lists. Segments are fetched every few seconds and are not
+  /// worth a password verification or a `url` callback each, so they carry a session instead.
+  /// A player admitted through `url` authentication is only reported to `listener_add` again once
+  /// its sessions have expired.
+  /// # Errors
+  /// Returns [`Denied`] if the mount is protected and the request does not pass.
+  pub async fn start_session(
+    &self,
+    mount: &str,
+    uri: &Uri,
+    headers: &HeaderMap,
+    peer: SocketAddr
+  ) -> Result<Option<String>, Denied> {
+    let Some(AuthMode::Url(auth)) = self.mounts.get(mount) else {
+      return self.check(mount, uri, headers, peer).await.map(|_| self.session_query(mount));
+    };
+    let authorization = headers.get(AUTHORIZATION).map(|a| String::from_utf8_lossy(a.as_bytes()).into_owned());
+    let client = (
+      mount.to_string(),
+      peer.ip(),
+      uri.query().unwrap_or_default().to_string(),
+      authorization.unwrap_or_default(),
+    );
+    let now = unix_now();
+    let held = {
+      let mut sessions = self.url_sessions();
+      // expired sessions are dropped, which reports their players to `listener_remove`
+      sessions.retain(|_, (expires, _)| *expires > now);
+      sessions.remove(&client)
+    };
+    let url_session = match held {
+      Some((_, url_session)) => url_session,
+      None => auth.add(mount, uri, headers, peer).await?,
+    };
+    self.url_sessions().insert(client, (now + SESSION_LIFETIME, url_session));
+    Ok(self.session_query(mount))
+  }
+
+  /// The sessions hold no invariants a panicking holder could break, so poisoning is ignored.
+  fn url_sessions(&self) -> MutexGuard<'_, HashMap<UrlClient, (u64, UrlSession)>> {
+    self.url_sessions.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  fn session_query(&self, mount: &str) -> Option<String> {
+    self.mounts.contains_key(mount).then(|| {
+      let expires = unix_now() + SESSION_LIFETIME;
+      let sig = hex::encode(mac(&*SESSION_KEY, mount, expires).finalize().into_bytes());
+      format!("session={expires}.{sig}")
+    })
+  }
+
+  /// Whether `query` carries a session of `mount` that has not expired.
+  pub fn has_session(mount: &str, query: Option<&str>) -> bool {
+    let Some((expires, sig)) = query_param(query, "session").and_then(|s| s.split_once('.')) else {
+      return false;
+    };
+    match (expires.parse::<u64>(), hex::decode(sig)) {
+      (Ok(expires), Ok(sig)) if expires > unix_now() => {
+        mac(&*SESSION_KEY, mount, expires).verify_slice(&sig).is_ok()
+      },
+      _ => false,
+    }
+  }
+}
+
+/// Signs `mount` so it can be played until the unix time `expires`, returning the path and
+/// query string to hand out to a listener.
+pub fn sign_url(secret: &str, mount: &str, expires: u64) -> String {
+  let sig = hex::encode(mac(secret.as_bytes(), mount, expires).finalize().into_bytes());
+  format!("{mount}?expires={expires}&sig={sig}")
+}
+
+fn mac(secret: &[u8], mount: &str, expires: u64) -> HmacSha256 {
+  #[allow(clippy::expect_used)]
+  let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+  mac.update(format!("{mount}:{expires}").as_bytes());
+  mac
+}
+
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+  let encoded = headers.get(AUTHORIZATION)?.to_str().ok()?.strip_prefix("Basic ")?;
+  let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+  let (username, password) = decoded.split_once(':')?;
+  Some((username.to_string(), password.to_string()))
+}
+
+fn query_param<'a>(query: Option<&'a str>, key: &str) -> Option<&'a str> {
+  query?
+    .split('&')
+    .filter_map(|pair| pair.split_once('='))
+    .find(|(k, _)| *k == key)
+    .map(|(_, v)| v)
+}
+
+pub fn unix_now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn signed() -> ListenerAuth {
+    let config = HashMap::from([("tau.ogg".to_string(), ListenerAuthConfig::Signed { secret: "s3cret".into() })]);
+    ListenerAuth::from_config(&config).unwrap()
+  }
+
+  async fn check(auth: &ListenerAuth, url: &str) -> Result<(), Denied> {
+    let uri: Uri = url.parse().unwrap();
+    let peer = "127.0.0.1:5000".parse().unwrap();
+    auth.check(uri.path(), &uri, &HeaderMap::new(), peer).await.map(|_| ())
+  }
+
+  #[tokio::test]
+  async fn signed_urls() {
+    let auth = signed();
+    let url = sign_url("s3cret", "/tau.ogg", unix_now() + 60);
+    assert_eq!(check(&auth, &url).await, Ok(()));
+
+    let tampered = url.replace("expires=", "expires=1");
+    assert_eq!(check(&auth, &tampered).await, Err(Denied::Forbidden));
+
+    let expired = sign_url("s3cret", "/tau.ogg", unix_now() - 1);
+    assert_eq!(check(&auth, &expired).await, Err(Denied::Forbidden));
+
+    let other_key = sign_url("other", "/tau.ogg", unix_now() + 60);
+    assert_eq!(check(&auth, &other_key).await, Err(Denied::Forbidden));
+    assert_eq!(check(&auth, "/public.ogg").await, Ok(()));
+  }
+
+  #[test]
+  fn sessions_are_bound_to_the_mount() {
+    let auth = signed();
+    assert_eq!(auth.session_query("/public.ogg"), None);
+    let query = auth.session_query("/tau.ogg").unwrap();
+    assert!(ListenerAuth::has_session("/tau.ogg", Some(&query)));
+    assert!(ListenerAuth::has_session("/tau.ogg", Some(&format!("a=b&{query}"))));
+    assert!(!ListenerAuth::has_session("/other.ogg", Some(&query)));
+    assert!(!ListenerAuth::has_session("/tau.ogg", Some(&query.replace("session=", "session=1"))));
+    assert!(!ListenerAuth::has_session("/tau.ogg", None));
+  }
+
+  #[tokio::test]
+  async fn basic_auth_asks_again_for_wrong_credentials() {
+    let hash = crate::util::credentials::hash_password("pw").unwrap();
+    let config = HashMap::from([("tau.ogg".to_string(), ListenerAuthConfig::Basic {
+      users: vec![crate::config::ListenerUserConfig { username: "member".into(), password_hash: hash }],
+    })]);
+    let auth = ListenerAuth::from_config(&config).unwrap();
+    let uri: Uri = "/tau.ogg".parse().unwrap();
+    let peer = "127.0.0.1:5000".parse().unwrap();
+    let check = |credentials: &str| {
+      let mut headers = HeaderMap::new();
+      if !credentials.is_empty() {
+        let value = format!("Basic {}", STANDARD.encode(credentials));
+        headers.insert(AUTHORIZATION, value.parse().unwrap());
+      }
+      let auth = &auth;
+      let uri = &uri;
+      async move { auth.check("/tau.ogg", uri, &headers, peer).await.map(|_| ()) }
+    };
+    assert_eq!(check("member:pw").await, Ok(()));
+    assert_eq!(check("member:wrong").await, Err(Denied::Unauthorized));
+    assert_eq!(check("nobody:pw").await, Err(Denied::Unauthorized));
+    assert_eq!(check("").await, Err(Denied::Unauthorized));
+  }
+
+  #[test]
+  fn basic_credentials_are_parsed() {
+    let mut headers = HeaderMap::new();
+    headers.insert(AUTHORIZATION, "Basic bWVtYmVyOnB3OmQ=".parse().unwrap());
+    assert_eq!(basic_credentials(&headers), Some(("member".into(), "pw:d".into())));
+  }
+
+  #[tokio::test]
+  async fn url_players_are_added_once_per_session() {
+    let (addr, mut rx) = icecast::tests::stub_server().await;
+    let config = HashMap::from([("tau.ogg".to_string(), ListenerAuthConfig::Url {
+      listener_add: format!("http://{addr}/add"),
+      listener_remove: Some(format!("http://{addr}/remove")),
+    })]);
+    let auth = ListenerAuth::from_config(&config).unwrap();
+    let uri: Uri = "/tau/playlist.m3u8?user=member&pass=secret".parse().unwrap();
+    let peer = "127.0.0.1:5000".parse().unwrap();
+    for _ in 0..3 {
+      let session = auth.start_session("/tau.ogg", &uri, &HeaderMap::new(), peer).await.unwrap().unwrap();
+      assert!(ListenerAuth::has_session("/tau.ogg", Some(&session)));
+    }
+    assert!(rx.recv().await.unwrap().starts_with("action=listener_add"));
+
+    // once its session expired, the player is removed, and added again with its next playlist
+    auth.url_sessions().values_mut().for_each(|(expires, _)| *expires = 0);
+    auth.start_session("/tau.ogg", &uri, &HeaderMap::new(), peer).await.unwrap();
+    // the removal is posted in the background, alongside the new listener_add
+    let mut actions = [rx.recv().await.unwrap(), rx.recv().await.unwrap()]
+      .map(|body| body.split('&').next().unwrap().to_string());
+    actions.sort();
+    assert_eq!(actions, ["action=listener_add", "action=listener_remove"]);
+    assert!(rx.try_recv().is_err());
+  }
+}
@@ -0,0 +1,131 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Lockout after the first failed attempt, doubled for every consecutive failure.
+const BASE_LOCKOUT: Duration = Duration::from_secs(1);
+const MAX_LOCKOUT: Duration = Duration::from_mins(15);
+/// Failures older than this are forgotten, and the back-off starts over.
+const FORGET_AFTER: Duration = Duration::from_hours(1);
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+  count: u32,
+  locked_until: Instant,
+}
+
+/// Tracks failed source authentication attempts per IP address, and locks an address out
+/// with exponential back-off while it keeps failing.
+#[derive(Debug, Default)]
+pub struct Lockout {
+  failures: Mutex<HashMap<IpAddr, Failures>>,
+  /// Addresses with an attempt in progress.
+  pending: Mutex<HashSet<IpAddr>>,
+}
+
+/// An authentication attempt in progress from an address, until it is recorded or dropped.
+#[derive(Debug)]
+pub struct Attempt {
+  lockout: Arc<Lockout>,
+  ip: IpAddr,
+}
+
+impl Attempt {
+  /// Records a failed attempt and returns how long the address is now locked out for.
+  pub fn failed(self) -> Duration {
+    self.lockout.record_failure(self.ip)
+  }
+
+  pub fn succeeded(self) {
+    self.lockout.record_success(self.ip);
+  }
+}
+
+impl Drop for Attempt {
+  fn drop(&mut self) {
+    if let Ok(mut pending) = self.lockout.pending.lock() {
+      pending.remove(&self.ip);
+    }
+  }
+}
+
+impl Lockout {
+  /// Starts an attempt from `ip`, `None` while another one from it is in progress, so that an
+  /// address cannot try several passwords before its first failure locks it out.
+  pub fn attempt(self: &Arc<Self>, ip: IpAddr) -> Option<Attempt> {
+    let mut pending = self.pending.lock().ok()?;
+    pending.insert(ip).then(|| Attempt { lockout: self.clone(), ip })
+  }
+
+  /// Returns the remaining lockout for `ip`, if it is currently locked out.
+  pub fn locked(&self, ip: IpAddr) -> Option<Duration> {
+    let failures = self.failures.lock().ok()?;
+    failures
+      .get(&ip)
+      .map(|f| f.locked_until.saturating_duration_since(Instant::now()))
+      .filter(|remaining| !remaining.is_zero())
+  }
+
+  /// Records a failed attempt and returns how long `ip` is now locked out for.
+  pub fn record_failure(&self, ip: IpAddr) -> Duration {
+    let Ok(mut failures) = self.failures.lock() else { return BASE_LOCKOUT };
+    let now = Instant::now();
+    failures.retain(|_, f| now.saturating_duration_since(f.locked_until) < FORGET_AFTER);
+
+    let entry = failures.entry(ip).or_insert(Failures { count: 0, locked_until: now });
+    entry.count = entry.count.saturating_add(1);
+    let lockout = lockout_for(entry.count);
+    entry.locked_until = now + lockout;
+    lockout
+  }
+
+  /// Clears the failure history of `ip` after a successful authentication.
+  pub fn record_success(&self, ip: IpAddr) {
+    if let Ok(mut failures) = self.failures.lock() {
+      failures.remove(&ip);
+    }
+  }
+}
+
+fn lockout_for(count: u32) -> Duration {
+  let exponent = count.saturating_sub(1).min(16);
+  BASE_LOCKOUT.saturating_mul(1 << exponent).min(MAX_LOCKOUT)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lockout_doubles_and_caps() {
+    assert_eq!(lockout_for(1), Duration::from_secs(1));
+    assert_eq!(lockout_for(2), Duration::from_secs(2));
+    assert_eq!(lockout_for(5), Duration::from_secs(16));
+    assert_eq!(lockout_for(100), MAX_LOCKOUT);
+  }
+
+  #[test]
+  fn success_clears_lockout() {
+    let lockout = Lockout::default();
+    let ip = IpAddr::from([203, 0, 113, 7]);
+    assert!(lockout.locked(ip).is_none());
+    lockout.record_failure(ip);
+    assert!(lockout.locked(ip).is_some());
+    lockout.record_success(ip);
+    assert!(lockout.locked(ip).is_none());
+  }
+
+  #[test]
+  fn one_attempt_at_a_time_per_address() {
+    let lockout = Arc::new(Lockout::default());
+    let ip = IpAddr::from([203, 0, 113, 7]);
+    let attempt = lockout.attempt(ip).unwrap();
+    assert!(lockout.attempt(ip).is_none());
+    assert!(lockout.attempt(IpAddr::from([203, 0, 113, 8])).is_some());
+    attempt.failed();
+    assert!(lockout.locked(ip).is_some());
+    assert!(lockout.attempt(ip).is_some());
+  }
+}
@@ -1,4 +1,14 @@
+pub mod access;
 pub mod credentials;
+pub mod http_client;
 pub mod ip;
+pub mod listener_auth;
+pub mod lockout;
+pub mod mpeg_frames;
 pub mod ogg_headers;
+pub mod ogg_packets;
+pub mod opus;
+pub mod opus_headers;
+pub mod socket;
+pub mod systemd;
 pub mod ui;
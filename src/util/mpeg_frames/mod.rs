@@ -0,0 +1,163 @@
+//! MP3 and ADTS AAC frames, for sources sending those instead of Ogg pages.
+
+use hyper::body::Bytes;
+use crate::util::ogg_headers::Codec;
+
+/// Bytes kept while looking for a frame before giving up on them.
+const MAX_BUFFER: usize = 64 * 1024;
+
+/// Splits the bytes of an MP3 or ADTS AAC source into whole frames, dropping anything between
+/// them such as ID3 tags, so a listener joining at any chunk starts on a frame sync. The format
+/// is the one of the first frame found.
+#[derive(Default)]
+pub struct FrameReader {
+  buffer: Vec<u8>,
+  codec: Option<Codec>,
+  /// Bytes of an `ID3v2` tag still to come, dropped as they arrive rather than buffered.
+  skip: usize,
+}
+
+impl FrameReader {
+  pub const fn codec(&self) -> Option<Codec> {
+    self.codec
+  }
+
+  /// The frames completed by `data`, empty if none.
+  pub fn push(&mut self, data: &[u8]) -> Bytes {
+    let skipped = self.skip.min(data.len());
+    self.skip -= skipped;
+    self.buffer.extend_from_slice(&data[skipped..]);
+    let mut out = Vec::new();
+    let mut at = 0;
+    while let Some(rest) = self.buffer.get(at..).filter(|r| r.len() >= 10) {
+      if let Some(len) = id3_len(rest) {
+        if rest.len() < len {
+          // a tag can be larger than the buffer, e.g. with cover art
+          self.skip = len - rest.len();
+          at = self.buffer.len();
+          break;
+        }
+        at += len;
+        continue;
+      }
+      let Some((codec, len)) = frame(rest).filter(|(codec, _)| self.codec.is_none_or(|c| c == *codec)) else {
+        at += 1;
+        continue;
+      };
+      if rest.len() < len {
+        break;
+      }
+      self.codec = Some(codec);
+      out.extend_from_slice(&rest[..len]);
+      at += len;
+    }
+    self.buffer.drain(..at);
+    if self.buffer.len() > MAX_BUFFER {
+      self.buffer.clear();
+    }
+    Bytes::from(out)
+  }
+}
+
+/// The codec and length of the frame whose header starts `data`, if it does.
+fn frame(data: &[u8]) -> Option<(Codec, usize)> {
+  let [0xFF, b1, b2, ..] = *data else { return None };
+  if b1 & 0xF6 == 0xF0 {
+    // ADTS: a 13-bit frame length, headers included
+    let len = (usize::from(data[3] & 0x03) << 11) | (usize::from(data[4]) << 3) | (usize::from(data[5]) >> 5);
+    return (len > 7).then_some((Codec::Aac, len));
+  }
+  if b1 & 0xE0 != 0xE0 {
+    return None;
+  }
+  // MPEG 1, 2 or 2.5 audio, layer I, II or III
+  let (version, layer) = ((b1 >> 3) & 0x03, (b1 >> 1) & 0x03);
+  let (bitrate_index, rate_index, padding) = (usize::from(b2 >> 4), usize::from((b2 >> 2) & 0x03), usize::from((b2 >> 1) & 0x01));
+  // reserved version and layer, free and bad bitrates, reserved sample rate
+  if version == 1 || layer == 0 || bitrate_index == 0 || bitrate_index == 15 || rate_index == 3 {
+    return None;
+  }
+  let mpeg1 = version == 3;
+  let kbps: [u16; 14] = match (mpeg1, layer) {
+    (true, 3) => [32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448],
+    (true, 2) => [32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384],
+    (true, _) => [32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320],
+    (false, 3) => [32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256],
+    (false, _) => [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+  };
+  let bitrate = usize::from(kbps[bitrate_index - 1]) * 1000;
+  let rate = [44_100, 48_000, 32_000][rate_index] >> match version { 3 => 0, 2 => 1, _ => 2 };
+  let len = match layer {
+    3 => (12 * bitrate / rate + padding) * 4,
+    // layer III in MPEG 2 and 2.5 has half the samples per frame
+    1 if !mpeg1 => 72 * bitrate / rate + padding,
+    _ => 144 * bitrate / rate + padding,
+  };
+  Some((Codec::Mp3, len))
+}
+
+/// The length of the `ID3v2` tag starting `data`, if it does.
+fn id3_len(data: &[u8]) -> Option<usize> {
+  if !data.starts_with(b"ID3") {
+    return None;
+  }
+  // a syncsafe integer, 7 bits per byte
+  let size = data[6..10].iter().fold(0, |size, b| (size << 7) | usize::from(b & 0x7F));
+  let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+  Some(10 + size + footer)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn splits_mp3_frames() {
+    // MPEG 1 layer III, 128 kbps, 44.1 kHz: 417 bytes, 418 with padding
+    let mut frame = vec![0xFF, 0xFB, 0x90, 0x00];
+    frame.resize(417, 0);
+    let mut padded = vec![0xFF, 0xFB, 0x92, 0x00];
+    padded.resize(418, 0);
+    let mut data = b"ID3\x03\x00\x00\x00\x00\x00\x02xy".to_vec();
+    data.extend_from_slice(&frame);
+    data.extend_from_slice(&padded);
+
+    let mut reader = FrameReader::default();
+    assert!(reader.push(&data[..300]).is_empty());
+    let out = reader.push(&data[300..500]);
+    assert_eq!(reader.codec(), Some(Codec::Mp3));
+    assert_eq!(out, frame);
+    assert_eq!(reader.push(&data[500..]), padded);
+  }
+
+  #[test]
+  fn skips_tags_larger_than_the_buffer() {
+    let mut frame = vec![0xFF, 0xFB, 0x90, 0x00];
+    frame.resize(417, 0);
+    // a 100 KiB tag, with what looks like frames in it
+    let size = 100 * 1024;
+    let mut data = b"ID3\x04\x00\x00".to_vec();
+    data.extend((0..4).rev().map(|i| u8::try_from((size >> (7 * i)) & 0x7F).unwrap()));
+    data.extend(frame.iter().cycle().take(size));
+    data.extend_from_slice(&frame);
+
+    let mut reader = FrameReader::default();
+    let out: Vec<u8> = data.chunks(4096).flat_map(|chunk| reader.push(chunk)).collect();
+    assert_eq!(out, frame);
+    assert!(reader.buffer.is_empty());
+  }
+
+  #[test]
+  fn splits_adts_frames_and_resyncs() {
+    let mut frame = vec![0xFF, 0xF1, 0x50, 0x80, 0x04, 0x1F, 0xFC];
+    frame.resize(32, 0xAA);
+    let mut data = vec![0x00, 0x12];
+    data.extend_from_slice(&frame);
+    data.extend_from_slice(&frame);
+    data.extend_from_slice(&frame[..10]);
+
+    let mut reader = FrameReader::default();
+    assert_eq!(reader.push(&data).len(), 64);
+    assert_eq!(reader.codec(), Some(Codec::Aac));
+  }
+}
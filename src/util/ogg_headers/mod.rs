@@ -1,65 +1,303 @@
 use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use crate::util::ogg_packets::{PacketReader, ogg_crc};
+use crate::util::opus::SAMPLE_RATE;
+use crate::util::opus_headers::{OpusHead, OpusHeaderError, OpusTags};
 
-#[derive(Debug, Clone)]
+/// Most bytes of header pages kept for a stream, enough for comment headers and FLAC metadata
+/// with cover art. A source going past it gets no headers until its next identification header.
+const MAX_HEADER_BYTES: usize = 16 << 20;
+
+/// The codec of a source's stream: that of an Ogg stream, told by its first packet, or MP3 and
+/// ADTS AAC, passed through as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+  Opus,
+  Vorbis,
+  Flac,
+  Mp3,
+  Aac,
+}
+
+impl Codec {
+  /// The codec whose identification header `packet` is.
+  pub fn detect(packet: &[u8]) -> Option<Self> {
+    if packet.starts_with(b"OpusHead") {
+      Some(Self::Opus)
+    } else if packet.starts_with(b"\x01vorbis") {
+      Some(Self::Vorbis)
+    } else if packet.starts_with(b"\x7FFLAC") {
+      Some(Self::Flac)
+    } else {
+      None
+    }
+  }
+
+  pub const fn content_type(self) -> &'static str {
+    match self {
+      Self::Opus => "audio/ogg; codecs=opus",
+      Self::Vorbis => "audio/ogg; codecs=vorbis",
+      Self::Flac => "audio/ogg; codecs=flac",
+      Self::Mp3 => "audio/mpeg",
+      Self::Aac => "audio/aac",
+    }
+  }
+
+  /// The rate granule positions count at, from the identification header `packet`.
+  pub fn sample_rate(self, packet: &[u8]) -> Option<u32> {
+    match self {
+      // whatever the input sample rate
+      Self::Opus => Some(SAMPLE_RATE),
+      Self::Vorbis => Some(u32::from_le_bytes(packet.get(12..16)?.try_into().ok()?)),
+      // 20 bits into the STREAMINFO block, after the mapping header and the `fLaC` signature
+      Self::Flac => {
+        let rate = packet.get(27..30)?;
+        Some((u32::from(rate[0]) << 12) | (u32::from(rate[1]) << 4) | (u32::from(rate[2]) >> 4))
+      },
+      Self::Mp3 | Self::Aac => None,
+    }
+  }
+}
+
+impl std::fmt::Display for Codec {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(match self {
+      Self::Opus => "Opus",
+      Self::Vorbis => "Vorbis",
+      Self::Flac => "FLAC",
+      Self::Mp3 => "MP3",
+      Self::Aac => "AAC",
+    })
+  }
+}
+
+/// The header pages of the source's stream, captured to be sent to every new listener first.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OggHeaders {
-  pub head: Bytes,
-  pub tags: Bytes
+  pub codec: Codec,
+  /// The identification header page, then those holding the comment and any other header. None
+  /// for MP3 and AAC, which listeners can start decoding at any frame.
+  pub pages: Vec<Bytes>,
 }
 
 impl OggHeaders {
-  pub fn new(headers: (Bytes, Bytes)) -> Self {
-    Self {
-      head: headers.0,
-      tags: headers.1,
-    }
+  pub fn opus_head(&self) -> Result<OpusHead, OpusHeaderError> {
+    OpusHead::parse(&first_packet(self.pages.first(), "OpusHead")?)
+  }
+
+  pub fn opus_tags(&self) -> Result<OpusTags, OpusHeaderError> {
+    OpusTags::parse(&first_packet(self.pages.get(1), "OpusTags")?)
+  }
+
+  /// Whether only the comment header differs from `other`.
+  pub fn same_stream(&self, other: &Self) -> bool {
+    self.codec == other.codec && self.pages.first() == other.pages.first()
   }
 }
 
-pub enum OggHeaderType {
-  Head(Bytes),
-  Tags(Bytes),
-  /// Represents a catch-all of other ogg page data or header fragments
-  None
+fn first_packet(page: Option<&Bytes>, header: &'static str) -> Result<Bytes, OpusHeaderError> {
+  let page = page.ok_or(OpusHeaderError::Magic(header))?;
+  PacketReader::default().push(page).into_iter().next().ok_or(OpusHeaderError::Truncated(header))
 }
 
-fn get_header_segment(data: &Bytes) -> Result<usize, ()> {
-  let n_segs = data[26] as usize;
-  let offset = 27 + n_segs;
-  if data.len() < 27 + 8 { 
-    return Err(()) 
+/// Collects the header pages at the start of a source's stream: the identification header, and
+/// as many header packets as its codec has, two for Opus, three for Vorbis and, for FLAC, the
+/// metadata blocks up to the last one.
+#[derive(Default)]
+pub struct HeaderCapture {
+  reader: PacketReader,
+  codec: Option<Codec>,
+  pages: Vec<Bytes>,
+  /// Header packets still to come, `None` for FLAC streams that do not say.
+  remaining: Option<usize>,
+}
+
+impl HeaderCapture {
+  /// The headers, once `page` completes them. Pages before an identification header are skipped.
+  pub fn push(&mut self, page: &Bytes) -> Option<OggHeaders> {
+    let packets = self.reader.push(page);
+    let mut packets = packets.as_slice();
+    // an identification header also starts over a capture the source did not complete
+    if let Some(id) = packets.first()
+      && let Some(codec) = Codec::detect(id) {
+      self.pages.clear();
+      self.codec = Some(codec);
+      self.remaining = match codec {
+        Codec::Opus => Some(1),
+        Codec::Vorbis => Some(2),
+        // the number of header packets following the mapping header, 0 if unknown
+        Codec::Flac => id.get(7..9)
+          .map(|n| usize::from(u16::from_be_bytes([n[0], n[1]])))
+          .filter(|n| *n > 0),
+        // not carried in Ogg
+        Codec::Mp3 | Codec::Aac => return None,
+      };
+      packets = &packets[1..];
+    }
+    let codec = self.codec?;
+    if self.pages.iter().map(Bytes::len).sum::<usize>() + page.len() > MAX_HEADER_BYTES {
+      self.reset();
+      return None;
+    }
+    self.pages.push(page.clone());
+    for packet in packets {
+      // when FLAC does not say how many, the last metadata block has the top bit of its type set
+      let last = self.remaining.as_mut().map_or_else(
+        || packet.first().is_some_and(|b| b & 0x80 != 0),
+        |remaining| {
+          *remaining -= 1;
+          *remaining == 0
+        },
+      );
+      if last {
+        let pages = std::mem::take(&mut self.pages);
+        self.reset();
+        return Some(OggHeaders { codec, pages });
+      }
+    }
+    None
+  }
+
+  /// Starts over, when the source reconnects.
+  pub fn reset(&mut self) {
+    *self = Self::default();
   }
-  Ok(offset)
 }
 
-// pub fn validate_tags(data: &Bytes) -> Result<&Bytes, ()> {
-//   let offset = get_header_segment(data)?;
-//   if &data[offset..offset + 8] == b"OpusTags" {
-//     println!("header tags found");
-//     return Ok(data);
-//   }
-//   Err(())
-// }
-//
-// pub fn validate_header(data: &Bytes) -> Result<&Bytes, ()> {
-//   let offset = get_header_segment(data)?;
-//   if &data[offset..offset + 8] == b"OpusHead" {
-//     println!("header found");
-//     return Ok(data);
-//   }
-//   Err(())
-// }
-
-pub fn parse_ogg_headers(data: &Bytes) -> OggHeaderType {
-  let Ok(offset) = get_header_segment(data) else { return OggHeaderType::None };
-  if &data[offset..offset + 8] == b"OpusHead" {
-    println!("header found");
-    return OggHeaderType::Head(data.clone()); 
-  }
-  if &data[offset..offset + 8] == b"OpusTags" {
-    println!("header tags found");
-    return OggHeaderType::Tags(data.clone()); 
-  }
-  OggHeaderType::None
+/// Replaces comments of an `OpusTags` page, keeping the vendor string and any comment whose field
+/// name is not in `comments`. Fails if `page` does not hold a single, complete and valid
+/// `OpusTags` packet.
+pub fn set_opus_tags(page: &Bytes, comments: &[(String, String)]) -> Result<Bytes, OpusHeaderError> {
+  if !page.starts_with(b"OggS") {
+    return Err(OpusHeaderError::Magic("OpusTags"));
+  }
+  let n_segs = *page.get(26).ok_or(OpusHeaderError::Truncated("OpusTags"))? as usize;
+  let lacing = page.get(27..27 + n_segs).ok_or(OpusHeaderError::Truncated("OpusTags"))?;
+  // continued packet, or one spanning into the next page
+  if page[5] & 0x01 != 0 || lacing.last().is_none_or(|l| *l == 255) {
+    return Err(OpusHeaderError::NotSinglePage);
+  }
+  let start = 27 + n_segs;
+  let len = lacing.iter().map(|l| *l as usize).sum::<usize>();
+  let packet = page.get(start..start + len).ok_or(OpusHeaderError::Truncated("OpusTags"))?;
+
+  let mut tags = OpusTags::parse(packet)?;
+  tags.set(comments);
+  let packet = tags.to_bytes()?;
+
+  let full = packet.len() / 255;
+  if full >= 255 {
+    return Err(OpusHeaderError::NotSinglePage);
+  }
+  let mut out = page[..26].to_vec();
+  out.push(u8::try_from(full + 1).map_err(|_| OpusHeaderError::NotSinglePage)?);
+  out.extend(std::iter::repeat_n(255, full));
+  out.push(u8::try_from(packet.len() % 255).map_err(|_| OpusHeaderError::NotSinglePage)?);
+  out.extend_from_slice(&packet);
+  out[22..26].fill(0);
+  let crc = ogg_crc(&out);
+  out[22..26].copy_from_slice(&crc.to_le_bytes());
+  Ok(Bytes::from(out))
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tags_page(comments: &[&str]) -> Bytes {
+    let comments = comments.iter()
+      .filter_map(|c| c.split_once('='))
+      .map(|(field, value)| (field.to_string(), value.to_string()))
+      .collect();
+    let tags = OpusTags { vendor: "libopus 1.5".into(), comments, unparsed: Vec::new(), extra: Vec::new() };
+    let packet = tags.to_bytes().unwrap();
+    let mut page = b"OggS\0\0".to_vec();
+    page.extend_from_slice(&[0; 8]); // granule position
+    page.extend_from_slice(&7u32.to_le_bytes()); // serial
+    page.extend_from_slice(&1u32.to_le_bytes()); // sequence number
+    page.extend_from_slice(&[0; 4]);
+    page.push(1);
+    page.push(u8::try_from(packet.len()).unwrap());
+    page.extend_from_slice(&packet);
+    Bytes::from(page)
+  }
+
+  #[test]
+  fn crc_matches_reference() {
+    assert_eq!(ogg_crc(b"123456789"), 0x89a1_897f);
+  }
+
+  #[test]
+  fn replaces_matching_comments() {
+    let page = tags_page(&["title=Old", "ARTIST=Someone"]);
+    let new = set_opus_tags(&page, &[("TITLE".into(), "New".into())]).unwrap();
+    let expected = tags_page(&["ARTIST=Someone", "TITLE=New"]);
+    assert_eq!(new[..22], expected[..22]);
+    assert_eq!(new[26..], expected[26..]);
+
+    let mut zeroed = new.to_vec();
+    zeroed[22..26].fill(0);
+    assert_eq!(new[22..26], ogg_crc(&zeroed).to_le_bytes());
+    let packet = PacketReader::default().push(&new).remove(0);
+    assert_eq!(OpusTags::parse(&packet).unwrap().comments.len(), 2);
+  }
+
+  #[test]
+  fn rejects_other_pages() {
+    let mut page = tags_page(&[]).to_vec();
+    page[28..36].copy_from_slice(b"OpusHead");
+    assert_eq!(set_opus_tags(&Bytes::from(page), &[]), Err(OpusHeaderError::Magic("OpusTags")));
+
+    let page = tags_page(&["title=Old"]);
+    for len in [20, 30, page.len() - 1] {
+      assert_eq!(set_opus_tags(&page.slice(..len), &[]), Err(OpusHeaderError::Truncated("OpusTags")), "{len}");
+    }
+  }
+
+  fn page(flags: u8, packets: &[&[u8]]) -> Bytes {
+    let mut page = b"OggS\0".to_vec();
+    page.push(flags);
+    page.extend_from_slice(&[0; 20]);
+    page.push(u8::try_from(packets.len()).unwrap());
+    page.extend(packets.iter().map(|p| u8::try_from(p.len()).unwrap()));
+    for packet in packets {
+      page.extend_from_slice(packet);
+    }
+    Bytes::from(page)
+  }
 
+  #[test]
+  fn captures_headers_by_codec() {
+    let mut capture = HeaderCapture::default();
+    // audio before the identification header is skipped
+    assert!(capture.push(&page(0, &[b"audio"])).is_none());
+
+    let id = b"\x01vorbis\0\0\0\0\x02\x44\xac\0\0";
+    let vorbis = [page(0x02, &[id]), page(0, &[b"\x03vorbis", b"\x05vorbis"])];
+    assert!(capture.push(&vorbis[0]).is_none());
+    let headers = capture.push(&vorbis[1]).unwrap();
+    assert_eq!(headers, OggHeaders { codec: Codec::Vorbis, pages: vorbis.to_vec() });
+    assert_eq!(Codec::Vorbis.sample_rate(id), Some(44_100));
+    assert!(capture.push(&page(0, &[b"audio"])).is_none());
+
+    // FLAC with an unknown number of header packets: up to the last metadata block
+    let mut mapping = b"\x7FFLAC\x01\x00\x00\x00fLaC\x00\x00\x00\x22".to_vec();
+    mapping.extend_from_slice(&[0x10, 0, 0x10, 0, 0, 0, 0, 0, 0, 0, 0x0b, 0xb8, 0x01, 0xf0]);
+    mapping.resize(51, 0);
+    let flac = [page(0x02, &[&mapping]), page(0, &[b"\x04comments"]), page(0, &[b"\x81padding"])];
+    assert!(capture.push(&flac[0]).is_none());
+    assert!(capture.push(&flac[1]).is_none());
+    assert_eq!(capture.push(&flac[2]).unwrap().pages.len(), 3);
+    assert_eq!(Codec::Flac.sample_rate(&mapping), Some(48_000));
+
+    // metadata blocks that never end are not kept past the limit
+    let large = page(0, &[&[0x04; 254][..]; 250]);
+    assert!(capture.push(&flac[0]).is_none());
+    for _ in 0..=MAX_HEADER_BYTES / large.len() {
+      assert!(capture.push(&large).is_none());
+    }
+    assert!(capture.codec.is_none() && capture.pages.is_empty());
+    assert!(capture.push(&flac[2]).is_none());
+  }
+}
@@ -0,0 +1,238 @@
+use hyper::body::Bytes;
+
+const CONTINUED: u8 = 0x01;
+const BEGINNING_OF_STREAM: u8 = 0x02;
+/// Lacing values of a page, each for up to 255 bytes.
+const MAX_SEGMENTS: usize = 255;
+/// Largest packet joined across pages, well above the comment headers with cover art. A longer
+/// packet is dropped rather than buffered without end.
+const MAX_PACKET: usize = 16 << 20;
+
+/// Splits the Ogg pages of a single logical stream back into packets, joining packets that span
+/// several pages.
+#[derive(Default)]
+pub struct PacketReader {
+  /// The start of a packet continued on the next page.
+  partial: Option<Vec<u8>>,
+  /// Whether the current packet is dropped because its start was missed.
+  skipping: bool,
+}
+
+impl PacketReader {
+  /// The packets completed by `page`. A page that is not a valid Ogg page is skipped, as is the
+  /// tail of a packet whose start was missed, e.g. after joining mid-stream.
+  pub fn push(&mut self, page: &Bytes) -> Vec<Bytes> {
+    let mut packets = Vec::new();
+    let Some((flags, lacing, mut offset)) = parse_page(page) else {
+      self.reset();
+      return packets;
+    };
+    if flags & CONTINUED == 0 || flags & BEGINNING_OF_STREAM != 0 {
+      self.reset();
+    } else if self.partial.is_none() {
+      self.skipping = true;
+    }
+    let mut start = offset;
+    for len in lacing {
+      offset += usize::from(*len);
+      if *len == 255 {
+        continue;
+      }
+      if self.skipping {
+        self.skipping = false;
+      } else if let Some(mut partial) = self.partial.take() {
+        partial.extend_from_slice(&page[start..offset]);
+        packets.push(Bytes::from(partial));
+      } else {
+        packets.push(page.slice(start..offset));
+      }
+      start = offset;
+    }
+    if lacing.last() == Some(&255) && !self.skipping {
+      let partial = self.partial.get_or_insert_default();
+      if partial.len() + (offset - start) > MAX_PACKET {
+        self.partial = None;
+        self.skipping = true;
+      } else {
+        partial.extend_from_slice(&page[start..offset]);
+      }
+    }
+    packets
+  }
+
+  /// Forgets a partial packet, when pages were lost.
+  pub fn reset(&mut self) {
+    self.partial = None;
+    self.skipping = false;
+  }
+}
+
+/// The fields of an Ogg page header that place the page in its logical stream.
+pub struct PageHeader {
+  /// `None` when no packet ends on the page, the granule position -1.
+  pub granule_position: Option<u64>,
+  pub serial: u32,
+  pub sequence: u32,
+}
+
+impl PageHeader {
+  pub fn parse(page: &[u8]) -> Option<Self> {
+    parse_page(page)?;
+    let granule = i64::from_le_bytes(page[6..14].try_into().ok()?);
+    Some(Self {
+      granule_position: u64::try_from(granule).ok(),
+      serial: u32::from_le_bytes(page[14..18].try_into().ok()?),
+      sequence: u32::from_le_bytes(page[18..22].try_into().ok()?),
+    })
+  }
+}
+
+/// Writes the packets of a single logical stream as Ogg pages, a page per packet, or several
+/// for a packet of more than 255 lacing values.
+pub struct PageWriter {
+  serial: u32,
+  sequence: u32,
+}
+
+impl PageWriter {
+  pub const fn new(serial: u32) -> Self {
+    Self { serial, sequence: 0 }
+  }
+
+  /// The pages holding `packet`, the last one ending at `granule_position`. The first page
+  /// written begins the stream.
+  pub fn push(&mut self, packet: &[u8], granule_position: u64) -> Vec<Bytes> {
+    let mut lacing = vec![255; packet.len() / 255];
+    lacing.push(u8::try_from(packet.len() % 255).unwrap_or_default());
+    let mut pages = Vec::new();
+    let mut data = packet;
+    let mut segments = lacing.chunks(MAX_SEGMENTS).peekable();
+    while let Some(lacing) = segments.next() {
+      let mut flags = if pages.is_empty() { 0 } else { CONTINUED };
+      if self.sequence == 0 {
+        flags |= BEGINNING_OF_STREAM;
+      }
+      // -1 on pages no packet ends on
+      let granule = if segments.peek().is_some() { -1 } else { i64::try_from(granule_position).unwrap_or(i64::MAX) };
+      let len = lacing.iter().map(|l| usize::from(*l)).sum::<usize>();
+      let mut page = b"OggS\0".to_vec();
+      page.push(flags);
+      page.extend_from_slice(&granule.to_le_bytes());
+      page.extend_from_slice(&self.serial.to_le_bytes());
+      page.extend_from_slice(&self.sequence.to_le_bytes());
+      page.extend_from_slice(&[0; 4]);
+      page.push(u8::try_from(lacing.len()).unwrap_or(u8::MAX));
+      page.extend_from_slice(lacing);
+      page.extend_from_slice(&data[..len]);
+      let crc = ogg_crc(&page);
+      page[22..26].copy_from_slice(&crc.to_le_bytes());
+      pages.push(Bytes::from(page));
+      data = &data[len..];
+      self.sequence = self.sequence.wrapping_add(1);
+    }
+    pages
+  }
+}
+
+/// CRC-32 of an Ogg page: polynomial 0x04c11db7, no reflection, zero initial value and no final
+/// xor, computed with the checksum field zeroed.
+pub fn ogg_crc(data: &[u8]) -> u32 {
+  data.iter().fold(0u32, |crc, byte| {
+    (0..8).fold(crc ^ (u32::from(*byte) << 24), |crc, _| {
+      if crc & 0x8000_0000 == 0 { crc << 1 } else { (crc << 1) ^ 0x04c1_1db7 }
+    })
+  })
+}
+
+/// The header type flags, lacing values and offset of the first packet of a page.
+fn parse_page(page: &[u8]) -> Option<(u8, &[u8], usize)> {
+  if !page.starts_with(b"OggS") || page.get(4) != Some(&0) {
+    return None;
+  }
+  let n_segs = usize::from(*page.get(26)?);
+  let lacing = page.get(27..27 + n_segs)?;
+  let offset = 27 + n_segs;
+  let len = lacing.iter().map(|l| usize::from(*l)).sum::<usize>();
+  (page.len() >= offset + len).then_some((page[5], lacing, offset))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn page(flags: u8, lacing: &[u8]) -> Bytes {
+    let mut page = b"OggS\0".to_vec();
+    page.push(flags);
+    page.extend_from_slice(&[0; 20]);
+    page.push(u8::try_from(lacing.len()).unwrap());
+    page.extend_from_slice(lacing);
+    for (i, len) in lacing.iter().enumerate() {
+      page.extend(std::iter::repeat_n(u8::try_from(i).unwrap(), usize::from(*len)));
+    }
+    Bytes::from(page)
+  }
+
+  #[test]
+  fn joins_packets_across_pages() {
+    let mut reader = PacketReader::default();
+    let packets = reader.push(&page(BEGINNING_OF_STREAM, &[3, 255]));
+    assert_eq!(packets.len(), 1);
+    assert_eq!(packets[0].len(), 3);
+
+    let packets = reader.push(&page(CONTINUED, &[10, 4]));
+    assert_eq!(packets.iter().map(Bytes::len).collect::<Vec<_>>(), [265, 4]);
+  }
+
+  #[test]
+  fn drops_packet_tails_without_a_start() {
+    let mut reader = PacketReader::default();
+    let packets = reader.push(&page(CONTINUED, &[255, 10, 4]));
+    assert_eq!(packets.iter().map(Bytes::len).collect::<Vec<_>>(), [4]);
+
+    // a partial packet is dropped when the next page does not continue it
+    assert!(reader.push(&page(0, &[255])).is_empty());
+    assert_eq!(reader.push(&page(0, &[7])).len(), 1);
+    assert!(reader.push(&Bytes::from_static(b"not a page")).is_empty());
+
+    // a missed start spanning several pages
+    assert!(reader.push(&page(CONTINUED, &[255])).is_empty());
+    assert_eq!(reader.push(&page(CONTINUED, &[3, 5])).iter().map(Bytes::len).collect::<Vec<_>>(), [5]);
+  }
+
+  #[test]
+  fn drops_packets_over_the_limit() {
+    let mut reader = PacketReader::default();
+    let full = page(0, &[255; 255]);
+    assert!(reader.push(&full).is_empty());
+    let continued = page(CONTINUED, &[255; 255]);
+    for _ in 0..MAX_PACKET / (255 * 255) {
+      assert!(reader.push(&continued).is_empty());
+    }
+    assert!(reader.partial.is_none());
+    // the end of the dropped packet is skipped, the next one is read
+    assert_eq!(reader.push(&page(CONTINUED, &[255, 3, 5])).iter().map(Bytes::len).collect::<Vec<_>>(), [5]);
+  }
+
+  #[test]
+  fn writes_pages_the_reader_splits_back() {
+    let mut writer = PageWriter::new(7);
+    let small = writer.push(&[1; 20], 960);
+    assert_eq!(small.len(), 1);
+    assert_eq!(small[0][5], BEGINNING_OF_STREAM);
+    let header = PageHeader::parse(&small[0]).unwrap();
+    assert_eq!((header.granule_position, header.serial, header.sequence), (Some(960), 7, 0));
+
+    // 255 lacing values of 255 and one of 0 do not fit one page
+    let large = vec![2; 255 * 255];
+    let pages = writer.push(&large, 1920);
+    assert_eq!(pages.len(), 2);
+    assert_eq!(pages[1][5], CONTINUED);
+    assert_eq!(PageHeader::parse(&pages[0]).unwrap().granule_position, None);
+    assert_eq!(PageHeader::parse(&pages[1]).unwrap().sequence, 2);
+
+    let mut reader = PacketReader::default();
+    assert_eq!(reader.push(&small[0]), [Bytes::from_static(&[1; 20])]);
+    assert!(reader.push(&pages[0]).is_empty());
+    assert_eq!(reader.push(&pages[1]), [Bytes::from(large)]);
+  }
+}
@@ -0,0 +1,44 @@
+/// Opus always decodes at 48 kHz, whatever the input sample rate in `OpusHead` says.
+pub const SAMPLE_RATE: u32 = 48_000;
+
+/// Number of 48 kHz samples in an Opus packet, from its TOC byte and frame count (RFC 6716,
+/// section 3.1). `None` for an empty or malformed packet.
+pub fn packet_samples(packet: &[u8]) -> Option<u32> {
+  let toc = *packet.first()?;
+  let config = toc >> 3;
+  let frame = match config {
+    // SILK: 10, 20, 40 and 60 ms
+    0..=11 => [480, 960, 1920, 2880][usize::from(config % 4)],
+    // hybrid: 10 and 20 ms
+    12..=15 => [480, 960][usize::from(config % 2)],
+    // CELT: 2.5, 5, 10 and 20 ms
+    _ => [120, 240, 480, 960][usize::from(config % 4)],
+  };
+  let frames = match toc & 0x03 {
+    0 => 1,
+    1 | 2 => 2,
+    _ => u32::from(*packet.get(1)? & 0x3f),
+  };
+  let samples = frame * frames;
+  // a packet holds at most 120 ms of audio
+  (frames > 0 && samples <= 5760).then_some(samples)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn counts_samples_from_toc() {
+    // CELT fullband 20 ms, one frame
+    assert_eq!(packet_samples(&[0xfc, 0, 0]), Some(960));
+    // SILK 60 ms, two frames
+    assert_eq!(packet_samples(&[(3 << 3) | 1]), Some(5760));
+    // CELT 2.5 ms, code 3 with 4 frames
+    assert_eq!(packet_samples(&[(16 << 3) | 3, 4]), Some(480));
+    // code 3 with too many frames, and no frame count
+    assert_eq!(packet_samples(&[(3 << 3) | 3, 3]), None);
+    assert_eq!(packet_samples(&[(16 << 3) | 3]), None);
+    assert_eq!(packet_samples(&[]), None);
+  }
+}
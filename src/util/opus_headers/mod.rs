@@ -0,0 +1,266 @@
+//! The Opus identification and comment headers of an Ogg Opus stream (RFC 7845, section 5).
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum OpusHeaderError {
+  #[error("not an {0} packet")]
+  Magic(&'static str),
+
+  #[error("truncated {0} packet")]
+  Truncated(&'static str),
+
+  #[error("unsupported OpusHead version: {0}")]
+  UnsupportedVersion(u8),
+
+  #[error("invalid channel count: {0}")]
+  InvalidChannelCount(u8),
+
+  #[error("invalid channel mapping: {0}")]
+  InvalidChannelMapping(String),
+
+  #[error("OpusTags packet too large")]
+  TooLarge,
+
+  #[error("the OpusTags packet does not fit a single Ogg page")]
+  NotSinglePage,
+}
+
+/// The identification header, `OpusHead`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpusHead {
+  pub version: u8,
+  pub channels: u8,
+  /// Samples at 48 kHz to discard from the decoder output when starting playback.
+  pub pre_skip: u16,
+  /// Sample rate of the original input, for information only.
+  pub input_sample_rate: u32,
+  /// Gain to apply to the decoder output, in Q7.8 dB.
+  pub output_gain: i16,
+  pub mapping_family: u8,
+  /// The channel mapping table, present for every mapping family but 0.
+  pub mapping: Option<ChannelMapping>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelMapping {
+  pub stream_count: u8,
+  pub coupled_count: u8,
+  /// The decoded channel of each output channel, 255 for silence.
+  pub table: Vec<u8>,
+}
+
+impl OpusHead {
+  const MAGIC: &[u8] = b"OpusHead";
+
+  pub fn parse(packet: &[u8]) -> Result<Self, OpusHeaderError> {
+    let truncated = || OpusHeaderError::Truncated("OpusHead");
+    let head = packet.strip_prefix(Self::MAGIC).ok_or(OpusHeaderError::Magic("OpusHead"))?;
+    if head.len() < 11 {
+      return Err(truncated());
+    }
+    // the major version, in the upper four bits, changes for incompatible layouts
+    let version = head[0];
+    if version >> 4 != 0 {
+      return Err(OpusHeaderError::UnsupportedVersion(version));
+    }
+    let channels = head[1];
+    if channels == 0 {
+      return Err(OpusHeaderError::InvalidChannelCount(channels));
+    }
+    let mapping_family = head[10];
+    // family 0 is mono or stereo, family 1 the Vorbis channel orders of up to 7.1 surround
+    if (mapping_family == 0 && channels > 2) || (mapping_family == 1 && channels > 8) {
+      return Err(OpusHeaderError::InvalidChannelCount(channels));
+    }
+    let mapping = if mapping_family == 0 {
+      None
+    } else {
+      let table = head.get(11..13 + usize::from(channels)).ok_or_else(truncated)?;
+      let (stream_count, coupled_count) = (table[0], table[1]);
+      let decoded = usize::from(stream_count) + usize::from(coupled_count);
+      if stream_count == 0 || coupled_count > stream_count || decoded > 255 {
+        return Err(OpusHeaderError::InvalidChannelMapping(
+          format!("{stream_count} streams with {coupled_count} coupled")
+        ));
+      }
+      if let Some(channel) = table[2..].iter().find(|c| **c != 255 && usize::from(**c) >= decoded) {
+        return Err(OpusHeaderError::InvalidChannelMapping(
+          format!("channel {channel} of {decoded} decoded channels")
+        ));
+      }
+      Some(ChannelMapping { stream_count, coupled_count, table: table[2..].to_vec() })
+    };
+    Ok(Self {
+      version,
+      channels,
+      pre_skip: u16::from_le_bytes([head[2], head[3]]),
+      input_sample_rate: u32::from_le_bytes([head[4], head[5], head[6], head[7]]),
+      output_gain: i16::from_le_bytes([head[8], head[9]]),
+      mapping_family,
+      mapping,
+    })
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut out = Self::MAGIC.to_vec();
+    out.extend_from_slice(&[self.version, self.channels]);
+    out.extend_from_slice(&self.pre_skip.to_le_bytes());
+    out.extend_from_slice(&self.input_sample_rate.to_le_bytes());
+    out.extend_from_slice(&self.output_gain.to_le_bytes());
+    out.push(self.mapping_family);
+    if let Some(mapping) = &self.mapping {
+      out.extend_from_slice(&[mapping.stream_count, mapping.coupled_count]);
+      out.extend_from_slice(&mapping.table);
+    }
+    out
+  }
+}
+
+/// The comment header, `OpusTags`: the vendor string and the user comments, `FIELD=value`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpusTags {
+  /// The vendor string, with any invalid UTF-8 replaced.
+  pub vendor: String,
+  pub comments: Vec<(String, String)>,
+  /// User comments that are not UTF-8 or have no `=`, written back as is after the others.
+  #[serde(skip)]
+  pub unparsed: Vec<Vec<u8>>,
+  /// Binary data following the comments, kept as is.
+  #[serde(skip)]
+  pub extra: Vec<u8>,
+}
+
+impl OpusTags {
+  const MAGIC: &[u8] = b"OpusTags";
+
+  pub fn parse(packet: &[u8]) -> Result<Self, OpusHeaderError> {
+    let mut reader = packet.strip_prefix(Self::MAGIC).ok_or(OpusHeaderError::Magic("OpusTags"))?;
+    let vendor = String::from_utf8_lossy(read_field(&mut reader)?).into_owned();
+    let count = read_u32(&mut reader)?;
+    let mut comments = Vec::new();
+    let mut unparsed = Vec::new();
+    for _ in 0..count {
+      let comment = read_field(&mut reader)?;
+      match std::str::from_utf8(comment).ok().and_then(|c| c.split_once('=')) {
+        Some((field, value)) => comments.push((field.to_string(), value.to_string())),
+        None => unparsed.push(comment.to_vec()),
+      }
+    }
+    Ok(Self { vendor, comments, unparsed, extra: reader.to_vec() })
+  }
+
+  pub fn to_bytes(&self) -> Result<Vec<u8>, OpusHeaderError> {
+    let mut out = Self::MAGIC.to_vec();
+    write_field(&mut out, self.vendor.as_bytes())?;
+    let count = u32::try_from(self.comments.len() + self.unparsed.len()).map_err(|_| OpusHeaderError::TooLarge)?;
+    out.extend_from_slice(&count.to_le_bytes());
+    for (field, value) in &self.comments {
+      write_field(&mut out, format!("{field}={value}").as_bytes())?;
+    }
+    for comment in &self.unparsed {
+      write_field(&mut out, comment)?;
+    }
+    out.extend_from_slice(&self.extra);
+    Ok(out)
+  }
+
+  /// Replaces the comments whose field name, compared case-insensitively, is in `comments`,
+  /// and appends `comments`.
+  pub fn set(&mut self, comments: &[(String, String)]) {
+    self.comments.retain(|(name, _)| !comments.iter().any(|(field, _)| field.eq_ignore_ascii_case(name)));
+    self.comments.extend_from_slice(comments);
+  }
+}
+
+fn read_u32(reader: &mut &[u8]) -> Result<u32, OpusHeaderError> {
+  let bytes = reader.get(..4).ok_or(OpusHeaderError::Truncated("OpusTags"))?;
+  let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+  *reader = &reader[4..];
+  Ok(value)
+}
+
+fn read_field<'a>(reader: &mut &'a [u8]) -> Result<&'a [u8], OpusHeaderError> {
+  let len = read_u32(reader)? as usize;
+  let field = reader.get(..len).ok_or(OpusHeaderError::Truncated("OpusTags"))?;
+  *reader = &reader[len..];
+  Ok(field)
+}
+
+fn write_field(out: &mut Vec<u8>, field: &[u8]) -> Result<(), OpusHeaderError> {
+  let len = u32::try_from(field.len()).map_err(|_| OpusHeaderError::TooLarge)?;
+  out.extend_from_slice(&len.to_le_bytes());
+  out.extend_from_slice(field);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trips_opus_head() {
+    let stereo = b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\xfe\x00";
+    let head = OpusHead::parse(stereo).unwrap();
+    assert_eq!((head.channels, head.pre_skip, head.input_sample_rate, head.output_gain), (2, 312, 48_000, -512));
+    assert!(head.mapping.is_none());
+    assert_eq!(head.to_bytes(), stereo);
+
+    // 5.1 surround: 4 streams, 2 of them coupled
+    let surround = b"OpusHead\x01\x06\x38\x01\x80\xbb\x00\x00\x00\x00\x01\x04\x02\x00\x04\x01\x02\x03\x05";
+    let head = OpusHead::parse(surround).unwrap();
+    assert_eq!(head.mapping.as_ref().map(|m| (m.stream_count, m.coupled_count)), Some((4, 2)));
+    assert_eq!(head.to_bytes(), surround);
+
+    assert_eq!(OpusHead::parse(&stereo[..15]), Err(OpusHeaderError::Truncated("OpusHead")));
+    assert_eq!(OpusHead::parse(&surround[..20]), Err(OpusHeaderError::Truncated("OpusHead")));
+    let mut version = stereo.to_vec();
+    version[8] = 0x10;
+    assert_eq!(OpusHead::parse(&version), Err(OpusHeaderError::UnsupportedVersion(0x10)));
+    let mut mapping = surround.to_vec();
+    mapping[23] = 6;
+    assert!(matches!(OpusHead::parse(&mapping), Err(OpusHeaderError::InvalidChannelMapping(_))));
+    // family 1 stops at 7.1
+    let nine = b"OpusHead\x01\x09\x38\x01\x80\xbb\x00\x00\x00\x00\x01\x05\x04\x00\x01\x02\x03\x04\x05\x06\x07\x08";
+    assert_eq!(OpusHead::parse(nine), Err(OpusHeaderError::InvalidChannelCount(9)));
+  }
+
+  #[test]
+  fn round_trips_and_sets_opus_tags() {
+    let mut packet = b"OpusTags".to_vec();
+    write_field(&mut packet, b"libopus 1.5").unwrap();
+    packet.extend_from_slice(&2u32.to_le_bytes());
+    write_field(&mut packet, b"title=Old").unwrap();
+    write_field(&mut packet, b"ARTIST=Someone").unwrap();
+    packet.extend_from_slice(b"\x01binary");
+    let mut tags = OpusTags::parse(&packet).unwrap();
+    assert_eq!(tags.vendor, "libopus 1.5");
+    assert_eq!(tags.comments, [("title".into(), "Old".into()), ("ARTIST".into(), "Someone".into())]);
+    assert_eq!(tags.to_bytes().unwrap(), packet);
+
+    tags.set(&[("TITLE".into(), "New".into())]);
+    assert_eq!(tags.comments, [("ARTIST".into(), "Someone".into()), ("TITLE".into(), "New".into())]);
+    assert_eq!(tags.extra, b"\x01binary");
+
+    assert_eq!(OpusTags::parse(&packet[..20]), Err(OpusHeaderError::Truncated("OpusTags")));
+  }
+
+  #[test]
+  fn keeps_comments_it_cannot_read() {
+    let mut packet = b"OpusTags".to_vec();
+    write_field(&mut packet, b"enc\xff").unwrap();
+    packet.extend_from_slice(&3u32.to_le_bytes());
+    write_field(&mut packet, b"TITLE=Song").unwrap();
+    write_field(&mut packet, b"no field").unwrap();
+    write_field(&mut packet, b"ARTIST=\xfe").unwrap();
+    let mut tags = OpusTags::parse(&packet).unwrap();
+    assert_eq!(tags.vendor, "enc\u{fffd}");
+    assert_eq!(tags.comments, [("TITLE".into(), "Song".into())]);
+    assert_eq!(tags.unparsed, [b"no field".to_vec(), b"ARTIST=\xfe".to_vec()]);
+
+    tags.set(&[("TITLE".into(), "New".into())]);
+    let tags = OpusTags::parse(&tags.to_bytes().unwrap()).unwrap();
+    assert_eq!(tags.comments, [("TITLE".into(), "New".into())]);
+    assert_eq!(tags.unparsed.len(), 2);
+  }
+}
@@ -0,0 +1,293 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Peer address reported for connections over a Unix domain socket. These come from the same
+/// host, like a reverse proxy connecting over loopback TCP, and are treated the same way by the
+/// access lists.
+pub const UNIX_PEER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Where a task accepts connections: a TCP address, a Unix domain socket, or both.
+#[derive(Debug, Clone)]
+pub struct Binding {
+  pub tcp: Option<SocketAddr>,
+  pub unix: Option<PathBuf>,
+  /// Permissions of the Unix socket file.
+  pub mode: u32,
+}
+
+/// The listening sockets of a [`Binding`], or those passed in by systemd.
+pub struct Listener {
+  sockets: Vec<Socket>,
+}
+
+enum Socket {
+  Tcp(TcpListener),
+  #[cfg(unix)]
+  Unix(UnixSocket),
+  /// A Unix socket owned by the service manager, which also removes its file.
+  #[cfg(unix)]
+  Inherited(tokio::net::UnixListener),
+}
+
+/// A listening address, as shown on startup.
+pub enum Address {
+  Tcp(SocketAddr),
+  Unix(Option<PathBuf>),
+}
+
+impl Listener {
+  /// # Errors
+  /// Fails if a socket cannot be bound, or a Unix socket path is in use by a running process.
+  pub async fn bind(binding: &Binding) -> anyhow::Result<Self> {
+    let mut sockets = Vec::new();
+    if let Some(addr) = binding.tcp {
+      match TcpListener::bind(addr).await {
+        Ok(listener) => sockets.push(Socket::Tcp(listener)),
+        Err(e) => anyhow::bail!("Could not bind to {addr}: {e}"),
+      }
+    }
+    if let Some(path) = &binding.unix {
+      #[cfg(unix)]
+      sockets.push(Socket::Unix(UnixSocket::bind(path.clone(), binding.mode)?));
+      #[cfg(not(unix))]
+      anyhow::bail!("Could not bind to '{}': Unix domain sockets are only available on unix", path.display());
+    }
+    Ok(Self { sockets })
+  }
+
+  /// Takes over listening TCP or Unix sockets, e.g. passed by systemd socket activation.
+  /// # Errors
+  /// Fails if a descriptor is not a listening stream socket of either kind.
+  #[cfg(unix)]
+  pub fn from_fds(fds: Vec<std::os::fd::OwnedFd>) -> anyhow::Result<Self> {
+    let mut sockets = Vec::new();
+    for fd in fds {
+      let socket = socket2::Socket::from(fd);
+      // a datagram or a connected socket has a local address too
+      if socket.r#type()? != socket2::Type::STREAM || !is_listener(&socket)? {
+        anyhow::bail!("Inherited file descriptor is not a listening stream socket");
+      }
+      socket.set_nonblocking(true)?;
+      let addr = socket.local_addr()?;
+      let fd = std::os::fd::OwnedFd::from(socket);
+      if addr.as_socket().is_some() {
+        sockets.push(Socket::Tcp(TcpListener::from_std(std::net::TcpListener::from(fd))?));
+      } else if addr.is_unix() {
+        let unix = std::os::unix::net::UnixListener::from(fd);
+        sockets.push(Socket::Inherited(tokio::net::UnixListener::from_std(unix)?));
+      } else {
+        anyhow::bail!("Inherited file descriptor is not a TCP or Unix socket");
+      }
+    }
+    Ok(Self { sockets })
+  }
+
+  pub fn addresses(&self) -> Vec<Address> {
+    self.sockets.iter()
+      .filter_map(|socket| match socket {
+        Socket::Tcp(listener) => listener.local_addr().ok().map(Address::Tcp),
+        #[cfg(unix)]
+        Socket::Unix(socket) => Some(Address::Unix(Some(socket.path.clone()))),
+        #[cfg(unix)]
+        Socket::Inherited(listener) => Some(Address::Unix(
+          listener.local_addr().ok().and_then(|addr| addr.as_pathname().map(PathBuf::from))
+        )),
+      })
+      .collect()
+  }
+
+  /// Accepts the next connection on any of the sockets.
+  pub async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+    if self.sockets.is_empty() {
+      return std::future::pending().await;
+    }
+    let accepts = self.sockets.iter().map(|socket| Box::pin(socket.accept()));
+    futures_util::future::select_all(accepts).await.0
+  }
+}
+
+impl Socket {
+  async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+    match self {
+      Self::Tcp(listener) => listener.accept().await.map(|(stream, peer)| (Stream::Tcp(stream), peer)),
+      #[cfg(unix)]
+      Self::Unix(UnixSocket { listener, .. }) | Self::Inherited(listener) => listener.accept().await
+        .map(|(stream, _)| (Stream::Unix(stream), UNIX_PEER)),
+    }
+  }
+}
+
+/// Whether `listen` was called on the socket. Where `SO_ACCEPTCONN` cannot be read, only the
+/// socket type is checked.
+#[cfg(unix)]
+fn is_listener(socket: &socket2::Socket) -> std::io::Result<bool> {
+  #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+  return socket.is_listener();
+  #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+  {
+    let _ = socket;
+    Ok(true)
+  }
+}
+
+/// A connection accepted by a [`Listener`].
+pub enum Stream {
+  Tcp(TcpStream),
+  #[cfg(unix)]
+  Unix(tokio::net::UnixStream),
+}
+
+impl Stream {
+  /// Whether the peer connected over a Unix domain socket, and so has no address of its own.
+  pub const fn is_unix(&self) -> bool {
+    match self {
+      Self::Tcp(_) => false,
+      #[cfg(unix)]
+      Self::Unix(_) => true,
+    }
+  }
+
+  pub fn set_nodelay(&self) {
+    if let Self::Tcp(stream) = self {
+      let _ = stream.set_nodelay(true);
+    }
+  }
+}
+
+impl AsyncRead for Stream {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Self::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+      #[cfg(unix)]
+      Self::Unix(s) => Pin::new(s).poll_read(cx, buf),
+    }
+  }
+}
+
+impl AsyncWrite for Stream {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    match self.get_mut() {
+      Self::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+      #[cfg(unix)]
+      Self::Unix(s) => Pin::new(s).poll_write(cx, buf),
+    }
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Self::Tcp(s) => Pin::new(s).poll_flush(cx),
+      #[cfg(unix)]
+      Self::Unix(s) => Pin::new(s).poll_flush(cx),
+    }
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Self::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+      #[cfg(unix)]
+      Self::Unix(s) => Pin::new(s).poll_shutdown(cx),
+    }
+  }
+
+  fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+    match self.get_mut() {
+      Self::Tcp(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+      #[cfg(unix)]
+      Self::Unix(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+    }
+  }
+
+  fn is_write_vectored(&self) -> bool {
+    match self {
+      Self::Tcp(s) => s.is_write_vectored(),
+      #[cfg(unix)]
+      Self::Unix(s) => s.is_write_vectored(),
+    }
+  }
+}
+
+/// A Unix domain socket whose file is removed again when it is dropped.
+#[cfg(unix)]
+pub struct UnixSocket {
+  pub listener: tokio::net::UnixListener,
+  path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+  /// Binds `path` with the permissions `mode`, replacing a socket file left behind by a process
+  /// that did not shut down cleanly.
+  /// # Errors
+  /// Fails if another process is listening on `path`, or the socket cannot be created.
+  pub fn bind(path: PathBuf, mode: u32) -> anyhow::Result<Self> {
+    use std::fs;
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(meta) = fs::symlink_metadata(&path) {
+      if !meta.file_type().is_socket() {
+        anyhow::bail!("'{}' exists and is not a socket", path.display());
+      }
+      if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+        anyhow::bail!("'{}' is in use, is tau-tower already running?", path.display());
+      }
+      fs::remove_file(&path)?;
+    }
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    let listener = match tokio::net::UnixListener::bind(&path) {
+      Ok(listener) => listener,
+      Err(e) => anyhow::bail!("Could not bind to '{}': {e}", path.display()),
+    };
+    // from here on the file is removed on drop, also when setting the permissions fails
+    let socket = Self { listener, path };
+    fs::set_permissions(&socket.path, fs::Permissions::from_mode(mode))?;
+    Ok(socket)
+  }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_file(&self.path);
+  }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::*;
+  use std::os::unix::fs::PermissionsExt;
+
+  #[tokio::test]
+  async fn replaces_stale_socket_and_removes_on_drop() {
+    let path = std::env::temp_dir().join(format!("tau-tower-test-{}.sock", std::process::id()));
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let socket = UnixSocket::bind(path.clone(), 0o660).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+    assert!(UnixSocket::bind(path.clone(), 0o660).is_err());
+
+    drop(socket);
+    assert!(!path.exists());
+  }
+
+  #[tokio::test]
+  async fn takes_over_only_listening_stream_sockets() {
+    use std::os::fd::OwnedFd;
+    let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = tcp.local_addr().unwrap();
+    let listener = Listener::from_fds(vec![OwnedFd::from(tcp)]).unwrap();
+    assert!(matches!(listener.addresses()[..], [Address::Tcp(a)] if a == addr));
+
+    let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    assert!(Listener::from_fds(vec![OwnedFd::from(udp)]).is_err());
+    let connected = std::net::TcpStream::connect(addr).unwrap();
+    assert!(Listener::from_fds(vec![OwnedFd::from(connected)]).is_err());
+  }
+}
@@ -0,0 +1,147 @@
+use crate::util::socket::Listener;
+
+/// Listening sockets passed by systemd socket activation, by side. A side without sockets is
+/// bound from the config as usual.
+#[derive(Default)]
+pub struct Activated {
+  pub source: Option<Listener>,
+  pub broadcast: Option<Listener>,
+}
+
+/// Takes over the sockets of a `.socket` unit. They are matched to a side by their
+/// `FileDescriptorName=`, `source` or `broadcast`; unnamed sockets are taken in order, source
+/// first.
+/// # Errors
+/// Fails on an unknown socket name, or a descriptor that is not a listening socket.
+#[cfg(unix)]
+pub fn listen_fds() -> anyhow::Result<Activated> {
+  use std::os::fd::{FromRawFd, OwnedFd};
+
+  let var = |key| std::env::var(key).ok();
+  let passed = passed_fds(
+    var("LISTEN_PID").as_deref(),
+    var("LISTEN_FDS").as_deref(),
+    var("LISTEN_FDNAMES").as_deref(),
+    std::process::id()
+  )?;
+  let (mut source, mut broadcast) = (Vec::new(), Vec::new());
+  for (i, (fd, name)) in passed.into_iter().enumerate() {
+    // SAFETY: systemd hands these descriptors to this process, nothing else owns them
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    // not inherited by hook commands
+    socket2::SockRef::from(&fd).set_cloexec(true)?;
+    match (name.as_str(), i) {
+      ("source", _) | ("unknown", 0) => source.push(fd),
+      ("broadcast", _) | ("unknown", 1) => broadcast.push(fd),
+      _ => anyhow::bail!("Unknown socket name `{name}`, expected `source` or `broadcast`"),
+    }
+  }
+  let listener = |fds: Vec<OwnedFd>| (!fds.is_empty()).then(|| Listener::from_fds(fds)).transpose();
+  Ok(Activated { source: listener(source)?, broadcast: listener(broadcast)? })
+}
+
+/// The descriptors passed with `LISTEN_FDS`, starting at 3, and their `LISTEN_FDNAMES`, or
+/// `unknown` when not named. None are taken when `LISTEN_PID` is not this process: they were
+/// meant for a parent, e.g. a shell script started by systemd.
+#[cfg(unix)]
+fn passed_fds(
+  pid: Option<&str>,
+  count: Option<&str>,
+  names: Option<&str>,
+  own_pid: u32
+) -> anyhow::Result<Vec<(std::os::fd::RawFd, String)>> {
+  const LISTEN_FDS_START: i32 = 3;
+
+  let Some(pid) = pid else { return Ok(Vec::new()) };
+  let pid: u32 = pid.parse().map_err(|_| anyhow::anyhow!("Invalid LISTEN_PID `{pid}`"))?;
+  let (true, Some(count)) = (pid == own_pid, count) else { return Ok(Vec::new()) };
+  let end = count.parse::<u16>().ok()
+    .and_then(|count| LISTEN_FDS_START.checked_add(count.into()))
+    .ok_or_else(|| anyhow::anyhow!("Invalid LISTEN_FDS `{count}`"))?;
+  let fds = LISTEN_FDS_START..end;
+  let names: Vec<String> = names.map_or_else(
+    || vec!["unknown".to_string(); fds.len()],
+    |names| names.split(':').map(ToString::to_string).collect()
+  );
+  if names.len() != fds.len() {
+    anyhow::bail!("LISTEN_FDNAMES names {} sockets, LISTEN_FDS passes {count}", names.len());
+  }
+  Ok(fds.zip(names).collect())
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> anyhow::Result<Activated> {
+  Ok(Activated::default())
+}
+
+/// The service is up, after startup or a reload. The notifications do nothing when tower is not
+/// run by systemd.
+pub fn ready() {
+  #[cfg(unix)]
+  notify(&[sd_notify::NotifyState::Ready]);
+}
+
+/// Shown by `systemctl status`.
+pub fn status(status: &str) {
+  #[cfg(unix)]
+  notify(&[sd_notify::NotifyState::Status(status)]);
+  #[cfg(not(unix))]
+  let _ = status;
+}
+
+/// The config is being reloaded, to be followed by [`ready`].
+pub fn reloading() {
+  #[cfg(unix)]
+  if let Ok(now) = sd_notify::NotifyState::monotonic_usec_now() {
+    notify(&[sd_notify::NotifyState::Reloading, now]);
+  }
+}
+
+pub fn stopping() {
+  #[cfg(unix)]
+  notify(&[sd_notify::NotifyState::Stopping]);
+}
+
+pub fn watchdog() {
+  #[cfg(unix)]
+  notify(&[sd_notify::NotifyState::Watchdog]);
+}
+
+/// The `WatchdogSec=` of the unit, if the watchdog is enabled for this process.
+pub fn watchdog_interval() -> Option<std::time::Duration> {
+  #[cfg(unix)]
+  return sd_notify::watchdog_enabled();
+  #[cfg(not(unix))]
+  None
+}
+
+#[cfg(unix)]
+fn notify(state: &[sd_notify::NotifyState]) {
+  if let Err(e) = sd_notify::notify(state) {
+    eprintln!("Could not notify systemd: {e}");
+  }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn listen_variables_are_parsed() {
+    let unknown = |fd| (fd, "unknown".to_string());
+    assert_eq!(passed_fds(Some("42"), Some("2"), None, 42).unwrap(), [unknown(3), unknown(4)]);
+    assert_eq!(
+      passed_fds(Some("42"), Some("2"), Some("broadcast:source"), 42).unwrap(),
+      [(3, "broadcast".to_string()), (4, "source".to_string())]
+    );
+    // meant for another process, or not socket activated at all
+    assert!(passed_fds(Some("41"), Some("2"), None, 42).unwrap().is_empty());
+    assert!(passed_fds(None, Some("2"), None, 42).unwrap().is_empty());
+    assert!(passed_fds(Some("42"), None, None, 42).unwrap().is_empty());
+
+    assert!(passed_fds(Some("pid"), Some("2"), None, 42).is_err());
+    assert!(passed_fds(Some("42"), Some("two"), None, 42).is_err());
+    assert!(passed_fds(Some("42"), Some("-1"), None, 42).is_err());
+    assert!(passed_fds(Some("42"), Some("2"), Some("source"), 42).is_err());
+  }
+}
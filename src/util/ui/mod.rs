@@ -1,6 +1,8 @@
 use std::path::Path;
 use std::net::IpAddr;
 use inline_colorization::{ color_reset, color_bright_red, color_bright_yellow, color_cyan};
+use crate::control::Status;
+use crate::mount::ListenerInfo;
 
 pub fn server_started_info(ip: IpAddr, port: u16, endpoint: &str) {
   println!(
@@ -9,6 +11,14 @@ pub fn server_started_info(ip: IpAddr, port: u16, endpoint: &str) {
   );
 }
 
+pub fn unix_socket_info(path: &Path, endpoint: &str) {
+  println!(
+    "\
+    {color_bright_yellow}Broadcasting on unix socket:{color_reset}\n\t{color_cyan}{}{color_reset} ({endpoint})",
+    path.display()
+  );
+}
+
 pub fn config_file_created_info(path: &Path) {
   let path = path.display();
   println!(
@@ -18,6 +28,89 @@ pub fn config_file_created_info(path: &Path) {
   );
 }
 
+pub fn status_info(status: &Status) {
+  let source = status.source.as_ref().map_or_else(
+    || "none".to_string(),
+    |s| format!("{} from {}, connected {}", s.username, s.remote_addr, format_duration(s.connected_secs))
+  );
+  println!(
+    "\
+    {color_bright_yellow}tau-tower {}{color_reset}, up {}\n\
+    mount:     {color_cyan}{}{color_reset}\n\
+    source:    {source}\n\
+    listeners: {}",
+    status.version, format_duration(status.uptime_secs), status.mount, status.listeners,
+  );
+  if let Some(clock) = &status.clock {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let (position, received) = (clock.position_secs as u64, clock.received_secs as u64);
+    println!(
+      "clock:     at {}, {} received, drift {:+.3}s, {} pages missed",
+      format_duration(position), format_duration(received), clock.drift_secs, clock.pages_missed,
+    );
+  }
+  if let Some(head) = &status.opus_head {
+    println!(
+      "stream:    Opus, {} channels, {} Hz input, pre-skip {}, gain {:+.2} dB, mapping family {}",
+      head.channels, head.input_sample_rate, head.pre_skip, f64::from(head.output_gain) / 256.0, head.mapping_family,
+    );
+  } else if let Some(codec) = status.codec {
+    println!("stream:    {codec}");
+  }
+  if let Some(title) = &status.stream_title {
+    println!("title:     {title}");
+  }
+  if let Some(tags) = &status.opus_tags {
+    println!("vendor:    {}", tags.vendor);
+    for (field, value) in &tags.comments {
+      // pictures and the like are base64 blobs
+      if value.chars().count() > 80 {
+        println!("  {field}=({} bytes)", value.len());
+      } else {
+        println!("  {field}={value}");
+      }
+    }
+  }
+  for variant in &status.variants {
+    let channels = match variant.channels {
+      Some(1) => "mono",
+      Some(_) => "stereo",
+      None => "source channels",
+    };
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let state = variant.clock.as_ref()
+      .map_or_else(|| "stopped".to_string(), |clock| format!("at {}", format_duration(clock.position_secs as u64)));
+    println!(
+      "variant:   {color_cyan}{}{color_reset}, {} kb/s, {channels}, complexity {}, {state}",
+      variant.mount, f64::from(variant.bitrate) / 1000.0, variant.complexity,
+    );
+  }
+}
+
+pub fn listeners_info(listeners: &[ListenerInfo]) {
+  if listeners.is_empty() {
+    println!("No listeners connected");
+    return;
+  }
+  println!("{color_bright_yellow}{:>6}  {:<40} {:>10}  USER AGENT{color_reset}", "ID", "ADDRESS", "CONNECTED");
+  for l in listeners {
+    println!(
+      "{:>6}  {:<40} {:>10}  {}",
+      l.id, l.remote_addr, format_duration(l.connected_secs), l.user_agent.as_deref().unwrap_or("-")
+    );
+  }
+}
+
+/// Formats seconds as e.g. `1h 2m 3s`, leaving out leading zero units.
+pub fn format_duration(secs: u64) -> String {
+  let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+  match (h, m) {
+    (0, 0) => format!("{s}s"),
+    (0, _) => format!("{m}m {s}s"),
+    _ => format!("{h}h {m}m {s}s"),
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -28,6 +121,13 @@ mod tests {
     server_started_info(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8080, "/endpoint");
   }
    
+  #[test]
+  fn formats_durations() {
+    assert_eq!(format_duration(7), "7s");
+    assert_eq!(format_duration(60), "1m 0s");
+    assert_eq!(format_duration(3723), "1h 2m 3s");
+  }
+
   #[test] 
   fn print_config_created() {
     config_file_created_info(&PathBuf::from_str("./path/to/file").unwrap());
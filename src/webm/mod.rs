@@ -0,0 +1,225 @@
+//! Live WebM (Matroska) for Opus: an EBML header and an unknown-size Segment holding the track
+//! description and unknown-size Clusters of `SimpleBlock`s, one per Opus packet, as Media
+//! Source Extensions expect it.
+
+use hyper::body::Bytes;
+use crate::util::ogg_packets::PacketReader;
+use crate::util::opus::{SAMPLE_RATE, packet_samples};
+use crate::util::opus_headers::OpusHead;
+
+const EBML: u32 = 0x1A45_DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_A966;
+const TIMECODE_SCALE: u32 = 0x2A_D7B1;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const CODEC_DELAY: u32 = 0x56AA;
+const SEEK_PRE_ROLL: u32 = 0x56BB;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const CLUSTER: u32 = 0x1F43_B675;
+const TIMECODE: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+
+/// Size of an element streamed before its length is known.
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+/// Timestamps are in milliseconds.
+const TIMECODE_SCALE_NS: u64 = 1_000_000;
+/// A new cluster is started once a second, well within the 16-bit block timecodes.
+const CLUSTER_DURATION_MS: u64 = 1000;
+/// Pre-roll recommended for Opus after seeking, 80 ms.
+const SEEK_PRE_ROLL_NS: u64 = 80_000_000;
+
+/// Turns the Ogg pages of a listener's stream into WebM, starting at timestamp 0.
+#[derive(Default)]
+pub struct Remuxer {
+  reader: PacketReader,
+  opus_head: Option<Bytes>,
+  /// Position of the next packet, in 48 kHz samples.
+  time: u64,
+  cluster_start: Option<u64>,
+}
+
+impl Remuxer {
+  /// The WebM bytes for `page`, the headers once the `OpusHead` comes by. `None` once the
+  /// source switches to a stream with a different `OpusHead`, which a WebM stream cannot
+  /// follow; the listener has to reconnect.
+  pub fn push(&mut self, page: &Bytes) -> Option<Bytes> {
+    let mut out = Vec::new();
+    for packet in self.reader.push(page) {
+      if packet.starts_with(b"OpusHead") {
+        match &self.opus_head {
+          Some(head) if *head == packet => {},
+          Some(_) => return None,
+          None => {
+            out.extend(header(&packet)?);
+            self.opus_head = Some(packet);
+          },
+        }
+      } else if self.opus_head.is_some()
+        && !packet.starts_with(b"OpusTags")
+        && let Some(samples) = packet_samples(&packet) {
+        self.block(&mut out, &packet);
+        self.time += u64::from(samples);
+      }
+    }
+    Some(Bytes::from(out))
+  }
+
+  fn block(&mut self, out: &mut Vec<u8>, packet: &[u8]) {
+    let now = self.time * 1000 / u64::from(SAMPLE_RATE);
+    let cluster_start = match self.cluster_start {
+      Some(start) if now - start < CLUSTER_DURATION_MS => start,
+      _ => {
+        write_id(out, CLUSTER);
+        out.extend_from_slice(&UNKNOWN_SIZE);
+        write_uint(out, TIMECODE, now);
+        self.cluster_start = Some(now);
+        now
+      },
+    };
+    let relative = i16::try_from(now - cluster_start).unwrap_or(i16::MAX);
+    let mut block = vec![0x81];
+    block.extend_from_slice(&relative.to_be_bytes());
+    // keyframe, every Opus packet can be decoded on its own
+    block.push(0x80);
+    block.extend_from_slice(packet);
+    write_element(out, SIMPLE_BLOCK, &block);
+  }
+}
+
+/// The EBML header, and the start of the Segment with the Info and the Opus track, whose
+/// `CodecPrivate` is the `OpusHead` packet. `None` if `opus_head` is not a valid `OpusHead`.
+fn header(opus_head: &[u8]) -> Option<Vec<u8>> {
+  let head = OpusHead::parse(opus_head).ok()?;
+
+  let mut out = Vec::new();
+  let mut ebml = Vec::new();
+  write_uint(&mut ebml, EBML_VERSION, 1);
+  write_uint(&mut ebml, EBML_READ_VERSION, 1);
+  write_uint(&mut ebml, EBML_MAX_ID_LENGTH, 4);
+  write_uint(&mut ebml, EBML_MAX_SIZE_LENGTH, 8);
+  write_element(&mut ebml, DOC_TYPE, b"webm");
+  write_uint(&mut ebml, DOC_TYPE_VERSION, 4);
+  write_uint(&mut ebml, DOC_TYPE_READ_VERSION, 2);
+  write_element(&mut out, EBML, &ebml);
+
+  write_id(&mut out, SEGMENT);
+  out.extend_from_slice(&UNKNOWN_SIZE);
+
+  let mut info = Vec::new();
+  write_uint(&mut info, TIMECODE_SCALE, TIMECODE_SCALE_NS);
+  write_element(&mut info, MUXING_APP, b"tau-tower");
+  write_element(&mut info, WRITING_APP, b"tau-tower");
+  write_element(&mut out, INFO, &info);
+
+  let mut audio = Vec::new();
+  write_element(&mut audio, SAMPLING_FREQUENCY, &f64::from(SAMPLE_RATE).to_be_bytes());
+  write_uint(&mut audio, CHANNELS, u64::from(head.channels));
+  let mut track = Vec::new();
+  write_uint(&mut track, TRACK_NUMBER, 1);
+  write_uint(&mut track, TRACK_UID, 1);
+  // audio
+  write_uint(&mut track, TRACK_TYPE, 2);
+  write_element(&mut track, CODEC_ID, b"A_OPUS");
+  write_element(&mut track, CODEC_PRIVATE, &head.to_bytes());
+  write_uint(&mut track, CODEC_DELAY, u64::from(head.pre_skip) * 1_000_000_000 / u64::from(SAMPLE_RATE));
+  write_uint(&mut track, SEEK_PRE_ROLL, SEEK_PRE_ROLL_NS);
+  write_element(&mut track, AUDIO, &audio);
+  let mut tracks = Vec::new();
+  write_element(&mut tracks, TRACK_ENTRY, &track);
+  write_element(&mut out, TRACKS, &tracks);
+  Some(out)
+}
+
+/// Element ids carry their length marker, so they are written as is, without leading zeros.
+fn write_id(out: &mut Vec<u8>, id: u32) {
+  let bytes = id.to_be_bytes();
+  let skip = bytes.iter().take_while(|b| **b == 0).count();
+  out.extend_from_slice(&bytes[skip..]);
+}
+
+fn write_element(out: &mut Vec<u8>, id: u32, content: &[u8]) {
+  write_id(out, id);
+  write_size(out, content.len() as u64);
+  out.extend_from_slice(content);
+}
+
+/// An unsigned integer element, in as few bytes as it takes.
+fn write_uint(out: &mut Vec<u8>, id: u32, value: u64) {
+  let bytes = value.to_be_bytes();
+  let skip = bytes.iter().take_while(|b| **b == 0).count().min(7);
+  write_element(out, id, &bytes[skip..]);
+}
+
+/// An EBML variable size integer: the position of the first set bit gives the length. Values
+/// with all bits set are reserved for the unknown size.
+fn write_size(out: &mut Vec<u8>, size: u64) {
+  let len = (1..=8u32).find(|len| size < (1 << (7 * len)) - 1).unwrap_or(8);
+  let marked = size | (1 << (7 * len));
+  out.extend_from_slice(&marked.to_be_bytes()[8 - len as usize..]);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn page(flags: u8, packet: &[u8]) -> Bytes {
+    let mut page = b"OggS\0".to_vec();
+    page.push(flags);
+    page.extend_from_slice(&[0; 20]);
+    page.extend_from_slice(&[1, u8::try_from(packet.len()).unwrap()]);
+    page.extend_from_slice(packet);
+    Bytes::from(page)
+  }
+
+  #[test]
+  fn encodes_sizes_and_ints() {
+    let mut out = Vec::new();
+    write_size(&mut out, 5);
+    write_size(&mut out, 126);
+    write_size(&mut out, 127);
+    assert_eq!(out, [0x85, 0xFE, 0x40, 0x7F]);
+
+    let mut out = Vec::new();
+    write_uint(&mut out, TIMECODE_SCALE, TIMECODE_SCALE_NS);
+    write_uint(&mut out, TIMECODE, 0);
+    assert_eq!(out, [0x2A, 0xD7, 0xB1, 0x83, 0x0F, 0x42, 0x40, 0xE7, 0x81, 0x00]);
+  }
+
+  #[test]
+  fn remuxes_packets_into_clusters() {
+    let head = b"OpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00";
+    let mut remuxer = Remuxer::default();
+    assert!(remuxer.push(&page(0x02, head)).unwrap().starts_with(&[0x1A, 0x45, 0xDF, 0xA3]));
+    assert!(remuxer.push(&page(0, b"OpusTags")).unwrap().is_empty());
+
+    // 20 ms packets: a cluster at 0, blocks up to 980 ms, and a new cluster at 1000 ms
+    let out: Vec<Bytes> = (0..51).map(|_| remuxer.push(&page(0, &[0xFC, 1, 2])).unwrap()).collect();
+    assert!(out[0].starts_with(&[0x1F, 0x43, 0xB6, 0x75]));
+    assert_eq!(&out[0][12..], [0xE7, 0x81, 0x00, 0xA3, 0x87, 0x81, 0x00, 0x00, 0x80, 0xFC, 1, 2]);
+    assert_eq!(&out[49][..], [0xA3, 0x87, 0x81, 0x03, 0xD4, 0x80, 0xFC, 1, 2]);
+    assert_eq!(&out[50][12..15], [0xE7, 0x82, 0x03]);
+
+    // the same stream after a reconnect continues, another one ends the WebM stream
+    assert!(remuxer.push(&page(0x02, head)).is_some());
+    let mut mono = head.to_vec();
+    mono[9] = 1;
+    assert!(remuxer.push(&page(0x02, &mono)).is_none());
+  }
+}